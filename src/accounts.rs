@@ -0,0 +1,197 @@
+//! Password accounts.
+//!
+//! Passwords are stored as Argon2id PHC strings. Registration bodies may carry
+//! a write-only `password`, and `POST /login` checks one against the stored
+//! hash, rehashing it when it was made with outdated parameters, before
+//! issuing [tokens](crate::auth).
+
+use actix_web::{web, HttpResponse};
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::auth::Tokens;
+use crate::error::{ApiError, FieldViolation};
+use crate::model::Role;
+use crate::policy::RoleGrants;
+use crate::repository::UserRepository;
+use crate::versioning::UserRepresentation;
+
+/// Shortest accepted password, in characters.
+const MIN_PASSWORD_LEN: usize = 8;
+/// Longest accepted password, in characters; bounds the cost of hashing.
+const MAX_PASSWORD_LEN: usize = 128;
+
+/// Registers `POST /login`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/login", web::post().to(login));
+}
+
+/// Hashes and verifies passwords with Argon2id.
+#[derive(Clone)]
+pub struct Passwords {
+    params: Params,
+    /// Hash checked when the user does not exist, so that unknown usernames
+    /// take as long to reject as wrong passwords.
+    dummy_hash: String,
+}
+
+/// Outcome of checking a password.
+pub enum Verification {
+    Invalid,
+    Valid,
+    /// The password is valid and this is its hash with the current parameters,
+    /// to store in place of the outdated one.
+    Rehashed(String),
+}
+
+impl Passwords {
+    pub fn new(params: Params) -> Self {
+        let dummy_hash = hash_with(&params, "dummy password");
+        Self { params, dummy_hash }
+    }
+
+    /// Hashes `password` with a fresh salt, off the async workers.
+    pub async fn hash(&self, password: String) -> String {
+        let params = self.params.clone();
+        web::block(move || hash_with(&params, &password))
+            .await
+            .expect("the blocking thread pool is running")
+    }
+
+    /// Checks `password` against `hash`, or against a dummy hash when there is
+    /// no stored hash. Outputs are compared in constant time.
+    pub async fn verify(&self, hash: Option<String>, password: String) -> Verification {
+        let params = self.params.clone();
+        let (hash, known) = match hash {
+            Some(hash) => (hash, true),
+            None => (self.dummy_hash.clone(), false),
+        };
+        web::block(move || {
+            let Ok(parsed) = PasswordHash::new(&hash) else {
+                return Verification::Invalid;
+            };
+            // Verified even when the user is unknown, for the timing.
+            let verified = argon2(&params).verify_password(password.as_bytes(), &parsed).is_ok();
+            if !known || !verified {
+                return Verification::Invalid;
+            }
+            if is_current(&params, &parsed) {
+                Verification::Valid
+            } else {
+                Verification::Rehashed(hash_with(&params, &password))
+            }
+        })
+        .await
+        .expect("the blocking thread pool is running")
+    }
+}
+
+fn argon2(params: &Params) -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+}
+
+fn hash_with(params: &Params, password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    argon2(params)
+        .hash_password(password.as_bytes(), &salt)
+        .expect("parameters were validated at startup")
+        .to_string()
+}
+
+/// Whether `hash` was made with the algorithm, version and costs in use.
+fn is_current(params: &Params, hash: &PasswordHash<'_>) -> bool {
+    let Ok(stored) = Params::try_from(hash) else {
+        return false;
+    };
+    hash.algorithm == Algorithm::Argon2id.ident()
+        && hash.version == Some(Version::V0x13.into())
+        && stored.m_cost() == params.m_cost()
+        && stored.t_cost() == params.t_cost()
+        && stored.p_cost() == params.p_cost()
+}
+
+/// A user in representation `R` with an optional initial password.
+#[derive(JsonSchema)]
+#[schemars(rename = "{R}Registration")]
+pub struct Registration<R> {
+    #[schemars(flatten)]
+    pub user: R,
+    /// Never returned by the API.
+    #[schemars(length(min = 8, max = 128), extend("writeOnly" = true))]
+    pub password: Option<String>,
+}
+
+impl<R: UserRepresentation> Registration<R> {
+    /// Splits the `password` member off a registration body and parses the
+    /// rest as `R`, so that representations rejecting unknown fields still do.
+    pub fn parse(mut body: Value) -> Result<Self, ApiError> {
+        let password = match body.as_object_mut().and_then(|body| body.remove("password")) {
+            None => None,
+            Some(Value::String(password)) => Some(password),
+            Some(_) => return Err(ApiError::ValidationFailed("password must be a string".into())),
+        };
+        let user = serde_json::from_value(body).map_err(|err| ApiError::ValidationFailed(err.to_string()))?;
+        Ok(Self { user, password })
+    }
+}
+
+/// Checks the password policy.
+pub fn validate_password(password: &str) -> Result<(), ApiError> {
+    if (MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&password.chars().count()) {
+        return Ok(());
+    }
+    Err(ApiError::InvalidFields(vec![FieldViolation {
+        field: "password".into(),
+        code: "length".into(),
+        message: "must be 8 to 128 characters".into(),
+    }]))
+}
+
+/// Validates and hashes the password of a new user, if it has one.
+pub async fn password_hash(passwords: &Passwords, password: Option<String>) -> Result<Option<String>, ApiError> {
+    let Some(password) = password else {
+        return Ok(None);
+    };
+    validate_password(&password)?;
+    Ok(Some(passwords.hash(password).await))
+}
+
+/// Body of `POST /login`.
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Checks a username and password and issues tokens for their user.
+async fn login(repo: web::Data<dyn UserRepository>, passwords: web::Data<Passwords>, tokens: web::Data<Tokens>, grants: web::Data<RoleGrants>, json: web::Json<Credentials>) -> Result<HttpResponse, ApiError> {
+    let (username, roles) = check_credentials(&**repo, &passwords, &grants, json.into_inner()).await?;
+    let generation = repo.find_token_generation(&username).await?.unwrap_or_default();
+    Ok(HttpResponse::Ok().json(tokens.issue(&username, &roles, &generation)))
+}
+
+/// Checks a username and password, upgrading an outdated hash, and returns
+/// the user's username and roles.
+pub async fn check_credentials(repo: &dyn UserRepository, passwords: &Passwords, grants: &RoleGrants, credentials: Credentials) -> Result<(String, Vec<Role>), ApiError> {
+    let Credentials { username, password } = credentials;
+    let hash = repo.find_password_hash(&username).await?;
+    match passwords.verify(hash, password).await {
+        Verification::Invalid => return Err(ApiError::InvalidCredentials),
+        Verification::Valid => {}
+        Verification::Rehashed(hash) => {
+            // The login itself succeeded; the upgrade is retried next time.
+            if let Err(err) = repo.set_password_hash(&username, hash).await {
+                tracing::warn!(error = %err, "cannot store upgraded password hash");
+            }
+        }
+    }
+    let Some(roles) = grants.roles_of(repo, &username).await? else {
+        // Deleted since the hash was read.
+        return Err(ApiError::InvalidCredentials);
+    };
+    Ok((username, roles))
+}
@@ -0,0 +1,346 @@
+//! API keys for callers that act without a user, such as backend jobs.
+//!
+//! Admins create keys with `POST /api-keys`, whose response is the only place
+//! the key ever appears: the store keeps a SHA-256 hash of its secret, in a
+//! collection of its own. A key is sent in the `X-Api-Key` header and grants
+//! the [`Scope`]s it was created with until it expires or is revoked with
+//! `DELETE /api-keys/{id}`.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use actix_web::{dev::ServiceRequest, web, HttpResponse};
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, DateTime},
+    Client, Collection,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+use crate::auth::Principal;
+use crate::error::{ApiError, FieldViolation};
+use crate::model::Scope;
+use crate::policy::{self, Authorize};
+use crate::repository::RepositoryError;
+use crate::secrets::{self, rfc3339};
+
+/// Request header carrying an API key.
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+/// Start of every key, so leaked keys are easy to recognize and scan for.
+const KEY_PREFIX: &str = "uk_";
+
+/// Registers `/api-keys` and `/api-keys/{id}`, both for admins only.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/api-keys")
+            .route(web::post().to(create_key))
+            .route(web::get().to(list_keys))
+            .wrap(Authorize(policy::MANAGE_API_KEYS)),
+    )
+    .service(
+        web::resource("/api-keys/{id}")
+            .route(web::delete().to(revoke_key))
+            .wrap(Authorize(policy::MANAGE_API_KEYS)),
+    );
+}
+
+/// An API key as stored: everything but its secret, which is only kept hashed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ApiKeyRecord {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// [Hash](secrets::hash) of the secret.
+    pub secret_hash: String,
+    /// Username of the admin who created the key.
+    pub created_by: String,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<DateTime>,
+}
+
+impl ApiKeyRecord {
+    /// Whether the key can be used at `now`.
+    fn is_active(&self, now: DateTime) -> bool {
+        self.revoked_at.is_none() && now < self.expires_at
+    }
+}
+
+/// Storage operations for API keys.
+#[async_trait]
+pub trait ApiKeyRepository: Send + Sync {
+    async fn insert(&self, key: ApiKeyRecord) -> Result<(), RepositoryError>;
+
+    /// Gets the key with the supplied id, if any.
+    async fn find(&self, id: &str) -> Result<Option<ApiKeyRecord>, RepositoryError>;
+
+    /// Gets every key, revoked and expired ones included, oldest first.
+    async fn list(&self) -> Result<Vec<ApiKeyRecord>, RepositoryError>;
+
+    /// Marks the key with the supplied id as revoked at `at`, unless it
+    /// already was. Returns `false` when no such key exists.
+    async fn revoke(&self, id: &str, at: DateTime) -> Result<bool, RepositoryError>;
+
+    /// Records that the key with the supplied id was used at `at`.
+    async fn record_use(&self, id: &str, at: DateTime) -> Result<(), RepositoryError>;
+}
+
+/// [`ApiKeyRepository`] backed by a MongoDB collection, keyed by key id.
+#[derive(Clone)]
+pub struct MongoApiKeyRepository {
+    collection: Collection<ApiKeyRecord>,
+}
+
+impl MongoApiKeyRepository {
+    pub fn new(client: &Client, db_name: &str, coll_name: &str) -> Self {
+        Self { collection: client.database(db_name).collection(coll_name) }
+    }
+}
+
+#[async_trait]
+impl ApiKeyRepository for MongoApiKeyRepository {
+    async fn insert(&self, key: ApiKeyRecord) -> Result<(), RepositoryError> {
+        self.collection.insert_one(key).await?;
+        Ok(())
+    }
+
+    async fn find(&self, id: &str) -> Result<Option<ApiKeyRecord>, RepositoryError> {
+        Ok(self.collection.find_one(doc! { "_id": id }).await?)
+    }
+
+    async fn list(&self) -> Result<Vec<ApiKeyRecord>, RepositoryError> {
+        let cursor = self.collection.find(doc! {}).sort(doc! { "created_at": 1, "_id": 1 }).await?;
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn revoke(&self, id: &str, at: DateTime) -> Result<bool, RepositoryError> {
+        // $min keeps the first revocation time when a key is revoked twice.
+        let result = self
+            .collection
+            .update_one(doc! { "_id": id }, doc! { "$min": { "revoked_at": at } })
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn record_use(&self, id: &str, at: DateTime) -> Result<(), RepositoryError> {
+        // $max so concurrent requests cannot move the timestamp backwards.
+        self.collection
+            .update_one(doc! { "_id": id }, doc! { "$max": { "last_used_at": at } })
+            .await?;
+        Ok(())
+    }
+}
+
+/// [`ApiKeyRepository`] that keeps keys in process memory.
+#[derive(Default)]
+pub struct InMemoryApiKeyRepository {
+    keys: RwLock<BTreeMap<String, ApiKeyRecord>>,
+}
+
+impl InMemoryApiKeyRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl ApiKeyRepository for InMemoryApiKeyRepository {
+    async fn insert(&self, key: ApiKeyRecord) -> Result<(), RepositoryError> {
+        let mut keys = self.keys.write().unwrap();
+        if keys.contains_key(&key.id) {
+            return Err(RepositoryError::DuplicateKey("_id".into()));
+        }
+        keys.insert(key.id.clone(), key);
+        Ok(())
+    }
+
+    async fn find(&self, id: &str) -> Result<Option<ApiKeyRecord>, RepositoryError> {
+        Ok(self.keys.read().unwrap().get(id).cloned())
+    }
+
+    async fn list(&self) -> Result<Vec<ApiKeyRecord>, RepositoryError> {
+        let mut keys: Vec<ApiKeyRecord> = self.keys.read().unwrap().values().cloned().collect();
+        keys.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        Ok(keys)
+    }
+
+    async fn revoke(&self, id: &str, at: DateTime) -> Result<bool, RepositoryError> {
+        let mut keys = self.keys.write().unwrap();
+        let Some(key) = keys.get_mut(id) else {
+            return Ok(false);
+        };
+        key.revoked_at = Some(key.revoked_at.map_or(at, |revoked_at| revoked_at.min(at)));
+        Ok(true)
+    }
+
+    async fn record_use(&self, id: &str, at: DateTime) -> Result<(), RepositoryError> {
+        if let Some(key) = self.keys.write().unwrap().get_mut(id) {
+            key.last_used_at = Some(key.last_used_at.map_or(at, |used_at| used_at.max(at)));
+        }
+        Ok(())
+    }
+}
+
+/// Creates and checks API keys.
+pub struct ApiKeys {
+    repo: Arc<dyn ApiKeyRepository>,
+    /// Longest lifetime a key may be created with, and the default one.
+    max_ttl: Duration,
+}
+
+impl ApiKeys {
+    pub fn new(repo: Arc<dyn ApiKeyRepository>, max_ttl: Duration) -> Self {
+        Self { repo, max_ttl }
+    }
+
+    /// Stores a new key and returns it along with its secret.
+    async fn create(&self, request: NewApiKey, created_by: &str) -> Result<(String, ApiKeyRecord), ApiError> {
+        let ttl = match request.expires_in_secs {
+            None => self.max_ttl,
+            Some(secs) if secs <= self.max_ttl.as_secs() => Duration::from_secs(secs),
+            Some(_) => {
+                return Err(ApiError::InvalidFields(vec![FieldViolation {
+                    field: "expires_in_secs".into(),
+                    code: "range".into(),
+                    message: format!("must be at most {}", self.max_ttl.as_secs()),
+                }]))
+            }
+        };
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let secret = secrets::generate();
+        let created_at = DateTime::now();
+        let record = ApiKeyRecord {
+            id: id.clone(),
+            name: request.name,
+            scopes: request.scopes,
+            secret_hash: secrets::hash(&secret),
+            created_by: created_by.into(),
+            created_at,
+            expires_at: secrets::after(created_at, ttl),
+            last_used_at: None,
+            revoked_at: None,
+        };
+        self.repo.insert(record.clone()).await?;
+        Ok((format!("{KEY_PREFIX}{id}.{secret}"), record))
+    }
+
+    /// Returns the caller `key` identifies, if it is an active key, and
+    /// records its use.
+    async fn verify(&self, key: &str) -> Result<Principal, ApiError> {
+        let (id, secret) = key
+            .strip_prefix(KEY_PREFIX)
+            .and_then(|key| key.split_once('.'))
+            .ok_or(ApiError::InvalidApiKey)?;
+        let record = self.repo.find(id).await?.ok_or(ApiError::InvalidApiKey)?;
+        let now = DateTime::now();
+        if !secrets::matches(secret, &record.secret_hash) || !record.is_active(now) {
+            return Err(ApiError::InvalidApiKey);
+        }
+        // The key is valid either way; a lost timestamp only makes it look idle.
+        if let Err(err) = self.repo.record_use(id, now).await {
+            tracing::warn!(error = %err, "cannot record API key use");
+        }
+        Ok(Principal::for_api_key(id, record.scopes))
+    }
+}
+
+/// Reads the `X-Api-Key` header of a request.
+pub async fn authenticate(req: &ServiceRequest) -> Result<Principal, ApiError> {
+    let keys = req.app_data::<web::Data<ApiKeys>>().expect("ApiKeys is registered");
+    let key = req.headers().get(API_KEY_HEADER).ok_or(ApiError::Unauthenticated)?;
+    let key = key.to_str().map_err(|_| ApiError::InvalidApiKey)?;
+    keys.verify(key.trim()).await
+}
+
+/// Body of `POST /api-keys`.
+#[derive(Deserialize, Serialize, JsonSchema, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct NewApiKey {
+    /// Identifies the caller in listings, such as the job's name.
+    #[validate(length(min = 1, max = 100))]
+    #[schemars(length(min = 1, max = 100))]
+    pub name: String,
+    #[validate(length(min = 1, message = "must grant at least one scope"))]
+    #[schemars(length(min = 1))]
+    pub scopes: Vec<Scope>,
+    /// Lifetime of the key in seconds; defaults to the longest allowed.
+    #[validate(range(min = 1))]
+    #[schemars(range(min = 1))]
+    pub expires_in_secs: Option<u64>,
+}
+
+/// An API key as listed: never its secret.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_by: String,
+    #[schemars(extend("format" = "date-time"))]
+    pub created_at: String,
+    #[schemars(extend("format" = "date-time"))]
+    pub expires_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(extend("format" = "date-time"))]
+    pub last_used_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(extend("format" = "date-time"))]
+    pub revoked_at: Option<String>,
+}
+
+impl TryFrom<ApiKeyRecord> for ApiKeyInfo {
+    type Error = RepositoryError;
+
+    fn try_from(record: ApiKeyRecord) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: record.id,
+            name: record.name,
+            scopes: record.scopes,
+            created_by: record.created_by,
+            created_at: rfc3339(record.created_at)?,
+            expires_at: rfc3339(record.expires_at)?,
+            last_used_at: record.last_used_at.map(rfc3339).transpose()?,
+            revoked_at: record.revoked_at.map(rfc3339).transpose()?,
+        })
+    }
+}
+
+/// Response of `POST /api-keys`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct CreatedApiKey {
+    /// The value of the `X-Api-Key` header. It cannot be retrieved again.
+    pub key: String,
+    #[serde(flatten)]
+    pub info: ApiKeyInfo,
+}
+
+async fn create_key(keys: web::Data<ApiKeys>, principal: web::ReqData<Principal>, json: web::Json<NewApiKey>) -> Result<HttpResponse, ApiError> {
+    let request = json.into_inner();
+    request.validate()?;
+    let (key, record) = keys.create(request, &principal.username).await?;
+    Ok(HttpResponse::Created().json(CreatedApiKey { key, info: record.try_into()? }))
+}
+
+async fn list_keys(keys: web::Data<ApiKeys>) -> Result<HttpResponse, ApiError> {
+    let records = keys.repo.list().await?;
+    let infos = records.into_iter().map(ApiKeyInfo::try_from).collect::<Result<Vec<_>, _>>()?;
+    Ok(HttpResponse::Ok().json(infos))
+}
+
+/// Revokes a key; it stays listed with its revocation time.
+async fn revoke_key(keys: web::Data<ApiKeys>, id: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    if keys.repo.revoke(&id, DateTime::now()).await? {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ApiError::ApiKeyNotFound(id))
+    }
+}
@@ -0,0 +1,248 @@
+//! Bearer token authentication.
+//!
+//! `POST /login` issues a short-lived access token and a longer-lived refresh
+//! token, both JWTs naming the user in `sub`; access tokens also carry the
+//! user's roles. Routes wrapped in [`Authorize`](crate::policy::Authorize)
+//! only run with a valid access token, whose user is then available to
+//! handlers as a [`Principal`] request extension.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use actix_web::{dev::ServiceRequest, http::header::AUTHORIZATION, web, HttpResponse};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{AuthConfig, JwtAlgorithm};
+use crate::error::ApiError;
+use crate::model::{Role, Scope};
+use crate::policy::RoleGrants;
+use crate::repository::UserRepository;
+
+/// Registers `POST /token/refresh`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/token/refresh", web::post().to(refresh));
+}
+
+/// The authenticated caller of a request: a user, or an API key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Principal {
+    /// For API keys `api-key:{id}`, which no username can equal.
+    pub username: String,
+    /// Roles the user had when the token was issued.
+    pub roles: Vec<Role>,
+    /// Scopes of the API key; users have none.
+    pub scopes: Vec<Scope>,
+}
+
+impl Principal {
+    pub fn for_api_key(id: &str, scopes: Vec<Scope>) -> Self {
+        Self { username: format!("api-key:{id}"), roles: Vec::new(), scopes }
+    }
+}
+
+/// What a token may be used for, so refresh tokens cannot authorize requests
+/// and access tokens cannot be refreshed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum TokenKind {
+    Access,
+    Refresh,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Claims {
+    sub: String,
+    iss: String,
+    iat: u64,
+    exp: u64,
+    typ: TokenKind,
+    /// Only set in access tokens; refreshing reads the current roles.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    roles: Vec<Role>,
+    /// Only set in refresh tokens: the token generation of the user they were
+    /// issued to, which refreshing checks is still current.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    gen: String,
+}
+
+/// Tokens returned by `POST /login` and `POST /token/refresh`, shaped like an
+/// OAuth 2.0 token response (RFC 6749 section 5.1).
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct TokenPair {
+    pub access_token: String,
+    /// Always `Bearer`.
+    pub token_type: String,
+    /// Lifetime of the access token, in seconds.
+    pub expires_in: u64,
+    pub refresh_token: String,
+    /// Lifetime of the refresh token, in seconds.
+    pub refresh_expires_in: u64,
+}
+
+/// Body of `POST /token/refresh`.
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// Keys verifying tokens: the HS256 secret, or the RS256 keys of the JWKS
+/// by `kid`.
+enum VerifyingKeys {
+    Secret(DecodingKey),
+    Jwks(HashMap<String, DecodingKey>),
+}
+
+/// Issues and verifies tokens.
+pub struct Tokens {
+    header: Header,
+    signing_key: EncodingKey,
+    verifying_keys: VerifyingKeys,
+    validation: Validation,
+    issuer: String,
+    access_ttl: Duration,
+    refresh_ttl: Duration,
+}
+
+impl Tokens {
+    /// Loads the configured keys, reading the RS256 key files.
+    pub fn from_config(config: &AuthConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        match config.algorithm {
+            JwtAlgorithm::Hs256 => Ok(Self::hs256(config, config.secret.as_bytes())),
+            JwtAlgorithm::Rs256 => {
+                let private_key = std::fs::read(&config.private_key_file)
+                    .map_err(|err| format!("cannot read {}: {err}", config.private_key_file))?;
+                let jwks = std::fs::read_to_string(&config.jwks_file)
+                    .map_err(|err| format!("cannot read {}: {err}", config.jwks_file))?;
+                Self::rs256(config, &private_key, &jwks)
+            }
+        }
+    }
+
+    pub fn hs256(config: &AuthConfig, secret: &[u8]) -> Self {
+        Self::new(
+            config,
+            Header::new(Algorithm::HS256),
+            EncodingKey::from_secret(secret),
+            VerifyingKeys::Secret(DecodingKey::from_secret(secret)),
+        )
+    }
+
+    /// Signs with the PEM `private_key` and verifies with the RSA keys of the
+    /// `jwks` document, which must include `config.key_id`.
+    pub fn rs256(config: &AuthConfig, private_key: &[u8], jwks: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let signing_key = EncodingKey::from_rsa_pem(private_key).map_err(|err| format!("invalid RSA private key: {err}"))?;
+        let jwks: JwkSet = serde_json::from_str(jwks).map_err(|err| format!("invalid JWKS: {err}"))?;
+        let mut keys = HashMap::new();
+        for jwk in &jwks.keys {
+            // Keys for other algorithms or without an id cannot verify our tokens.
+            let Some(kid) = &jwk.common.key_id else { continue };
+            if let Ok(key) = DecodingKey::from_jwk(jwk) {
+                keys.insert(kid.clone(), key);
+            }
+        }
+        if !keys.contains_key(&config.key_id) {
+            return Err(format!("the JWKS has no usable key with kid {:?}", config.key_id).into());
+        }
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(config.key_id.clone());
+        Ok(Self::new(config, header, signing_key, VerifyingKeys::Jwks(keys)))
+    }
+
+    fn new(config: &AuthConfig, header: Header, signing_key: EncodingKey, verifying_keys: VerifyingKeys) -> Self {
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&config.issuer]);
+        validation.set_required_spec_claims(&["exp", "iss", "sub"]);
+        Self {
+            header,
+            signing_key,
+            verifying_keys,
+            validation,
+            issuer: config.issuer.clone(),
+            access_ttl: config.access_token_ttl(),
+            refresh_ttl: config.refresh_token_ttl(),
+        }
+    }
+
+    /// Issues a new access and refresh token for `username`, granting `roles`
+    /// until the access token expires. The refresh token is only honoured
+    /// while the user's token generation is still `generation`.
+    pub fn issue(&self, username: &str, roles: &[Role], generation: &str) -> TokenPair {
+        TokenPair {
+            access_token: self.sign(username, TokenKind::Access, self.access_ttl, roles, ""),
+            token_type: "Bearer".into(),
+            expires_in: self.access_ttl.as_secs(),
+            refresh_token: self.sign(username, TokenKind::Refresh, self.refresh_ttl, &[], generation),
+            refresh_expires_in: self.refresh_ttl.as_secs(),
+        }
+    }
+
+    fn sign(&self, username: &str, typ: TokenKind, ttl: Duration, roles: &[Role], generation: &str) -> String {
+        let iat = jsonwebtoken::get_current_timestamp();
+        let claims = Claims {
+            sub: username.into(),
+            iss: self.issuer.clone(),
+            iat,
+            exp: iat.saturating_add(ttl.as_secs()),
+            typ,
+            roles: roles.to_vec(),
+            gen: generation.into(),
+        };
+        jsonwebtoken::encode(&self.header, &claims, &self.signing_key).expect("keys were checked at startup")
+    }
+
+    /// Returns the user `token` was issued to, if it is a valid, unexpired
+    /// token of the given kind.
+    fn verify(&self, token: &str, kind: TokenKind) -> Result<Principal, ApiError> {
+        let claims = self.decode(token, kind)?;
+        Ok(Principal { username: claims.sub, roles: claims.roles, scopes: Vec::new() })
+    }
+
+    fn decode(&self, token: &str, kind: TokenKind) -> Result<Claims, ApiError> {
+        let key = match &self.verifying_keys {
+            VerifyingKeys::Secret(key) => key,
+            VerifyingKeys::Jwks(keys) => {
+                let header = jsonwebtoken::decode_header(token).map_err(|_| ApiError::InvalidToken)?;
+                header.kid.and_then(|kid| keys.get(&kid)).ok_or(ApiError::InvalidToken)?
+            }
+        };
+        let claims = jsonwebtoken::decode::<Claims>(token, key, &self.validation)
+            .map_err(|_| ApiError::InvalidToken)?
+            .claims;
+        if claims.typ != kind {
+            return Err(ApiError::InvalidToken);
+        }
+        Ok(claims)
+    }
+}
+
+/// Exchanges a refresh token for a new token pair with the user's current
+/// roles, as long as the user it was issued to still exists: a user deleted
+/// and created again under the same name has a new token generation.
+async fn refresh(repo: web::Data<dyn UserRepository>, tokens: web::Data<Tokens>, grants: web::Data<RoleGrants>, json: web::Json<RefreshRequest>) -> Result<HttpResponse, ApiError> {
+    let claims = tokens.decode(&json.refresh_token, TokenKind::Refresh)?;
+    if repo.find_token_generation(&claims.sub).await?.as_deref() != Some(claims.gen.as_str()) {
+        return Err(ApiError::InvalidToken);
+    }
+    let Some(roles) = grants.roles_of(&**repo, &claims.sub).await? else {
+        return Err(ApiError::InvalidToken);
+    };
+    Ok(HttpResponse::Ok().json(tokens.issue(&claims.sub, &roles, &claims.gen)))
+}
+
+/// Reads the `Authorization: Bearer` access token of a request.
+pub fn authenticate(req: &ServiceRequest) -> Result<Principal, ApiError> {
+    let tokens = req.app_data::<web::Data<Tokens>>().expect("Tokens is registered");
+    let header = req.headers().get(AUTHORIZATION).ok_or(ApiError::Unauthenticated)?;
+    let token = header
+        .to_str()
+        .ok()
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim())
+        .ok_or(ApiError::InvalidToken)?;
+    tokens.verify(token, TokenKind::Access)
+}
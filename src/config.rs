@@ -0,0 +1,946 @@
+//! Typed service configuration.
+//!
+//! Settings are resolved from, in increasing order of precedence:
+//!
+//! 1. built-in defaults,
+//! 2. an optional TOML file, named by `CONFIG_FILE` or `config.toml` in the
+//!    working directory,
+//! 3. a `.env` file in the working directory,
+//! 4. process environment variables.
+//!
+//! `.env` never overrides a variable that is already set in the environment.
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::pagination::{PageLimits, DEFAULT_MAX_PAGE_SIZE, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE_LIMIT};
+
+/// File read when `CONFIG_FILE` is not set, if it exists.
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// Errors that prevent the service from starting.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    Io { path: String, source: std::io::Error },
+    /// The configuration file is not valid TOML or has unknown settings.
+    Toml { path: String, source: toml::de::Error },
+    /// An environment variable could not be parsed.
+    InvalidEnv { name: &'static str, value: String, reason: String },
+    /// A setting is out of range once every source has been applied.
+    Invalid { setting: &'static str, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => write!(f, "cannot read {path}: {source}"),
+            ConfigError::Toml { path, source } => write!(f, "invalid {path}: {source}"),
+            ConfigError::InvalidEnv { name, value, reason } => {
+                write!(f, "invalid value {value:?} for {name}: {reason}")
+            }
+            ConfigError::Invalid { setting, reason } => write!(f, "invalid {setting}: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Which [`UserRepository`](crate::repository::UserRepository) backs the service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Mongodb,
+    Memory,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "mongodb" => Ok(StorageBackend::Mongodb),
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err("expected \"mongodb\" or \"memory\"".into()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    pub port: u16,
+    /// Worker threads; `None` uses one per physical CPU core.
+    pub workers: Option<usize>,
+    /// Time allowed for a client to send the request head.
+    pub client_request_timeout_secs: u64,
+    /// Time allowed for in-flight requests to finish on shutdown.
+    pub shutdown_timeout_secs: u64,
+    /// Time between a shutdown signal and the listener closing, during which
+    /// `/readyz` already fails so load balancers can stop routing here.
+    pub shutdown_drain_secs: u64,
+    /// Time each `/readyz` check may take before it counts as failed.
+    pub health_check_timeout_ms: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "127.0.0.1".into(),
+            port: 8080,
+            workers: None,
+            client_request_timeout_secs: 5,
+            shutdown_timeout_secs: 30,
+            shutdown_drain_secs: 5,
+            health_check_timeout_ms: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub mongodb_uri: String,
+    pub database: String,
+    pub collection: String,
+    /// Collection holding API keys, apart from users.
+    pub api_keys_collection: String,
+    /// Collection holding cookie sessions, expired by a TTL index.
+    pub sessions_collection: String,
+    /// Collection holding email verification and password reset tokens,
+    /// expired by a TTL index.
+    pub email_tokens_collection: String,
+    pub connect_timeout_secs: u64,
+    pub server_selection_timeout_secs: u64,
+    /// Index creation attempts at startup before starting in degraded mode.
+    pub startup_attempts: u32,
+    /// Delay before the first retry; later delays double up to the maximum.
+    pub retry_initial_backoff_ms: u64,
+    pub retry_max_backoff_secs: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Mongodb,
+            mongodb_uri: "mongodb://localhost:27017".into(),
+            database: "myApp".into(),
+            collection: "users".into(),
+            api_keys_collection: "api_keys".into(),
+            sessions_collection: "sessions".into(),
+            email_tokens_collection: "email_tokens".into(),
+            connect_timeout_secs: 10,
+            server_selection_timeout_secs: 30,
+            startup_attempts: 5,
+            retry_initial_backoff_ms: 500,
+            retry_max_backoff_secs: 30,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PaginationConfig {
+    pub default_page_size: u64,
+    pub max_page_size: u64,
+}
+
+impl Default for PaginationConfig {
+    fn default() -> Self {
+        Self { default_page_size: DEFAULT_PAGE_SIZE, max_page_size: DEFAULT_MAX_PAGE_SIZE }
+    }
+}
+
+/// Where finished trace spans are sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceExporter {
+    /// Spans are not recorded.
+    None,
+    /// OTLP over HTTP with protobuf payloads, to `otlp_endpoint`.
+    Otlp,
+    /// One JSON object per span on standard output.
+    Stdout,
+    /// One JSON object per span, appended to `file_path`.
+    File,
+}
+
+impl FromStr for TraceExporter {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(TraceExporter::None),
+            "otlp" => Ok(TraceExporter::Otlp),
+            "stdout" => Ok(TraceExporter::Stdout),
+            "file" => Ok(TraceExporter::File),
+            _ => Err("expected \"none\", \"otlp\", \"stdout\" or \"file\"".into()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    pub exporter: TraceExporter,
+    pub service_name: String,
+    /// OTLP/HTTP traces endpoint, used by the `otlp` exporter.
+    pub otlp_endpoint: String,
+    /// Output file of the `file` exporter.
+    pub file_path: String,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            exporter: TraceExporter::None,
+            service_name: env!("CARGO_PKG_NAME").into(),
+            otlp_endpoint: "http://localhost:4318/v1/traces".into(),
+            file_path: "traces.jsonl".into(),
+        }
+    }
+}
+
+/// Argon2id costs of new password hashes. Stored hashes with other costs are
+/// upgraded when their owner logs in.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordConfig {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+/// The minimum recommended by OWASP for Argon2id.
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        Self { memory_kib: 19 * 1024, iterations: 2, parallelism: 1 }
+    }
+}
+
+/// How bearer tokens are signed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JwtAlgorithm {
+    /// HMAC with SHA-256, keyed by `secret`.
+    Hs256,
+    /// RSA with SHA-256: issued with `private_key_file`, verified against
+    /// the keys in `jwks_file`.
+    Rs256,
+}
+
+impl FromStr for JwtAlgorithm {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "hs256" => Ok(JwtAlgorithm::Hs256),
+            "rs256" => Ok(JwtAlgorithm::Rs256),
+            _ => Err("expected \"hs256\" or \"rs256\"".into()),
+        }
+    }
+}
+
+/// `SameSite` attribute of the session cookie.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSitePolicy {
+    /// Never sent on cross-site requests.
+    Strict,
+    /// Sent on top-level cross-site navigations, but not cross-site POSTs.
+    Lax,
+    /// Always sent; requires `secure`.
+    None,
+}
+
+impl FromStr for SameSitePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "strict" => Ok(SameSitePolicy::Strict),
+            "lax" => Ok(SameSitePolicy::Lax),
+            "none" => Ok(SameSitePolicy::None),
+            _ => Err("expected \"strict\", \"lax\" or \"none\"".into()),
+        }
+    }
+}
+
+/// Cookie sessions, an alternative to bearer tokens for browsers.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    pub cookie_name: String,
+    /// Lifetime of a session from login; it is not extended by use.
+    pub ttl_secs: u64,
+    /// Whether the cookie is only sent over HTTPS. Browsers also send secure
+    /// cookies to `http://localhost`.
+    pub secure: bool,
+    pub same_site: SameSitePolicy,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            cookie_name: "session".into(),
+            ttl_secs: 8 * 60 * 60,
+            secure: true,
+            same_site: SameSitePolicy::Strict,
+        }
+    }
+}
+
+/// How emails are sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    /// Kept in process memory and never delivered.
+    Memory,
+    /// One JSON object per email, appended to `file_path`.
+    File,
+    /// Delivered through the SMTP server at `smtp_host`.
+    Smtp,
+}
+
+impl FromStr for MailTransport {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "memory" => Ok(MailTransport::Memory),
+            "file" => Ok(MailTransport::File),
+            "smtp" => Ok(MailTransport::Smtp),
+            _ => Err("expected \"memory\", \"file\" or \"smtp\"".into()),
+        }
+    }
+}
+
+/// How the connection to the SMTP server is encrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Upgraded with STARTTLS, usually on port 587.
+    Starttls,
+    /// TLS from the start, usually on port 465.
+    Tls,
+    /// Not encrypted; only for local relays and test servers.
+    None,
+}
+
+impl FromStr for SmtpTls {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "starttls" => Ok(SmtpTls::Starttls),
+            "tls" => Ok(SmtpTls::Tls),
+            "none" => Ok(SmtpTls::None),
+            _ => Err("expected \"starttls\", \"tls\" or \"none\"".into()),
+        }
+    }
+}
+
+/// Placeholder replaced by the token in `verification_url` and `reset_url`.
+pub const TOKEN_PLACEHOLDER: &str = "{token}";
+
+/// Email verification and password reset mails.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    pub transport: MailTransport,
+    /// Sender of every email, such as `Users <no-reply@example.com>`.
+    pub from: String,
+    /// Output file of the `file` transport.
+    pub file_path: String,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub smtp_tls: SmtpTls,
+    /// SMTP credentials; no authentication when the username is empty.
+    pub smtp_username: String,
+    pub smtp_password: String,
+    /// Link sent to verify an email address, with `{token}` in place of the
+    /// token. It should lead to a page that posts the token to
+    /// `/email/verify`.
+    pub verification_url: String,
+    /// Link sent to reset a password, with `{token}` in place of the token.
+    /// It should lead to a page that posts the token and a new password to
+    /// `/password-reset/confirm`.
+    pub reset_url: String,
+    pub verification_ttl_secs: u64,
+    pub reset_ttl_secs: u64,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            transport: MailTransport::Memory,
+            from: "no-reply@localhost".into(),
+            file_path: "mail.jsonl".into(),
+            smtp_host: String::new(),
+            smtp_port: 587,
+            smtp_tls: SmtpTls::Starttls,
+            smtp_username: String::new(),
+            smtp_password: String::new(),
+            verification_url: "http://localhost:8080/verify-email?token={token}".into(),
+            reset_url: "http://localhost:8080/reset-password?token={token}".into(),
+            verification_ttl_secs: 2 * 24 * 60 * 60,
+            reset_ttl_secs: 60 * 60,
+        }
+    }
+}
+
+/// Keeps the SMTP password out of logs and panic messages.
+impl fmt::Debug for MailConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MailConfig")
+            .field("transport", &self.transport)
+            .field("from", &self.from)
+            .field("file_path", &self.file_path)
+            .field("smtp_host", &self.smtp_host)
+            .field("smtp_port", &self.smtp_port)
+            .field("smtp_tls", &self.smtp_tls)
+            .field("smtp_username", &self.smtp_username)
+            .field("smtp_password", &if self.smtp_password.is_empty() { "" } else { "<redacted>" })
+            .field("verification_url", &self.verification_url)
+            .field("reset_url", &self.reset_url)
+            .field("verification_ttl_secs", &self.verification_ttl_secs)
+            .field("reset_ttl_secs", &self.reset_ttl_secs)
+            .finish()
+    }
+}
+
+/// Shortest accepted HS256 secret, in bytes, see RFC 7518 section 3.2.
+pub const MIN_JWT_SECRET_LEN: usize = 32;
+
+/// Longest accepted access token lifetime, in seconds. Access tokens are not
+/// checked against the store, so they outlive their user by at most this long.
+pub const MAX_ACCESS_TOKEN_TTL_SECS: u64 = 60 * 60;
+
+/// Longest accepted lifetime, in seconds, of anything issued with a TTL: ten
+/// years, far from where expiry times overflow.
+pub const MAX_TTL_SECS: u64 = 10 * 365 * 24 * 60 * 60;
+
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub algorithm: JwtAlgorithm,
+    /// HS256 key of at least [`MIN_JWT_SECRET_LEN`] bytes, required with that
+    /// algorithm.
+    pub secret: String,
+    /// PEM-encoded RSA private key signing RS256 tokens.
+    pub private_key_file: String,
+    /// JSON Web Key Set verifying RS256 tokens.
+    pub jwks_file: String,
+    /// `kid` of issued RS256 tokens; must name the key in `jwks_file` that
+    /// matches `private_key_file`.
+    pub key_id: String,
+    /// `iss` claim of issued tokens, required of accepted ones.
+    pub issuer: String,
+    pub access_token_ttl_secs: u64,
+    pub refresh_token_ttl_secs: u64,
+    /// Usernames that have the admin role in addition to their assigned roles,
+    /// to bootstrap role management.
+    pub admins: Vec<String>,
+    /// Longest lifetime of an API key, also used when none is requested.
+    pub api_key_max_ttl_secs: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: JwtAlgorithm::Hs256,
+            secret: String::new(),
+            private_key_file: String::new(),
+            jwks_file: String::new(),
+            key_id: String::new(),
+            issuer: env!("CARGO_PKG_NAME").into(),
+            access_token_ttl_secs: 15 * 60,
+            refresh_token_ttl_secs: 14 * 24 * 60 * 60,
+            admins: Vec::new(),
+            api_key_max_ttl_secs: 365 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// Keeps the secret out of logs and panic messages.
+impl fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("algorithm", &self.algorithm)
+            .field("secret", &if self.secret.is_empty() { "" } else { "<redacted>" })
+            .field("private_key_file", &self.private_key_file)
+            .field("jwks_file", &self.jwks_file)
+            .field("key_id", &self.key_id)
+            .field("issuer", &self.issuer)
+            .field("access_token_ttl_secs", &self.access_token_ttl_secs)
+            .field("refresh_token_ttl_secs", &self.refresh_token_ttl_secs)
+            .field("admins", &self.admins)
+            .field("api_key_max_ttl_secs", &self.api_key_max_ttl_secs)
+            .finish()
+    }
+}
+
+/// Every setting of the service, grouped as in the TOML file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub pagination: PaginationConfig,
+    pub tracing: TracingConfig,
+    pub passwords: PasswordConfig,
+    pub auth: AuthConfig,
+    pub sessions: SessionConfig,
+    pub mail: MailConfig,
+}
+
+/// Parses an environment variable into a setting.
+fn set<T: FromStr>(target: &mut T, name: &'static str, value: String) -> Result<(), ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    *target = value
+        .parse()
+        .map_err(|err: T::Err| ConfigError::InvalidEnv { name, value, reason: err.to_string() })?;
+    Ok(())
+}
+
+impl Config {
+    /// Loads the configuration of the running process.
+    pub fn load() -> Result<Self, ConfigError> {
+        // A missing .env file is not an error.
+        dotenv::dotenv().ok();
+
+        let (path, required) = match std::env::var("CONFIG_FILE") {
+            Ok(path) => (path, true),
+            Err(_) => (DEFAULT_CONFIG_FILE.to_string(), false),
+        };
+        let toml = if required || Path::new(&path).exists() {
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|source| ConfigError::Io { path: path.clone(), source })?;
+            Some((path, contents))
+        } else {
+            None
+        };
+
+        Self::from_sources(
+            toml.as_ref().map(|(path, contents)| (path.as_str(), contents.as_str())),
+            |name| std::env::var(name).ok(),
+        )
+    }
+
+    /// Resolves the configuration from an optional `(path, contents)` TOML file
+    /// and an environment lookup, then validates it.
+    pub fn from_sources(toml: Option<(&str, &str)>, env: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let mut config = match toml {
+            Some((path, contents)) => toml::from_str(contents)
+                .map_err(|source| ConfigError::Toml { path: path.into(), source })?,
+            None => Config::default(),
+        };
+        config.apply_env(env)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self, env: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        let server = &mut self.server;
+        let storage = &mut self.storage;
+        let pagination = &mut self.pagination;
+        let tracing = &mut self.tracing;
+        let passwords = &mut self.passwords;
+        let auth = &mut self.auth;
+        let sessions = &mut self.sessions;
+        let mail = &mut self.mail;
+
+        if let Some(value) = env("BIND_ADDRESS") {
+            server.bind_address = value;
+        }
+        if let Some(value) = env("PORT") {
+            set(&mut server.port, "PORT", value)?;
+        }
+        if let Some(value) = env("WORKERS") {
+            let mut workers = 0;
+            set(&mut workers, "WORKERS", value)?;
+            server.workers = Some(workers);
+        }
+        if let Some(value) = env("CLIENT_REQUEST_TIMEOUT_SECS") {
+            set(&mut server.client_request_timeout_secs, "CLIENT_REQUEST_TIMEOUT_SECS", value)?;
+        }
+        if let Some(value) = env("SHUTDOWN_TIMEOUT_SECS") {
+            set(&mut server.shutdown_timeout_secs, "SHUTDOWN_TIMEOUT_SECS", value)?;
+        }
+        if let Some(value) = env("SHUTDOWN_DRAIN_SECS") {
+            set(&mut server.shutdown_drain_secs, "SHUTDOWN_DRAIN_SECS", value)?;
+        }
+        if let Some(value) = env("HEALTH_CHECK_TIMEOUT_MS") {
+            set(&mut server.health_check_timeout_ms, "HEALTH_CHECK_TIMEOUT_MS", value)?;
+        }
+        if let Some(value) = env("STORAGE_BACKEND") {
+            set(&mut storage.backend, "STORAGE_BACKEND", value)?;
+        }
+        if let Some(value) = env("MONGODB_URI") {
+            storage.mongodb_uri = value;
+        }
+        if let Some(value) = env("DB_NAME") {
+            storage.database = value;
+        }
+        if let Some(value) = env("COLL_NAME") {
+            storage.collection = value;
+        }
+        if let Some(value) = env("API_KEYS_COLL_NAME") {
+            storage.api_keys_collection = value;
+        }
+        if let Some(value) = env("SESSIONS_COLL_NAME") {
+            storage.sessions_collection = value;
+        }
+        if let Some(value) = env("EMAIL_TOKENS_COLL_NAME") {
+            storage.email_tokens_collection = value;
+        }
+        if let Some(value) = env("MONGODB_CONNECT_TIMEOUT_SECS") {
+            set(&mut storage.connect_timeout_secs, "MONGODB_CONNECT_TIMEOUT_SECS", value)?;
+        }
+        if let Some(value) = env("MONGODB_SERVER_SELECTION_TIMEOUT_SECS") {
+            set(&mut storage.server_selection_timeout_secs, "MONGODB_SERVER_SELECTION_TIMEOUT_SECS", value)?;
+        }
+        if let Some(value) = env("MONGODB_STARTUP_ATTEMPTS") {
+            set(&mut storage.startup_attempts, "MONGODB_STARTUP_ATTEMPTS", value)?;
+        }
+        if let Some(value) = env("MONGODB_RETRY_INITIAL_BACKOFF_MS") {
+            set(&mut storage.retry_initial_backoff_ms, "MONGODB_RETRY_INITIAL_BACKOFF_MS", value)?;
+        }
+        if let Some(value) = env("MONGODB_RETRY_MAX_BACKOFF_SECS") {
+            set(&mut storage.retry_max_backoff_secs, "MONGODB_RETRY_MAX_BACKOFF_SECS", value)?;
+        }
+        if let Some(value) = env("DEFAULT_PAGE_SIZE") {
+            set(&mut pagination.default_page_size, "DEFAULT_PAGE_SIZE", value)?;
+        }
+        if let Some(value) = env("MAX_PAGE_SIZE") {
+            set(&mut pagination.max_page_size, "MAX_PAGE_SIZE", value)?;
+        }
+        if let Some(value) = env("OTEL_TRACES_EXPORTER") {
+            set(&mut tracing.exporter, "OTEL_TRACES_EXPORTER", value)?;
+        }
+        if let Some(value) = env("OTEL_SERVICE_NAME") {
+            tracing.service_name = value;
+        }
+        if let Some(value) = env("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+            tracing.otlp_endpoint = value;
+        }
+        if let Some(value) = env("TRACES_FILE") {
+            tracing.file_path = value;
+        }
+        if let Some(value) = env("PASSWORD_HASH_MEMORY_KIB") {
+            set(&mut passwords.memory_kib, "PASSWORD_HASH_MEMORY_KIB", value)?;
+        }
+        if let Some(value) = env("PASSWORD_HASH_ITERATIONS") {
+            set(&mut passwords.iterations, "PASSWORD_HASH_ITERATIONS", value)?;
+        }
+        if let Some(value) = env("PASSWORD_HASH_PARALLELISM") {
+            set(&mut passwords.parallelism, "PASSWORD_HASH_PARALLELISM", value)?;
+        }
+        if let Some(value) = env("JWT_ALGORITHM") {
+            set(&mut auth.algorithm, "JWT_ALGORITHM", value)?;
+        }
+        if let Some(value) = env("JWT_SECRET") {
+            auth.secret = value;
+        }
+        if let Some(value) = env("JWT_PRIVATE_KEY_FILE") {
+            auth.private_key_file = value;
+        }
+        if let Some(value) = env("JWT_JWKS_FILE") {
+            auth.jwks_file = value;
+        }
+        if let Some(value) = env("JWT_KEY_ID") {
+            auth.key_id = value;
+        }
+        if let Some(value) = env("JWT_ISSUER") {
+            auth.issuer = value;
+        }
+        if let Some(value) = env("ACCESS_TOKEN_TTL_SECS") {
+            set(&mut auth.access_token_ttl_secs, "ACCESS_TOKEN_TTL_SECS", value)?;
+        }
+        if let Some(value) = env("REFRESH_TOKEN_TTL_SECS") {
+            set(&mut auth.refresh_token_ttl_secs, "REFRESH_TOKEN_TTL_SECS", value)?;
+        }
+        if let Some(value) = env("ADMIN_USERNAMES") {
+            auth.admins = value.split(',').map(str::trim).filter(|name| !name.is_empty()).map(str::to_string).collect();
+        }
+        if let Some(value) = env("API_KEY_MAX_TTL_SECS") {
+            set(&mut auth.api_key_max_ttl_secs, "API_KEY_MAX_TTL_SECS", value)?;
+        }
+        if let Some(value) = env("SESSION_COOKIE_NAME") {
+            sessions.cookie_name = value;
+        }
+        if let Some(value) = env("SESSION_TTL_SECS") {
+            set(&mut sessions.ttl_secs, "SESSION_TTL_SECS", value)?;
+        }
+        if let Some(value) = env("SESSION_COOKIE_SECURE") {
+            set(&mut sessions.secure, "SESSION_COOKIE_SECURE", value)?;
+        }
+        if let Some(value) = env("SESSION_COOKIE_SAME_SITE") {
+            set(&mut sessions.same_site, "SESSION_COOKIE_SAME_SITE", value)?;
+        }
+        if let Some(value) = env("MAIL_TRANSPORT") {
+            set(&mut mail.transport, "MAIL_TRANSPORT", value)?;
+        }
+        if let Some(value) = env("MAIL_FROM") {
+            mail.from = value;
+        }
+        if let Some(value) = env("MAIL_FILE") {
+            mail.file_path = value;
+        }
+        if let Some(value) = env("SMTP_HOST") {
+            mail.smtp_host = value;
+        }
+        if let Some(value) = env("SMTP_PORT") {
+            set(&mut mail.smtp_port, "SMTP_PORT", value)?;
+        }
+        if let Some(value) = env("SMTP_TLS") {
+            set(&mut mail.smtp_tls, "SMTP_TLS", value)?;
+        }
+        if let Some(value) = env("SMTP_USERNAME") {
+            mail.smtp_username = value;
+        }
+        if let Some(value) = env("SMTP_PASSWORD") {
+            mail.smtp_password = value;
+        }
+        if let Some(value) = env("EMAIL_VERIFICATION_URL") {
+            mail.verification_url = value;
+        }
+        if let Some(value) = env("PASSWORD_RESET_URL") {
+            mail.reset_url = value;
+        }
+        if let Some(value) = env("EMAIL_VERIFICATION_TTL_SECS") {
+            set(&mut mail.verification_ttl_secs, "EMAIL_VERIFICATION_TTL_SECS", value)?;
+        }
+        if let Some(value) = env("PASSWORD_RESET_TTL_SECS") {
+            set(&mut mail.reset_ttl_secs, "PASSWORD_RESET_TTL_SECS", value)?;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |setting, reason: &str| Err(ConfigError::Invalid { setting, reason: reason.into() });
+
+        if self.server.bind_address.trim().is_empty() {
+            return invalid("server.bind_address", "must not be empty");
+        }
+        if self.server.workers == Some(0) {
+            return invalid("server.workers", "must be at least 1");
+        }
+        if self.server.client_request_timeout_secs == 0 {
+            return invalid("server.client_request_timeout_secs", "must be at least 1");
+        }
+        if self.server.health_check_timeout_ms == 0 {
+            return invalid("server.health_check_timeout_ms", "must be at least 1");
+        }
+        // The full connection string is parsed at startup, which may need DNS.
+        let uri = &self.storage.mongodb_uri;
+        if self.storage.backend == StorageBackend::Mongodb && !(uri.starts_with("mongodb://") || uri.starts_with("mongodb+srv://")) {
+            return invalid("storage.mongodb_uri", "must start with mongodb:// or mongodb+srv://");
+        }
+        if self.storage.database.is_empty() {
+            return invalid("storage.database", "must not be empty");
+        }
+        if self.storage.collection.is_empty() {
+            return invalid("storage.collection", "must not be empty");
+        }
+        if self.storage.api_keys_collection.is_empty() {
+            return invalid("storage.api_keys_collection", "must not be empty");
+        }
+        if self.storage.api_keys_collection == self.storage.collection {
+            return invalid("storage.api_keys_collection", "must differ from storage.collection");
+        }
+        if self.storage.sessions_collection.is_empty() {
+            return invalid("storage.sessions_collection", "must not be empty");
+        }
+        if [&self.storage.collection, &self.storage.api_keys_collection].contains(&&self.storage.sessions_collection) {
+            return invalid("storage.sessions_collection", "must differ from storage.collection and storage.api_keys_collection");
+        }
+        if self.storage.email_tokens_collection.is_empty() {
+            return invalid("storage.email_tokens_collection", "must not be empty");
+        }
+        if [&self.storage.collection, &self.storage.api_keys_collection, &self.storage.sessions_collection].contains(&&self.storage.email_tokens_collection) {
+            return invalid("storage.email_tokens_collection", "must differ from the other collections");
+        }
+        if self.storage.connect_timeout_secs == 0 {
+            return invalid("storage.connect_timeout_secs", "must be at least 1");
+        }
+        if self.storage.server_selection_timeout_secs == 0 {
+            return invalid("storage.server_selection_timeout_secs", "must be at least 1");
+        }
+        if self.storage.startup_attempts == 0 {
+            return invalid("storage.startup_attempts", "must be at least 1");
+        }
+        if self.storage.retry_initial_backoff_ms == 0 {
+            return invalid("storage.retry_initial_backoff_ms", "must be at least 1");
+        }
+        if self.storage.retry_max_backoff_secs == 0 {
+            return invalid("storage.retry_max_backoff_secs", "must be at least 1");
+        }
+        if self.storage.retry_max_backoff() < self.storage.retry_initial_backoff() {
+            return invalid("storage.retry_max_backoff_secs", "must not be less than storage.retry_initial_backoff_ms");
+        }
+        if self.pagination.default_page_size == 0 {
+            return invalid("pagination.default_page_size", "must be at least 1");
+        }
+        // A default above the maximum is clamped when a page is requested.
+        if !(1..=MAX_PAGE_SIZE_LIMIT).contains(&self.pagination.max_page_size) {
+            return invalid("pagination.max_page_size", "must be 1 to 10000");
+        }
+        if self.tracing.service_name.is_empty() {
+            return invalid("tracing.service_name", "must not be empty");
+        }
+        if self.tracing.exporter == TraceExporter::Otlp && self.tracing.otlp_endpoint.is_empty() {
+            return invalid("tracing.otlp_endpoint", "must not be empty with the otlp exporter");
+        }
+        if self.tracing.exporter == TraceExporter::File && self.tracing.file_path.is_empty() {
+            return invalid("tracing.file_path", "must not be empty with the file exporter");
+        }
+        let passwords = &self.passwords;
+        if let Err(err) = argon2::Params::new(passwords.memory_kib, passwords.iterations, passwords.parallelism, None) {
+            return invalid("passwords", &err.to_string());
+        }
+        let auth = &self.auth;
+        if auth.algorithm == JwtAlgorithm::Hs256 && auth.secret.len() < MIN_JWT_SECRET_LEN {
+            return invalid("auth.secret", "must be at least 32 bytes with the hs256 algorithm");
+        }
+        if auth.algorithm == JwtAlgorithm::Rs256 {
+            for (setting, value) in [("auth.private_key_file", &auth.private_key_file), ("auth.jwks_file", &auth.jwks_file), ("auth.key_id", &auth.key_id)] {
+                if value.is_empty() {
+                    return invalid(setting, "must not be empty with the rs256 algorithm");
+                }
+            }
+        }
+        if auth.issuer.is_empty() {
+            return invalid("auth.issuer", "must not be empty");
+        }
+        if !(1..=MAX_ACCESS_TOKEN_TTL_SECS).contains(&auth.access_token_ttl_secs) {
+            return invalid("auth.access_token_ttl_secs", "must be 1 to 3600");
+        }
+        if auth.refresh_token_ttl_secs < auth.access_token_ttl_secs {
+            return invalid("auth.refresh_token_ttl_secs", "must be at least auth.access_token_ttl_secs");
+        }
+        if auth.refresh_token_ttl_secs > MAX_TTL_SECS {
+            return invalid("auth.refresh_token_ttl_secs", "must be at most 315360000");
+        }
+        if !(1..=MAX_TTL_SECS).contains(&auth.api_key_max_ttl_secs) {
+            return invalid("auth.api_key_max_ttl_secs", "must be 1 to 315360000");
+        }
+
+        let sessions = &self.sessions;
+        // RFC 6265 section 4.1.1: a cookie name is an RFC 7230 token.
+        if sessions.cookie_name.is_empty() || !sessions.cookie_name.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)) {
+            return invalid("sessions.cookie_name", "must be a non-empty token of letters, digits and !#$%&'*+-.^_`|~");
+        }
+        if !(1..=MAX_TTL_SECS).contains(&sessions.ttl_secs) {
+            return invalid("sessions.ttl_secs", "must be 1 to 315360000");
+        }
+        if sessions.same_site == SameSitePolicy::None && !sessions.secure {
+            return invalid("sessions.same_site", "must not be \"none\" unless sessions.secure is set");
+        }
+
+        let mail = &self.mail;
+        if let Err(err) = mail.from.parse::<lettre::message::Mailbox>() {
+            return invalid("mail.from", &err.to_string());
+        }
+        if mail.transport == MailTransport::File && mail.file_path.is_empty() {
+            return invalid("mail.file_path", "must not be empty with the file transport");
+        }
+        if mail.transport == MailTransport::Smtp && mail.smtp_host.is_empty() {
+            return invalid("mail.smtp_host", "must not be empty with the smtp transport");
+        }
+        if !mail.verification_url.contains(TOKEN_PLACEHOLDER) {
+            return invalid("mail.verification_url", "must contain {token}");
+        }
+        if !mail.reset_url.contains(TOKEN_PLACEHOLDER) {
+            return invalid("mail.reset_url", "must contain {token}");
+        }
+        if !(1..=MAX_TTL_SECS).contains(&mail.verification_ttl_secs) {
+            return invalid("mail.verification_ttl_secs", "must be 1 to 315360000");
+        }
+        if !(1..=MAX_TTL_SECS).contains(&mail.reset_ttl_secs) {
+            return invalid("mail.reset_ttl_secs", "must be 1 to 315360000");
+        }
+        Ok(())
+    }
+
+    pub fn page_limits(&self) -> PageLimits {
+        PageLimits {
+            default_size: self.pagination.default_page_size,
+            max_size: self.pagination.max_page_size,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn shutdown_drain(&self) -> Duration {
+        Duration::from_secs(self.shutdown_drain_secs)
+    }
+
+    pub fn health_check_timeout(&self) -> Duration {
+        Duration::from_millis(self.health_check_timeout_ms)
+    }
+}
+
+impl AuthConfig {
+    pub fn access_token_ttl(&self) -> Duration {
+        Duration::from_secs(self.access_token_ttl_secs)
+    }
+
+    pub fn refresh_token_ttl(&self) -> Duration {
+        Duration::from_secs(self.refresh_token_ttl_secs)
+    }
+
+    pub fn api_key_max_ttl(&self) -> Duration {
+        Duration::from_secs(self.api_key_max_ttl_secs)
+    }
+}
+
+impl SessionConfig {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+impl MailConfig {
+    pub fn verification_ttl(&self) -> Duration {
+        Duration::from_secs(self.verification_ttl_secs)
+    }
+
+    pub fn reset_ttl(&self) -> Duration {
+        Duration::from_secs(self.reset_ttl_secs)
+    }
+}
+
+impl PasswordConfig {
+    /// The Argon2 parameters; only valid once the configuration was validated.
+    pub fn params(&self) -> argon2::Params {
+        argon2::Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .expect("password hash costs were validated")
+    }
+}
+
+impl StorageConfig {
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    pub fn server_selection_timeout(&self) -> Duration {
+        Duration::from_secs(self.server_selection_timeout_secs)
+    }
+
+    pub fn retry_initial_backoff(&self) -> Duration {
+        Duration::from_millis(self.retry_initial_backoff_ms)
+    }
+
+    pub fn retry_max_backoff(&self) -> Duration {
+        Duration::from_secs(self.retry_max_backoff_secs)
+    }
+}
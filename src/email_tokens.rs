@@ -0,0 +1,347 @@
+//! Email verification and password reset.
+//!
+//! Both flows mail the user a link carrying a random token. Tokens are stored
+//! in their own collection, keyed by a hash of the token, and expire a fixed
+//! time after they were sent; a TTL index removes them from MongoDB. Taking a
+//! token deletes it in the same operation, so each one works once, and
+//! sending a new token of either kind replaces the user's earlier one.
+//!
+//! New users are sent a verification link, which they confirm with
+//! `POST /email/verify`. `POST /password-reset` mails a reset link to a
+//! user's verified address, and `POST /password-reset/confirm` sets the new
+//! password, ends the user's cookie sessions and revokes their refresh tokens.
+//! Access tokens already issued stay valid until they expire.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use actix_web::{web, HttpResponse};
+use async_trait::async_trait;
+use mongodb::{
+    bson::{doc, DateTime},
+    options::IndexOptions,
+    Client, Collection, IndexModel,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+
+use crate::accounts::{self, Passwords};
+use crate::config::{MailConfig, TOKEN_PLACEHOLDER};
+use crate::error::ApiError;
+use crate::mailer::{Email, Mailer};
+use crate::repository::{RepositoryError, UserRepository};
+use crate::secrets::{self, rfc3339};
+use crate::sessions::Sessions;
+
+/// Registers `/email/verify`, `/password-reset` and
+/// `/password-reset/confirm`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/email/verify", web::post().to(verify_email))
+        .route("/password-reset", web::post().to(request_reset))
+        .route("/password-reset/confirm", web::post().to(confirm_reset));
+}
+
+/// What a token may be used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Purpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+/// A token as stored.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EmailTokenRecord {
+    /// [Hash](secrets::hash) of the token, which is only known to the
+    /// recipient of the email.
+    #[serde(rename = "_id")]
+    pub id_hash: String,
+    pub purpose: Purpose,
+    pub username: String,
+    /// The address the token was sent to.
+    pub email: String,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+}
+
+/// Storage operations for email tokens.
+#[async_trait]
+pub trait EmailTokenRepository: Send + Sync {
+    async fn insert(&self, token: EmailTokenRecord) -> Result<(), RepositoryError>;
+
+    /// Deletes and returns the token for `purpose` whose value has the
+    /// supplied hash, if any. It may have expired but not been removed yet.
+    async fn take(&self, id_hash: &str, purpose: Purpose) -> Result<Option<EmailTokenRecord>, RepositoryError>;
+
+    /// Deletes every token for `purpose` of the user with the supplied
+    /// username.
+    async fn delete_for_user(&self, username: &str, purpose: Purpose) -> Result<(), RepositoryError>;
+}
+
+/// [`EmailTokenRepository`] backed by a MongoDB collection.
+#[derive(Clone)]
+pub struct MongoEmailTokenRepository {
+    collection: Collection<EmailTokenRecord>,
+}
+
+impl MongoEmailTokenRepository {
+    pub fn new(client: &Client, db_name: &str, coll_name: &str) -> Self {
+        Self { collection: client.database(db_name).collection(coll_name) }
+    }
+
+    /// Creates the TTL index removing expired tokens, and the index on
+    /// usernames used to replace a user's tokens.
+    pub async fn create_indexes(&self) -> Result<(), RepositoryError> {
+        let expiry = IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+            .build();
+        let username = IndexModel::builder().keys(doc! { "username": 1, "purpose": 1 }).build();
+        self.collection.create_indexes([expiry, username]).await?;
+        Ok(())
+    }
+}
+
+fn purpose_bson(purpose: Purpose) -> mongodb::bson::Bson {
+    mongodb::bson::to_bson(&purpose).expect("purposes always serialize")
+}
+
+#[async_trait]
+impl EmailTokenRepository for MongoEmailTokenRepository {
+    async fn insert(&self, token: EmailTokenRecord) -> Result<(), RepositoryError> {
+        self.collection.insert_one(token).await?;
+        Ok(())
+    }
+
+    async fn take(&self, id_hash: &str, purpose: Purpose) -> Result<Option<EmailTokenRecord>, RepositoryError> {
+        Ok(self.collection.find_one_and_delete(doc! { "_id": id_hash, "purpose": purpose_bson(purpose) }).await?)
+    }
+
+    async fn delete_for_user(&self, username: &str, purpose: Purpose) -> Result<(), RepositoryError> {
+        self.collection.delete_many(doc! { "username": username, "purpose": purpose_bson(purpose) }).await?;
+        Ok(())
+    }
+}
+
+/// [`EmailTokenRepository`] that keeps tokens in process memory.
+///
+/// Expired tokens are dropped whenever one is added, standing in for the TTL
+/// index.
+#[derive(Default)]
+pub struct InMemoryEmailTokenRepository {
+    tokens: RwLock<BTreeMap<String, EmailTokenRecord>>,
+}
+
+impl InMemoryEmailTokenRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl EmailTokenRepository for InMemoryEmailTokenRepository {
+    async fn insert(&self, token: EmailTokenRecord) -> Result<(), RepositoryError> {
+        let mut tokens = self.tokens.write().unwrap();
+        let now = DateTime::now();
+        tokens.retain(|_, token| now < token.expires_at);
+        if tokens.contains_key(&token.id_hash) {
+            return Err(RepositoryError::DuplicateKey("_id".into()));
+        }
+        tokens.insert(token.id_hash.clone(), token);
+        Ok(())
+    }
+
+    async fn take(&self, id_hash: &str, purpose: Purpose) -> Result<Option<EmailTokenRecord>, RepositoryError> {
+        let mut tokens = self.tokens.write().unwrap();
+        if tokens.get(id_hash).is_none_or(|token| token.purpose != purpose) {
+            return Ok(None);
+        }
+        Ok(tokens.remove(id_hash))
+    }
+
+    async fn delete_for_user(&self, username: &str, purpose: Purpose) -> Result<(), RepositoryError> {
+        self.tokens.write().unwrap().retain(|_, token| token.username != username || token.purpose != purpose);
+        Ok(())
+    }
+}
+
+/// Issues, mails and redeems email tokens.
+pub struct EmailTokens {
+    repo: Arc<dyn EmailTokenRepository>,
+    mailer: Arc<dyn Mailer>,
+    verification_url: String,
+    reset_url: String,
+    verification_ttl: Duration,
+    reset_ttl: Duration,
+}
+
+impl EmailTokens {
+    pub fn new(repo: Arc<dyn EmailTokenRepository>, mailer: Arc<dyn Mailer>, config: &MailConfig) -> Self {
+        Self {
+            repo,
+            mailer,
+            verification_url: config.verification_url.clone(),
+            reset_url: config.reset_url.clone(),
+            verification_ttl: config.verification_ttl(),
+            reset_ttl: config.reset_ttl(),
+        }
+    }
+
+    /// Stores a new token for `purpose` in place of the user's earlier ones,
+    /// and returns it with its expiry.
+    async fn issue(&self, purpose: Purpose, username: &str, email: &str) -> Result<(String, DateTime), RepositoryError> {
+        let ttl = match purpose {
+            Purpose::VerifyEmail => self.verification_ttl,
+            Purpose::ResetPassword => self.reset_ttl,
+        };
+        self.repo.delete_for_user(username, purpose).await?;
+        let token = secrets::generate();
+        let created_at = DateTime::now();
+        let record = EmailTokenRecord {
+            id_hash: secrets::hash(&token),
+            purpose,
+            username: username.into(),
+            email: email.into(),
+            created_at,
+            expires_at: secrets::after(created_at, ttl),
+        };
+        self.repo.insert(record.clone()).await?;
+        Ok((token, record.expires_at))
+    }
+
+    /// Mails `email` a link verifying that it belongs to `username`.
+    pub async fn send_verification(&self, username: &str, email: &str) -> Result<(), ApiError> {
+        let (token, expires_at) = self.issue(Purpose::VerifyEmail, username, email).await?;
+        let link = self.verification_url.replace(TOKEN_PLACEHOLDER, &token);
+        let body = format!(
+            "Hello {username},\n\nConfirm that this is your email address by opening this link:\n\n{link}\n\n\
+             The link works once, until {}. If you did not sign up, ignore this email.\n",
+            rfc3339(expires_at)?
+        );
+        let email = Email { to: email.into(), subject: "Verify your email address".into(), body };
+        self.mailer.send(email).await.map_err(ApiError::MailUnavailable)
+    }
+
+    /// Mails `email`, the verified address of `username`, a link to reset
+    /// their password.
+    async fn send_reset(&self, username: &str, email: &str) -> Result<(), ApiError> {
+        let (token, expires_at) = self.issue(Purpose::ResetPassword, username, email).await?;
+        let link = self.reset_url.replace(TOKEN_PLACEHOLDER, &token);
+        let body = format!(
+            "Hello {username},\n\nSomeone asked to reset your password. Choose a new one by opening this link:\n\n{link}\n\n\
+             The link works once, until {}. If you did not ask for it, ignore this email; your password is unchanged.\n",
+            rfc3339(expires_at)?
+        );
+        let email = Email { to: email.into(), subject: "Reset your password".into(), body };
+        self.mailer.send(email).await.map_err(ApiError::MailUnavailable)
+    }
+
+    /// Redeems an unexpired token for `purpose`.
+    async fn take(&self, token: &str, purpose: Purpose) -> Result<EmailTokenRecord, ApiError> {
+        let record = self.repo.take(&secrets::hash(token), purpose).await?;
+        record
+            .filter(|record| DateTime::now() < record.expires_at)
+            .ok_or(ApiError::InvalidEmailToken)
+    }
+}
+
+/// Body of `POST /email/verify`.
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VerifyEmailRequest {
+    /// The token from the verification link.
+    pub token: String,
+}
+
+/// Body of `POST /password-reset`.
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PasswordResetRequest {
+    pub username: String,
+}
+
+/// Body of `POST /password-reset/confirm`.
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PasswordResetConfirmation {
+    /// The token from the reset link.
+    pub token: String,
+    #[schemars(length(min = 8, max = 128), extend("writeOnly" = true))]
+    pub password: String,
+}
+
+/// Marks the address a verification token was sent to as verified, unless
+/// the user has changed address or been deleted since.
+async fn verify_email(repo: web::Data<dyn UserRepository>, tokens: web::Data<EmailTokens>, json: web::Json<VerifyEmailRequest>) -> Result<HttpResponse, ApiError> {
+    let record = tokens.take(&json.token, Purpose::VerifyEmail).await?;
+    if !repo.set_verified_email(&record.username, &record.email).await? {
+        return Err(ApiError::InvalidEmailToken);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Runs `send` after the response, in the span of the request, logging its
+/// failure with `message`. The response neither waits on the mail server nor
+/// reveals by its timing whether anything was sent.
+pub fn send_in_background(message: &'static str, send: impl Future<Output = Result<(), ApiError>> + 'static) {
+    let send = async move {
+        if let Err(err) = send.await {
+            tracing::warn!(error = %err, "{message}");
+        }
+    };
+    actix_rt::spawn(send.instrument(tracing::Span::current()));
+}
+
+/// Mails a reset link to the user's verified address.
+///
+/// Answers at once and the same whether or not the user exists or has a
+/// verified address, so usernames cannot be probed.
+async fn request_reset(repo: web::Data<dyn UserRepository>, tokens: web::Data<EmailTokens>, json: web::Json<PasswordResetRequest>) -> Result<HttpResponse, ApiError> {
+    let username = json.into_inner().username;
+    send_in_background("cannot send password reset email", async move {
+        match repo.find_verified_email(&username).await? {
+            Some(email) => tokens.send_reset(&username, &email).await,
+            None => Ok(()),
+        }
+    });
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Sets a new password with a reset token and ends the user's sessions.
+///
+/// The token only works while the address it was sent to is still the
+/// user's verified address, so it cannot reset the password of a new user of
+/// the same name or one who has since changed address.
+async fn confirm_reset(repo: web::Data<dyn UserRepository>, passwords: web::Data<Passwords>, tokens: web::Data<EmailTokens>, sessions: web::Data<Sessions>, json: web::Json<PasswordResetConfirmation>) -> Result<HttpResponse, ApiError> {
+    let PasswordResetConfirmation { token, password } = json.into_inner();
+    // Checked first, so a rejected password does not use up the token.
+    accounts::validate_password(&password)?;
+    let record = tokens.take(&token, Purpose::ResetPassword).await?;
+    if repo.find_verified_email(&record.username).await?.as_deref() != Some(record.email.as_str()) {
+        return Err(ApiError::InvalidEmailToken);
+    }
+    let hash = passwords.hash(password).await;
+    if !repo.set_password_hash(&record.username, hash).await? {
+        return Err(ApiError::InvalidEmailToken);
+    }
+    sessions.end_all(&record.username).await?;
+    repo.rotate_token_generation(&record.username).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Mails the user with the supplied username a new verification link, unless
+/// their address is already verified.
+pub async fn resend_verification(repo: web::Data<dyn UserRepository>, tokens: web::Data<EmailTokens>, username: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let username = username.into_inner();
+    let Some(user) = repo.find_by_username(&username).await? else {
+        return Err(ApiError::UserNotFound(username));
+    };
+    if repo.find_verified_email(&username).await?.as_deref() == Some(user.email.as_str()) {
+        return Ok(HttpResponse::NoContent().finish());
+    }
+    tokens.send_verification(&username, &user.email).await?;
+    Ok(HttpResponse::Accepted().finish())
+}
@@ -0,0 +1,284 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use actix_web::{
+    error::{JsonPayloadError, QueryPayloadError},
+    http::{header::WWW_AUTHENTICATE, StatusCode},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use validator::ValidationErrors;
+
+use crate::logging;
+use crate::mailer::MailError;
+use crate::repository::RepositoryError;
+
+/// Content type of every error body, see RFC 7807.
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Errors returned by the HTTP handlers.
+///
+/// Every variant renders as an RFC 7807 problem document whose `code` member is
+/// stable and safe for clients to match on.
+#[derive(Debug)]
+pub enum ApiError {
+    /// No user has the requested username.
+    UserNotFound(String),
+    /// Another user already has the same value for a uniquely indexed field;
+    /// holds the name of that field.
+    Duplicate(String),
+    /// The request body could not be parsed.
+    ValidationFailed(String),
+    /// The request body parsed but some fields hold unacceptable values.
+    InvalidFields(Vec<FieldViolation>),
+    /// A JSON Patch `test` operation did not hold.
+    PatchTestFailed(String),
+    /// The user changed between being read and being patched; holds the
+    /// username.
+    ConcurrentUpdate(String),
+    /// The request body has a content type the endpoint does not accept.
+    UnsupportedMediaType(String),
+    /// The user store failed to complete the operation.
+    StorageUnavailable(RepositoryError),
+    /// The username and password do not match an account.
+    InvalidCredentials,
+    /// The request has no bearer token but the route requires one.
+    Unauthenticated,
+    /// The bearer or refresh token is malformed, forged, expired or of the
+    /// wrong kind.
+    InvalidToken,
+    /// The caller's roles or API key scopes do not allow the action; holds its
+    /// description.
+    Forbidden(&'static str),
+    /// The API key is malformed, unknown, expired or revoked.
+    InvalidApiKey,
+    /// The session cookie names no current session.
+    InvalidSession,
+    /// No API key has the requested id.
+    ApiKeyNotFound(String),
+    /// The email verification or password reset token is unknown, used or
+    /// expired.
+    InvalidEmailToken,
+    /// The email could not be handed to the mail server.
+    MailUnavailable(MailError),
+}
+
+/// RFC 7807 problem document, extended with a machine-readable `code`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub type_uri: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub code: String,
+    /// The field that caused a `duplicate_*` conflict.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    /// Every rejected field of an `InvalidFields` error.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldViolation>,
+    /// The `X-Request-Id` of the failed request, for correlating with logs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// A single rule a request field failed.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub struct FieldViolation {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+impl ApiError {
+    /// Stable identifier of the error kind.
+    ///
+    /// Conflicts are reported per field, e.g. `duplicate_username` or `duplicate_email`.
+    pub fn code(&self) -> Cow<'static, str> {
+        match self {
+            ApiError::UserNotFound(_) => "user_not_found".into(),
+            ApiError::Duplicate(field) => format!("duplicate_{field}").into(),
+            ApiError::ValidationFailed(_) | ApiError::InvalidFields(_) => "validation_failed".into(),
+            ApiError::PatchTestFailed(_) => "patch_test_failed".into(),
+            ApiError::ConcurrentUpdate(_) => "concurrent_update".into(),
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type".into(),
+            ApiError::StorageUnavailable(_) => "storage_unavailable".into(),
+            ApiError::InvalidCredentials => "invalid_credentials".into(),
+            ApiError::Unauthenticated => "unauthenticated".into(),
+            ApiError::InvalidToken => "invalid_token".into(),
+            ApiError::Forbidden(_) => "forbidden".into(),
+            ApiError::InvalidApiKey => "invalid_api_key".into(),
+            ApiError::InvalidSession => "invalid_session".into(),
+            ApiError::ApiKeyNotFound(_) => "api_key_not_found".into(),
+            ApiError::InvalidEmailToken => "invalid_email_token".into(),
+            ApiError::MailUnavailable(_) => "mail_unavailable".into(),
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            ApiError::UserNotFound(_) => "User not found",
+            ApiError::Duplicate(_) => "Duplicate value",
+            ApiError::ValidationFailed(_) | ApiError::InvalidFields(_) => "Validation failed",
+            ApiError::PatchTestFailed(_) => "Patch test failed",
+            ApiError::ConcurrentUpdate(_) => "Concurrent update",
+            ApiError::UnsupportedMediaType(_) => "Unsupported media type",
+            ApiError::StorageUnavailable(_) => "Storage unavailable",
+            ApiError::InvalidCredentials => "Invalid credentials",
+            ApiError::Unauthenticated => "Authentication required",
+            ApiError::InvalidToken => "Invalid token",
+            ApiError::Forbidden(_) => "Forbidden",
+            ApiError::InvalidApiKey => "Invalid API key",
+            ApiError::InvalidSession => "Invalid session",
+            ApiError::ApiKeyNotFound(_) => "API key not found",
+            ApiError::InvalidEmailToken => "Invalid email token",
+            ApiError::MailUnavailable(_) => "Mail unavailable",
+        }
+    }
+
+    /// Builds the problem document describing this error.
+    pub fn problem(&self) -> ProblemDetails {
+        ProblemDetails {
+            type_uri: format!("/problems/{}", self.code()),
+            title: self.title().into(),
+            status: self.status_code().as_u16(),
+            detail: self.to_string(),
+            code: self.code().into_owned(),
+            field: match self {
+                ApiError::Duplicate(field) => Some(field.clone()),
+                _ => None,
+            },
+            errors: match self {
+                ApiError::InvalidFields(violations) => violations.clone(),
+                _ => Vec::new(),
+            },
+            request_id: logging::current_request_id(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::UserNotFound(username) => write!(f, "No user found with username {username}"),
+            ApiError::Duplicate(field) => write!(f, "A user with this {field} already exists"),
+            ApiError::ValidationFailed(reason) => f.write_str(reason),
+            ApiError::InvalidFields(violations) => {
+                write!(f, "{} field(s) failed validation", violations.len())
+            }
+            ApiError::PatchTestFailed(reason) => f.write_str(reason),
+            ApiError::ConcurrentUpdate(username) => {
+                write!(f, "User {username} was changed by another request; fetch it and retry")
+            }
+            ApiError::UnsupportedMediaType(content_type) => {
+                write!(f, "Content type {content_type:?} is not supported here")
+            }
+            // The driver message may reveal deployment details, so it is not echoed.
+            ApiError::StorageUnavailable(_) => f.write_str("The user store could not complete the request"),
+            // Does not say which part was wrong, so usernames cannot be probed.
+            ApiError::InvalidCredentials => f.write_str("The username or password is incorrect"),
+            ApiError::Unauthenticated => f.write_str("A bearer access token, API key or session cookie is required"),
+            ApiError::InvalidToken => f.write_str("The token is invalid or expired"),
+            ApiError::Forbidden(action) => write!(f, "Your roles or scopes do not allow you to {action}"),
+            ApiError::InvalidApiKey => f.write_str("The API key is invalid, expired or revoked"),
+            ApiError::InvalidSession => f.write_str("The session has ended or expired"),
+            ApiError::ApiKeyNotFound(id) => write!(f, "No API key found with id {id}"),
+            ApiError::InvalidEmailToken => f.write_str("The link is invalid, already used or expired"),
+            // Like storage errors, the mail server's reply is only logged.
+            ApiError::MailUnavailable(_) => f.write_str("The email could not be sent"),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApiError::StorageUnavailable(err) => Some(err),
+            ApiError::MailUnavailable(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::UserNotFound(_) | ApiError::ApiKeyNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Duplicate(_) => StatusCode::CONFLICT,
+            ApiError::ValidationFailed(_) | ApiError::InvalidEmailToken => StatusCode::BAD_REQUEST,
+            ApiError::InvalidFields(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::PatchTestFailed(_) | ApiError::ConcurrentUpdate(_) => StatusCode::CONFLICT,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::StorageUnavailable(_) | ApiError::MailUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::InvalidCredentials | ApiError::Unauthenticated | ApiError::InvalidToken | ApiError::InvalidApiKey | ApiError::InvalidSession => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ApiError::StorageUnavailable(err) => tracing::error!(error = %err, "storage operation failed"),
+            ApiError::MailUnavailable(err) => tracing::error!(error = %err, "sending email failed"),
+            _ => {}
+        }
+        let mut response = HttpResponse::build(self.status_code());
+        // RFC 6750 section 3. API keys have no registered scheme; theirs
+        // mirrors the bearer one.
+        match self {
+            ApiError::Unauthenticated => {
+                response.insert_header((WWW_AUTHENTICATE, "Bearer"));
+            }
+            ApiError::InvalidToken => {
+                response.insert_header((WWW_AUTHENTICATE, "Bearer error=\"invalid_token\""));
+            }
+            ApiError::InvalidApiKey => {
+                response.insert_header((WWW_AUTHENTICATE, "ApiKey error=\"invalid_api_key\""));
+            }
+            _ => {}
+        }
+        response.content_type(PROBLEM_JSON).json(self.problem())
+    }
+}
+
+impl From<RepositoryError> for ApiError {
+    fn from(err: RepositoryError) -> Self {
+        match err {
+            RepositoryError::DuplicateKey(field) => ApiError::Duplicate(field),
+            err => ApiError::StorageUnavailable(err),
+        }
+    }
+}
+
+impl From<ValidationErrors> for ApiError {
+    fn from(errors: ValidationErrors) -> Self {
+        let mut violations: Vec<FieldViolation> = errors
+            .field_errors()
+            .into_iter()
+            .flat_map(|(field, errors)| {
+                errors.iter().map(move |error| FieldViolation {
+                    field: field.to_string(),
+                    code: error.code.to_string(),
+                    message: error
+                        .message
+                        .as_deref()
+                        .unwrap_or("is invalid")
+                        .to_string(),
+                })
+            })
+            .collect();
+        violations.sort_by(|a, b| a.field.cmp(&b.field));
+        ApiError::InvalidFields(violations)
+    }
+}
+
+/// Renders malformed JSON request bodies as `validation_failed` problems.
+pub fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::ValidationFailed(err.to_string()).into()
+}
+
+/// Renders malformed query strings as `validation_failed` problems.
+pub fn query_error_handler(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::ValidationFailed(err.to_string()).into()
+}
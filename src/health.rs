@@ -0,0 +1,115 @@
+//! Liveness and readiness probes.
+//!
+//! `/healthz` answers as long as the process can serve HTTP at all, while
+//! `/readyz` also checks the store and reports not ready once shutdown has
+//! begun, so load balancers stop routing new requests here while in-flight
+//! ones drain.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::{web, HttpResponse};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::repository::UserRepository;
+use crate::startup::Readiness;
+
+/// State the probes read, registered as application data.
+#[derive(Clone, Debug)]
+pub struct Probe {
+    pub readiness: Arc<Readiness>,
+    /// Time each readiness check may take before it counts as failed.
+    pub check_timeout: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Fail,
+}
+
+/// Outcome of a single check.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub latency_ms: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Body of both probes; `status` is `ok` only if every check is.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Report {
+    pub status: Status,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    fn new(checks: Vec<Check>) -> Self {
+        let status = if checks.iter().all(|check| check.status == Status::Ok) { Status::Ok } else { Status::Fail };
+        Self { status, checks }
+    }
+
+    fn respond(self) -> HttpResponse {
+        let mut response = match self.status {
+            Status::Ok => HttpResponse::Ok(),
+            Status::Fail => HttpResponse::ServiceUnavailable(),
+        };
+        response.insert_header(("Cache-Control", "no-store")).json(self)
+    }
+}
+
+/// Registers the probe endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/healthz", web::get().to(healthz))
+        .route("/readyz", web::get().to(readyz));
+}
+
+/// Reports that the process is alive; never touches the store.
+async fn healthz() -> HttpResponse {
+    Report::new(Vec::new()).respond()
+}
+
+/// Reports whether this instance should receive traffic: it is not shutting
+/// down, the store answers a ping, and the unique username index exists.
+async fn readyz(repo: web::Data<dyn UserRepository>, probe: web::Data<Probe>) -> HttpResponse {
+    let shutdown = Check {
+        name: "shutdown".into(),
+        status: if probe.readiness.is_draining() { Status::Fail } else { Status::Ok },
+        latency_ms: 0.0,
+        error: probe.readiness.is_draining().then(|| "shutting down".into()),
+    };
+    let (ping, index) = futures_util::join!(
+        check("storage", probe.check_timeout, repo.ping()),
+        check("username_index", probe.check_timeout, async {
+            match repo.username_index_exists().await {
+                Ok(true) => Ok(()),
+                Ok(false) => Err("index does not exist".to_string()),
+                Err(err) => Err(err.to_string()),
+            }
+        }),
+    );
+    Report::new(vec![shutdown, ping, index]).respond()
+}
+
+/// Runs one check, timing it and failing it after `timeout`.
+async fn check<E: ToString>(name: &str, timeout: Duration, check: impl Future<Output = Result<(), E>>) -> Check {
+    let started = Instant::now();
+    let result = actix_rt::time::timeout(timeout, check).await;
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let error = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(err)) => Some(err.to_string()),
+        Err(_) => Some(format!("timed out after {} ms", timeout.as_millis())),
+    };
+    Check {
+        name: name.into(),
+        status: if error.is_none() { Status::Ok } else { Status::Fail },
+        latency_ms,
+        error,
+    }
+}
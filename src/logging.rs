@@ -0,0 +1,177 @@
+//! Structured JSON logs and request correlation.
+//!
+//! Every request runs inside a `request` span carrying its `request_id`, so
+//! each log line written while handling it names the request. The id is taken
+//! from the `X-Request-Id` request header when it is well formed, generated
+//! otherwise, and echoed in the response header and in problem documents.
+//!
+//! The same span is exported as the OpenTelemetry server span of the request
+//! when tracing is enabled, see [`telemetry`](crate::telemetry).
+
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::Instant;
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue, USER_AGENT},
+    Error,
+};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing::{field::Empty, Instrument, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{filter::LevelFilter, fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan, Layer, Registry};
+
+use crate::metrics::UNMATCHED_ROUTE;
+use crate::telemetry;
+
+/// Header carrying the request id, in both directions.
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest client-supplied request id that is propagated rather than replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Builds a layer writing one JSON object per log line to `writer`, with the
+/// fields of the innermost span under `span`.
+fn json_layer<S, W>(writer: W) -> impl Layer<S>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_current_span(true)
+        .with_span_list(false)
+        .with_writer(writer)
+}
+
+/// Builds a subscriber writing JSON logs to `writer` and, with a `provider`,
+/// exporting spans through it.
+pub fn subscriber<W>(writer: W, provider: Option<&SdkTracerProvider>) -> impl Subscriber + Send + Sync
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    use opentelemetry::trace::TracerProvider;
+
+    let traces = provider.map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME"))));
+    Registry::default().with(LevelFilter::INFO).with(json_layer(writer)).with(traces)
+}
+
+/// Installs the subscriber, logging to stdout, for the whole process.
+pub fn init(provider: Option<&SdkTracerProvider>) {
+    tracing::subscriber::set_global_default(subscriber(std::io::stdout, provider))
+        .expect("no other subscriber is installed");
+}
+
+/// The id of the request being handled by the current task, if any.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Uses the client's id if it is short printable ASCII, so it cannot forge
+/// log structure or headers, and a random UUID otherwise.
+fn request_id(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic()))
+        .map_or_else(|| uuid::Uuid::new_v4().to_string(), str::to_string)
+}
+
+/// Middleware assigning request ids and writing one access log line per request.
+pub struct RequestLogging;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestLogging
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestLoggingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestLoggingMiddleware { service: Rc::new(service) }))
+    }
+}
+
+pub struct RequestLoggingMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestLoggingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let id = request_id(&req);
+        let span = tracing::info_span!(
+            "request",
+            request_id = %id,
+            trace_id = Empty,
+            otel.name = %req.method(),
+            otel.kind = "server",
+            otel.status_code = Empty,
+            http.request.method = %req.method(),
+            http.route = Empty,
+            http.response.status_code = Empty,
+        );
+        // Only fails when spans are not exported, in which case there is no trace to join.
+        let _ = span.set_parent(telemetry::extract_trace_context(req.headers()));
+        let trace_id = span.context().span().span_context().trace_id();
+        if trace_id != opentelemetry::trace::TraceId::INVALID {
+            span.record("trace_id", trace_id.to_string());
+        }
+        let request_span = span.clone();
+        let handled = REQUEST_ID.scope(id.clone(), async move {
+            let started = Instant::now();
+            let method = req.method().clone();
+            let user_agent = req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+
+            let mut response = service.call(req).await?;
+            let route = response.request().match_pattern();
+            if let Some(route) = &route {
+                // The OpenTelemetry span has already started, so `otel.name` can no longer be recorded.
+                request_span.context().span().update_name(format!("{method} {route}"));
+                request_span.record("http.route", route.as_str());
+            }
+            request_span.record("http.response.status_code", i64::from(response.status().as_u16()));
+            if response.status().is_server_error() {
+                request_span.record("otel.status_code", "error");
+            }
+            tracing::info!(
+                target: "access",
+                method = %method,
+                route = route.as_deref().unwrap_or(UNMATCHED_ROUTE),
+                status = response.status().as_u16(),
+                duration_ms = started.elapsed().as_secs_f64() * 1000.0,
+                user_agent,
+                "request completed"
+            );
+            let id = HeaderValue::from_str(&id).expect("request ids are printable ASCII");
+            response.headers_mut().insert(REQUEST_ID_HEADER, id);
+            Ok(response)
+        });
+        Box::pin(handled.instrument(span))
+    }
+}
@@ -0,0 +1,158 @@
+//! Outgoing email.
+//!
+//! Handlers send email through a [`Mailer`], chosen by `mail.transport`:
+//! [`SmtpMailer`] delivers it, while [`JsonLinesMailer`] and
+//! [`InMemoryMailer`] only record it, so flows that send links by email can be
+//! followed offline and in tests.
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use lettre::message::{header::ContentType, Mailbox};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use mongodb::bson::DateTime;
+use serde_json::json;
+
+use crate::config::{MailConfig, MailTransport, SmtpTls};
+use crate::secrets::rfc3339;
+
+/// A plain text email to a single recipient.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// The email could not be built or handed over.
+#[derive(Debug)]
+pub struct MailError(pub String);
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for MailError {}
+
+/// Sends email.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), MailError>;
+}
+
+/// Builds the mailer for the configured transport.
+pub fn from_config(config: &MailConfig) -> Result<Arc<dyn Mailer>, Box<dyn std::error::Error>> {
+    let from: Mailbox = config.from.parse()?;
+    Ok(match config.transport {
+        MailTransport::Memory => {
+            tracing::warn!("mail.transport is \"memory\": emails are not delivered");
+            Arc::new(InMemoryMailer::new())
+        }
+        MailTransport::File => {
+            let file = OpenOptions::new().create(true).append(true).open(&config.file_path)?;
+            Arc::new(JsonLinesMailer::new(from, file))
+        }
+        MailTransport::Smtp => Arc::new(SmtpMailer::new(config, from)?),
+    })
+}
+
+/// [`Mailer`] delivering email through an SMTP server.
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(config: &MailConfig, from: Mailbox) -> Result<Self, lettre::transport::smtp::Error> {
+        let host = config.smtp_host.as_str();
+        let builder = match config.smtp_tls {
+            SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+        };
+        let mut builder = builder.port(config.smtp_port);
+        if !config.smtp_username.is_empty() {
+            builder = builder.credentials(Credentials::new(config.smtp_username.clone(), config.smtp_password.clone()));
+        }
+        Ok(Self { transport: builder.build(), from })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        let to: Mailbox = email.to.parse().map_err(|err| MailError(format!("invalid recipient: {err}")))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(email.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.body)
+            .map_err(|err| MailError(err.to_string()))?;
+        self.transport.send(message).await.map_err(|err| MailError(err.to_string()))?;
+        Ok(())
+    }
+}
+
+/// [`Mailer`] writing one JSON object per email instead of delivering it, for
+/// local development.
+pub struct JsonLinesMailer<W> {
+    from: Mailbox,
+    writer: Arc<Mutex<W>>,
+}
+
+impl<W> JsonLinesMailer<W> {
+    pub fn new(from: Mailbox, writer: W) -> Self {
+        Self { from, writer: Arc::new(Mutex::new(writer)) }
+    }
+}
+
+#[async_trait]
+impl<W: Write + Send + 'static> Mailer for JsonLinesMailer<W> {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        let sent_at = rfc3339(DateTime::now()).map_err(|err| MailError(err.to_string()))?;
+        let line = json!({
+            "sent_at": sent_at,
+            "from": self.from.to_string(),
+            "to": email.to,
+            "subject": email.subject,
+            "body": email.body,
+        });
+        let mut line = serde_json::to_vec(&line).expect("emails always serialize");
+        line.push(b'\n');
+        let mut writer = self.writer.lock().map_err(|_| MailError("writer poisoned".into()))?;
+        writer.write_all(&line).and_then(|()| writer.flush()).map_err(|err| MailError(err.to_string()))
+    }
+}
+
+/// [`Mailer`] that keeps every email in process memory.
+#[derive(Default)]
+pub struct InMemoryMailer {
+    sent: Mutex<Vec<Email>>,
+}
+
+impl InMemoryMailer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every email sent so far, oldest first.
+    #[cfg(test)]
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Mailer for InMemoryMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        self.sent.lock().unwrap().push(email);
+        Ok(())
+    }
+}
@@ -1,151 +1,285 @@
+mod accounts;
+mod api_keys;
+mod auth;
+mod config;
+mod email_tokens;
+mod error;
+mod health;
+mod logging;
+mod mailer;
+mod metrics;
 mod model;
+mod openapi;
+mod pagination;
+mod patch;
+mod policy;
+mod query;
+mod repository;
+mod resources;
+mod secrets;
+mod sessions;
+mod startup;
+mod streaming;
+mod telemetry;
 #[cfg(test)]
 mod test;
+mod versioning;
 
-use actix_web::{get, post, delete, web, App, HttpResponse, HttpServer};
-use model::User;
-use mongodb::{bson::{self, doc}, options::IndexOptions, Client, Collection, IndexModel};
-use futures_util::stream::TryStreamExt;
-
-const DB_NAME: &str = "myApp";
-const COLL_NAME: &str = "users";
-
-/// Adds a new user to the "users" collection in the database.
-#[post("/add_user")]
-async fn add_user(client: web::Data<Client>, json: web::Json<User>) -> HttpResponse {
-    let collection = client.database(DB_NAME).collection(COLL_NAME);
-    let result = collection.insert_one(json.into_inner()).await;
-    match result {
-        Ok(_) => HttpResponse::Ok().body("user added"),
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
-    }
+use std::sync::Arc;
+use std::time::Duration;
+
+use accounts::{Passwords, Registration};
+use api_keys::{ApiKeyRepository, ApiKeys, InMemoryApiKeyRepository, MongoApiKeyRepository};
+use auth::{Principal, Tokens};
+use actix_web::{dev::ServerHandle, get, post, delete, http::header::LINK, middleware::DefaultHeaders, web, App, HttpResponse, HttpServer};
+use config::{Config, StorageBackend};
+use email_tokens::{EmailTokenRepository, EmailTokens, InMemoryEmailTokenRepository, MongoEmailTokenRepository};
+use error::{json_error_handler, query_error_handler, ApiError};
+use model::{User, UserPatch};
+use mongodb::{options::ClientOptions, Client};
+use pagination::{PageLimits, PageQuery};
+use query::UserListQuery;
+use repository::{InMemoryUserRepository, MongoUserRepository, UserRepository};
+use sessions::{InMemorySessionRepository, MongoSessionRepository, SessionRepository, Sessions};
+use health::Probe;
+use logging::RequestLogging;
+use metrics::{InstrumentedRepository, Metrics, RecordMetrics};
+use policy::{Authorize, RoleGrants};
+use startup::{Readiness, ReadinessGatedRepository};
+use streaming::StreamQuery;
+use telemetry::TracedRepository;
+use validator::Validate;
+use versioning::UserRepresentation;
+
+/// Instant the verb-style routes were deprecated, as an RFC 9745 `Deprecation` value.
+const LEGACY_DEPRECATION: &str = "@1792022400";
+/// Date after which the verb-style routes may be removed, see RFC 8594.
+const LEGACY_SUNSET: &str = "Thu, 01 Jul 2027 00:00:00 GMT";
+
+/// Marks responses of the verb-style routes as deprecated in favour of `/v1/users`.
+fn legacy_deprecation() -> DefaultHeaders {
+    DefaultHeaders::new()
+        .add(("Deprecation", LEGACY_DEPRECATION))
+        .add(("Sunset", LEGACY_SUNSET))
+        .add((LINK, format!("<{}>; rel=\"successor-version\"", User::USERS_PATH)))
+}
+
+/// Adds a new user to the "users" collection in the database and mails them a
+/// link to verify their email address.
+#[post("/add_user", wrap = "legacy_deprecation()", wrap = "Authorize(policy::CREATE_USER)")]
+async fn add_user(repo: web::Data<dyn UserRepository>, passwords: web::Data<Passwords>, email_tokens: web::Data<EmailTokens>, json: web::Json<serde_json::Value>) -> Result<HttpResponse, ApiError> {
+    let registration = Registration::<User>::parse(json.into_inner())?;
+    let user = registration.user;
+    user.validate()?;
+    let password_hash = accounts::password_hash(&passwords, registration.password).await?;
+    repo.insert(user.clone(), password_hash).await?;
+    resources::send_verification_to_created(email_tokens, &user);
+    Ok(HttpResponse::Ok().body("user added"))
 }
 
 /// Gets the user with the supplied username.
-#[get("/get_user/{username}")]
-async fn get_user(client: web::Data<Client>, username: web::Path<String>) -> HttpResponse {
-    let username = username.into_inner();
-    let collection: Collection<User> = client.database(DB_NAME).collection(COLL_NAME);
-    match collection.find_one(doc! { "username": &username }).await {
-        Ok(Some(user)) => HttpResponse::Ok().json(user),
-        Ok(None) => {
-            HttpResponse::NotFound().body(format!("No user found with username {username}"))
-        }
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
-    }
+#[get("/get_user/{username}", wrap = "legacy_deprecation()")]
+async fn get_user(repo: web::Data<dyn UserRepository>, username: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    resources::get_user::<User>(repo, username).await
 }
 
-/// Gets all users in the collection.
-#[get("/get_users")]
-async fn get_users(client: web::Data<Client>) -> HttpResponse {
-    let collection: Collection<User> = client.database(DB_NAME).collection(COLL_NAME);
-    let cursor = collection.find(doc! {}).await;
-
-    match cursor {
-        Ok(mut users) => {
-            let mut all_users = vec![];
-            while let Some(user) = users.try_next().await.unwrap() {
-                all_users.push(user);
-            }
-            HttpResponse::Ok().json(all_users)
-        }
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
-    }
+/// Gets one page of the users in the collection, see [`resources::list_users`].
+#[get("/get_users", wrap = "legacy_deprecation()", wrap = "Authorize(policy::LIST_USERS)")]
+async fn get_users(repo: web::Data<dyn UserRepository>, limits: web::Data<PageLimits>, page: web::Query<PageQuery>, list: web::Query<UserListQuery>, stream: web::Query<StreamQuery>) -> Result<HttpResponse, ApiError> {
+    resources::list_users::<User>(repo, limits, page, list, stream).await
 }
 
 /// Updates the user with the supplied username.
-#[post("/update_user/{username}")]
-async fn update_user(client: web::Data<Client>, username: web::Path<String>, form: web::Json<serde_json::Value>) -> HttpResponse {
+#[post("/update_user/{username}", wrap = "legacy_deprecation()", wrap = "Authorize(policy::UPDATE_USER)")]
+async fn update_user(repo: web::Data<dyn UserRepository>, principal: web::ReqData<Principal>, username: web::Path<String>, patch: web::Json<UserPatch>) -> Result<HttpResponse, ApiError> {
     let username = username.into_inner();
-    let collection: Collection<User> = client.database(DB_NAME).collection(COLL_NAME);
-
-    let mut update_doc = doc! {};
-
-    if let Some(first_name) = form.get("first_name") {
-        if let Ok(bson_first_name) = bson::to_bson(first_name) {
-            update_doc.insert("first_name", bson_first_name);
-        }
-    }
-    if let Some(last_name) = form.get("last_name") {
-        if let Ok(bson_last_name) = bson::to_bson(last_name) {
-            update_doc.insert("last_name", bson_last_name);
-        }
+    let patch = patch.into_inner();
+    if patch.is_empty() {
+        return Err(ApiError::ValidationFailed("patch must set at least one of first_name, last_name or email".into()));
     }
-    if let Some(email) = form.get("email") {
-        if let Ok(bson_email) = bson::to_bson(email) {
-            update_doc.insert("email", bson_email);
-        }
-    }
-
-    let update_doc = doc! { "$set": update_doc };
+    patch.validate()?;
+    resources::check_email_change(&**repo, &principal, &username, patch.email.as_deref()).await?;
 
-    match collection.update_one(doc! { "username": &username }, update_doc).await {
-        Ok(update_result) => {
-            if update_result.matched_count > 0 {
-                HttpResponse::Ok().body("User updated")
-            } else {
-                HttpResponse::NotFound().body(format!("No user found with username {username}"))
-            }
-        }
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    if repo.update(&username, patch).await? {
+        Ok(HttpResponse::Ok().body("User updated"))
+    } else {
+        Err(ApiError::UserNotFound(username))
     }
 }
 
-
-
 /// Deletes the user with the supplied username.
-#[delete("/delete_user/{username}")]
-async fn delete_user(client: web::Data<Client>, username: web::Path<String>) -> HttpResponse {
+#[delete("/delete_user/{username}", wrap = "legacy_deprecation()", wrap = "Authorize(policy::DELETE_USER)")]
+async fn delete_user(repo: web::Data<dyn UserRepository>, sessions: web::Data<Sessions>, username: web::Path<String>) -> Result<HttpResponse, ApiError> {
     let username = username.into_inner();
-    let collection: Collection<User> = client.database(DB_NAME).collection(COLL_NAME);
-
-    match collection.delete_one(doc! { "username": &username }).await {
-        Ok(delete_result) => {
-            if delete_result.deleted_count > 0 {
-                HttpResponse::Ok().body("User deleted")
-            } else {
-                HttpResponse::NotFound().body(format!("No user found with username {username}"))
-            }
-        }
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    if repo.delete(&username).await? {
+        resources::end_sessions_of_deleted(&sessions, &username).await;
+        Ok(HttpResponse::Ok().body("User deleted"))
+    } else {
+        Err(ApiError::UserNotFound(username))
     }
 }
 
-/// Creates an index on the "username" field to force the values to be unique.
-async fn create_username_index(client: &Client) {
-    let options = IndexOptions::builder().unique(true).build();
-    let model = IndexModel::builder()
-        .keys(doc! { "username": 1 })
-        .options(options)
-        .build();
-    client
-        .database(DB_NAME)
-        .collection::<User>(COLL_NAME)
-        .create_index(model)
-        .await
-        .expect("creating an index should succeed");
+/// Registers every endpoint on the application.
+fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(web::JsonConfig::default().error_handler(json_error_handler))
+        .app_data(web::QueryConfig::default().error_handler(query_error_handler))
+        .configure(health::configure)
+        .configure(metrics::configure)
+        .configure(openapi::configure)
+        .configure(accounts::configure)
+        .configure(auth::configure)
+        .configure(api_keys::configure)
+        .configure(sessions::configure)
+        .configure(email_tokens::configure)
+        .configure(versioning::configure)
+        .service(add_user)
+        .service(get_user)
+        .service(get_users)
+        .service(update_user)
+        .service(delete_user);
+}
+
+/// Waits for SIGTERM or Ctrl-C, then fails readiness for `drain` before
+/// stopping the server gracefully.
+async fn shutdown_on_signal(server: ServerHandle, readiness: Arc<Readiness>, drain: Duration) {
+    #[cfg(unix)]
+    {
+        use actix_rt::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler should install");
+        futures_util::future::select(Box::pin(actix_rt::signal::ctrl_c()), Box::pin(terminate.recv())).await;
+    }
+    #[cfg(not(unix))]
+    actix_rt::signal::ctrl_c().await.ok();
+
+    tracing::info!(drain = ?drain, "shutdown requested; draining before stopping");
+    readiness.begin_drain();
+    actix_rt::time::sleep(drain).await;
+    server.stop(true).await;
+}
+
+/// The repositories of the configured storage backend.
+struct Stores {
+    users: Arc<dyn UserRepository>,
+    api_keys: Arc<dyn ApiKeyRepository>,
+    sessions: Arc<dyn SessionRepository>,
+    email_tokens: Arc<dyn EmailTokenRepository>,
+    readiness: Arc<Readiness>,
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let uri = std::env::var("MONGODB_URI").unwrap_or_else(|_| "mongodb://localhost:27017".into());
+    let config = Config::load().unwrap_or_else(|err| {
+        eprintln!("invalid configuration: {err}");
+        std::process::exit(1);
+    });
+    let tracer_provider = telemetry::tracer_provider(&config.tracing).unwrap_or_else(|err| {
+        eprintln!("cannot set up trace export: {err}");
+        std::process::exit(1);
+    });
+    logging::init(tracer_provider.as_ref());
 
-    let client = Client::with_uri_str(&uri).await.expect("failed to connect");
-    create_username_index(&client).await;
+    let metrics = Arc::new(Metrics::new());
+    let Stores { users: repo, api_keys: key_repo, sessions: session_repo, email_tokens: email_token_repo, readiness } = match config.storage.backend {
+        StorageBackend::Memory => {
+            let readiness = Arc::new(Readiness::default());
+            readiness.mark_storage_ready();
+            Stores {
+                users: Arc::new(InMemoryUserRepository::new()),
+                api_keys: Arc::new(InMemoryApiKeyRepository::new()),
+                sessions: Arc::new(InMemorySessionRepository::new()),
+                email_tokens: Arc::new(InMemoryEmailTokenRepository::new()),
+                readiness,
+            }
+        }
+        StorageBackend::Mongodb => {
+            let storage = &config.storage;
+            let mut options = ClientOptions::parse(&storage.mongodb_uri).await.unwrap_or_else(|err| {
+                eprintln!("invalid configuration: invalid storage.mongodb_uri: {err}");
+                std::process::exit(1);
+            });
+            options.connect_timeout = Some(storage.connect_timeout());
+            options.server_selection_timeout = Some(storage.server_selection_timeout());
 
-    HttpServer::new(move || {
+            // The driver connects lazily, so this only fails on invalid options.
+            let client = Client::with_options(options).unwrap_or_else(|err| {
+                eprintln!("invalid configuration: invalid MongoDB client options: {err}");
+                std::process::exit(1);
+            });
+            let repo = MongoUserRepository::new(&client, &storage.database, &storage.collection);
+            let key_repo = MongoApiKeyRepository::new(&client, &storage.database, &storage.api_keys_collection);
+            let session_repo = MongoSessionRepository::new(&client, &storage.database, &storage.sessions_collection);
+            let email_token_repo = MongoEmailTokenRepository::new(&client, &storage.database, &storage.email_tokens_collection);
+            let readiness = startup::initialize_storage(&repo, storage).await;
+            startup::create_session_indexes(session_repo.clone(), storage);
+            startup::create_email_token_indexes(email_token_repo.clone(), storage);
+            let repo = Arc::new(TracedRepository::new(Arc::new(repo), &storage.collection));
+            let repo = Arc::new(InstrumentedRepository::new(repo, &storage.collection, metrics.clone()));
+            let key_repo = Arc::new(TracedRepository::new(Arc::new(key_repo), &storage.api_keys_collection));
+            let key_repo = Arc::new(InstrumentedRepository::new(key_repo, &storage.api_keys_collection, metrics.clone()));
+            let session_repo = Arc::new(TracedRepository::new(Arc::new(session_repo), &storage.sessions_collection));
+            let session_repo = Arc::new(InstrumentedRepository::new(session_repo, &storage.sessions_collection, metrics.clone()));
+            let email_token_repo = Arc::new(TracedRepository::new(Arc::new(email_token_repo), &storage.email_tokens_collection));
+            let email_token_repo = Arc::new(InstrumentedRepository::new(email_token_repo, &storage.email_tokens_collection, metrics.clone()));
+            Stores {
+                users: Arc::new(ReadinessGatedRepository::new(repo, readiness.clone())),
+                api_keys: key_repo,
+                sessions: session_repo,
+                email_tokens: email_token_repo,
+                readiness,
+            }
+        }
+    };
+
+    let limits = config.page_limits();
+    let passwords = Passwords::new(config.passwords.params());
+    let tokens = Arc::new(Tokens::from_config(&config.auth).unwrap_or_else(|err| {
+        eprintln!("cannot load token keys: {err}");
+        std::process::exit(1);
+    }));
+    let grants = RoleGrants::new(config.auth.admins.clone());
+    let api_keys = Arc::new(ApiKeys::new(key_repo, config.auth.api_key_max_ttl()));
+    let sessions = Arc::new(Sessions::new(session_repo, &config.sessions));
+    let mailer = mailer::from_config(&config.mail).unwrap_or_else(|err| {
+        eprintln!("cannot set up mail transport: {err}");
+        std::process::exit(1);
+    });
+    let email_tokens = Arc::new(EmailTokens::new(email_token_repo, mailer, &config.mail));
+    let server = &config.server;
+    let probe = Probe { readiness: readiness.clone(), check_timeout: server.health_check_timeout() };
+    let mut http_server = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(client.clone()))
-            .service(add_user)
-            .service(get_user)
-            .service(get_users)
-            .service(update_user)
-            .service(delete_user)
+            .app_data(web::Data::from(repo.clone()))
+            .app_data(web::Data::new(limits))
+            .app_data(web::Data::new(passwords.clone()))
+            .app_data(web::Data::from(tokens.clone()))
+            .app_data(web::Data::new(grants.clone()))
+            .app_data(web::Data::from(api_keys.clone()))
+            .app_data(web::Data::from(sessions.clone()))
+            .app_data(web::Data::from(email_tokens.clone()))
+            .app_data(web::Data::new(probe.clone()))
+            .app_data(web::Data::from(metrics.clone()))
+            .wrap(RecordMetrics::new(metrics.clone()))
+            .wrap(RequestLogging)
+            .configure(configure)
     })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
-}
-
+    .client_request_timeout(Duration::from_secs(server.client_request_timeout_secs))
+    .shutdown_timeout(server.shutdown_timeout_secs);
+    if let Some(workers) = server.workers {
+        http_server = http_server.workers(workers);
+    }
 
+    // Signals are handled by shutdown_on_signal so readiness can fail first.
+    let running = http_server
+        .bind((server.bind_address.as_str(), server.port))?
+        .disable_signals()
+        .run();
+    actix_rt::spawn(shutdown_on_signal(running.handle(), readiness, server.shutdown_drain()));
+    running.await?;
 
+    if let Some(provider) = tracer_provider {
+        if let Err(err) = provider.shutdown() {
+            eprintln!("failed to flush traces: {err}");
+        }
+    }
+    Ok(())
+}
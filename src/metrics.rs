@@ -0,0 +1,323 @@
+//! Prometheus metrics, exposed in the text format at `/metrics`.
+//!
+//! HTTP requests are recorded by the [`RecordMetrics`] middleware, labelled by
+//! route pattern rather than path so usernames do not become label values.
+//! MongoDB operations are recorded by [`InstrumentedRepository`], labelled by
+//! collection.
+
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    web, Error, HttpResponse,
+};
+use async_trait::async_trait;
+use mongodb::bson::DateTime;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder};
+
+use crate::api_keys::{ApiKeyRecord, ApiKeyRepository};
+use crate::email_tokens::{EmailTokenRecord, EmailTokenRepository, Purpose};
+use crate::model::{Role, User, UserPatch};
+use crate::pagination::PageRequest;
+use crate::query::UserQuery;
+use crate::repository::{RepositoryError, UserRepository};
+use crate::sessions::{SessionRecord, SessionRepository};
+use crate::streaming::UserStream;
+
+/// Route label of requests that matched no route.
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Every metric of the service, in its own registry.
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    storage_operation_duration: HistogramVec,
+    storage_operation_errors: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled."),
+            &["method", "route", "status"],
+        )
+        .expect("metric options are valid");
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time taken to produce HTTP response heads."),
+            &["method", "route", "status"],
+        )
+        .expect("metric options are valid");
+        let storage_operation_duration = HistogramVec::new(
+            HistogramOpts::new("mongodb_operation_duration_seconds", "Time taken by MongoDB operations."),
+            &["collection", "operation"],
+        )
+        .expect("metric options are valid");
+        let storage_operation_errors = IntCounterVec::new(
+            Opts::new("mongodb_operation_errors_total", "MongoDB operations that failed."),
+            &["collection", "operation"],
+        )
+        .expect("metric options are valid");
+
+        let registry = Registry::new();
+        registry.register(Box::new(http_requests.clone())).expect("metric names are unique");
+        registry.register(Box::new(http_request_duration.clone())).expect("metric names are unique");
+        registry.register(Box::new(storage_operation_duration.clone())).expect("metric names are unique");
+        registry.register(Box::new(storage_operation_errors.clone())).expect("metric names are unique");
+
+        Self { registry, http_requests, http_request_duration, storage_operation_duration, storage_operation_errors }
+    }
+
+    fn observe_request(&self, method: &str, route: &str, status: u16, started: Instant) {
+        let status = status.to_string();
+        let labels = [method, route, status.as_str()];
+        self.http_requests.with_label_values(&labels).inc();
+        self.http_request_duration.with_label_values(&labels).observe(started.elapsed().as_secs_f64());
+    }
+
+    fn observe_operation<T>(&self, collection: &str, operation: &str, started: Instant, result: &Result<T, RepositoryError>) {
+        let labels = [collection, operation];
+        self.storage_operation_duration.with_label_values(&labels).observe(started.elapsed().as_secs_f64());
+        // A duplicate key is a MongoDB error, even though the API maps it to a conflict.
+        if result.is_err() {
+            self.storage_operation_errors.with_label_values(&labels).inc();
+        }
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("metrics always encode");
+        String::from_utf8(buffer).expect("the text format is UTF-8")
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Registers the `/metrics` endpoint.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/metrics", web::get().to(export));
+}
+
+async fn export(metrics: web::Data<Metrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(metrics.render())
+}
+
+/// Middleware counting requests and timing them per method, route and status.
+pub struct RecordMetrics {
+    metrics: Arc<Metrics>,
+}
+
+impl RecordMetrics {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RecordMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RecordMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RecordMetricsMiddleware { service: Rc::new(service), metrics: self.metrics.clone() }))
+    }
+}
+
+pub struct RecordMetricsMiddleware<S> {
+    service: Rc<S>,
+    metrics: Arc<Metrics>,
+}
+
+impl<S, B> Service<ServiceRequest> for RecordMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let metrics = self.metrics.clone();
+        Box::pin(async move {
+            let started = Instant::now();
+            let method = req.method().clone();
+            let response = service.call(req).await?;
+            // The pattern is only known once routing has happened.
+            let route = response.request().match_pattern();
+            let route = route.as_deref().unwrap_or(UNMATCHED_ROUTE);
+            metrics.observe_request(method.as_str(), route, response.status().as_u16(), started);
+            Ok(response)
+        })
+    }
+}
+
+/// Repository that records the latency and failures of every MongoDB
+/// operation the wrapped repository performs on `collection`.
+pub struct InstrumentedRepository<R: ?Sized> {
+    inner: Arc<R>,
+    collection: String,
+    metrics: Arc<Metrics>,
+}
+
+impl<R: ?Sized> InstrumentedRepository<R> {
+    pub fn new(inner: Arc<R>, collection: &str, metrics: Arc<Metrics>) -> Self {
+        Self { inner, collection: collection.into(), metrics }
+    }
+
+    async fn observe<T>(&self, operation: &str, call: impl Future<Output = Result<T, RepositoryError>>) -> Result<T, RepositoryError> {
+        let started = Instant::now();
+        let result = call.await;
+        self.metrics.observe_operation(&self.collection, operation, started, &result);
+        result
+    }
+}
+
+#[async_trait]
+impl<R: UserRepository + ?Sized> UserRepository for InstrumentedRepository<R> {
+    async fn insert(&self, user: User, password_hash: Option<String>) -> Result<(), RepositoryError> {
+        self.observe("insert_one", self.inner.insert(user, password_hash)).await
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, RepositoryError> {
+        self.observe("find_one", self.inner.find_by_username(username)).await
+    }
+
+    async fn find_page(&self, query: &UserQuery, page: &PageRequest) -> Result<Vec<User>, RepositoryError> {
+        self.observe("find", self.inner.find_page(query, page)).await
+    }
+
+    /// Only opening the cursor is timed; the stream is consumed at the client's pace.
+    async fn stream(&self, query: &UserQuery) -> Result<UserStream, RepositoryError> {
+        self.observe("find", self.inner.stream(query)).await
+    }
+
+    async fn update(&self, username: &str, patch: UserPatch) -> Result<bool, RepositoryError> {
+        self.observe("update_one", self.inner.update(username, patch)).await
+    }
+
+    async fn update_if_unchanged(&self, current: &User, patch: UserPatch) -> Result<bool, RepositoryError> {
+        self.observe("update_one", self.inner.update_if_unchanged(current, patch)).await
+    }
+
+    async fn delete(&self, username: &str) -> Result<bool, RepositoryError> {
+        self.observe("delete_one", self.inner.delete(username)).await
+    }
+
+    async fn find_password_hash(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        self.observe("find_one", self.inner.find_password_hash(username)).await
+    }
+
+    async fn set_password_hash(&self, username: &str, password_hash: String) -> Result<bool, RepositoryError> {
+        self.observe("update_one", self.inner.set_password_hash(username, password_hash)).await
+    }
+
+    async fn find_roles(&self, username: &str) -> Result<Option<Vec<Role>>, RepositoryError> {
+        self.observe("find_one", self.inner.find_roles(username)).await
+    }
+
+    async fn set_roles(&self, username: &str, roles: Vec<Role>) -> Result<bool, RepositoryError> {
+        self.observe("update_one", self.inner.set_roles(username, roles)).await
+    }
+
+    async fn find_token_generation(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        self.observe("find_one", self.inner.find_token_generation(username)).await
+    }
+
+    async fn rotate_token_generation(&self, username: &str) -> Result<bool, RepositoryError> {
+        self.observe("update_one", self.inner.rotate_token_generation(username)).await
+    }
+
+    async fn find_verified_email(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        self.observe("find_one", self.inner.find_verified_email(username)).await
+    }
+
+    async fn set_verified_email(&self, username: &str, email: &str) -> Result<bool, RepositoryError> {
+        self.observe("update_one", self.inner.set_verified_email(username, email)).await
+    }
+
+    async fn ping(&self) -> Result<(), RepositoryError> {
+        self.inner.ping().await
+    }
+
+    async fn username_index_exists(&self) -> Result<bool, RepositoryError> {
+        self.inner.username_index_exists().await
+    }
+}
+
+#[async_trait]
+impl<R: ApiKeyRepository + ?Sized> ApiKeyRepository for InstrumentedRepository<R> {
+    async fn insert(&self, key: ApiKeyRecord) -> Result<(), RepositoryError> {
+        self.observe("insert_one", self.inner.insert(key)).await
+    }
+
+    async fn find(&self, id: &str) -> Result<Option<ApiKeyRecord>, RepositoryError> {
+        self.observe("find_one", self.inner.find(id)).await
+    }
+
+    async fn list(&self) -> Result<Vec<ApiKeyRecord>, RepositoryError> {
+        self.observe("find", self.inner.list()).await
+    }
+
+    async fn revoke(&self, id: &str, at: DateTime) -> Result<bool, RepositoryError> {
+        self.observe("update_one", self.inner.revoke(id, at)).await
+    }
+
+    async fn record_use(&self, id: &str, at: DateTime) -> Result<(), RepositoryError> {
+        self.observe("update_one", self.inner.record_use(id, at)).await
+    }
+}
+
+#[async_trait]
+impl<R: SessionRepository + ?Sized> SessionRepository for InstrumentedRepository<R> {
+    async fn insert(&self, session: SessionRecord) -> Result<(), RepositoryError> {
+        self.observe("insert_one", self.inner.insert(session)).await
+    }
+
+    async fn find(&self, id_hash: &str) -> Result<Option<SessionRecord>, RepositoryError> {
+        self.observe("find_one", self.inner.find(id_hash)).await
+    }
+
+    async fn delete(&self, id_hash: &str) -> Result<(), RepositoryError> {
+        self.observe("delete_one", self.inner.delete(id_hash)).await
+    }
+
+    async fn delete_for_user(&self, username: &str) -> Result<(), RepositoryError> {
+        self.observe("delete_many", self.inner.delete_for_user(username)).await
+    }
+}
+
+#[async_trait]
+impl<R: EmailTokenRepository + ?Sized> EmailTokenRepository for InstrumentedRepository<R> {
+    async fn insert(&self, token: EmailTokenRecord) -> Result<(), RepositoryError> {
+        self.observe("insert_one", self.inner.insert(token)).await
+    }
+
+    async fn take(&self, id_hash: &str, purpose: Purpose) -> Result<Option<EmailTokenRecord>, RepositoryError> {
+        self.observe("find_one_and_delete", self.inner.take(id_hash, purpose)).await
+    }
+
+    async fn delete_for_user(&self, username: &str, purpose: Purpose) -> Result<(), RepositoryError> {
+        self.observe("delete_many", self.inner.delete_for_user(username, purpose)).await
+    }
+}
@@ -1,9 +1,165 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use validator::{Validate, ValidationError};
+
+/// Usernames are 3 to 32 ASCII letters, digits, `.`, `_` or `-`.
+static USERNAME_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[A-Za-z0-9._-]{3,32}$").unwrap());
 
-#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+/// Longest accepted first or last name, in characters.
+const MAX_NAME_LEN: u64 = 100;
+/// Longest accepted email address, see RFC 5321 section 4.5.3.1.
+const MAX_EMAIL_LEN: u64 = 254;
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Validate, JsonSchema)]
 pub struct User {
+    #[validate(
+        length(min = 1, max = "MAX_NAME_LEN", message = "must be 1 to 100 characters"),
+        custom(function = "not_blank", message = "must not be blank")
+    )]
     pub first_name: String,
+    #[validate(
+        length(min = 1, max = "MAX_NAME_LEN", message = "must be 1 to 100 characters"),
+        custom(function = "not_blank", message = "must not be blank")
+    )]
     pub last_name: String,
+    #[validate(regex(
+        path = *USERNAME_RE,
+        code = "username",
+        message = "must be 3 to 32 letters, digits, '.', '_' or '-'"
+    ))]
+    pub username: String,
+    #[validate(
+        email(message = "must be a valid email address"),
+        length(max = "MAX_EMAIL_LEN", message = "must be at most 254 characters")
+    )]
+    pub email: String,
+}
+
+/// Version 2 JSON shape of a [`User`], which groups the name parts.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UserV2 {
     pub username: String,
+    pub name: PersonName,
     pub email: String,
 }
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PersonName {
+    pub given: String,
+    pub family: String,
+}
+
+impl From<User> for UserV2 {
+    fn from(user: User) -> Self {
+        Self {
+            username: user.username,
+            name: PersonName { given: user.first_name, family: user.last_name },
+            email: user.email,
+        }
+    }
+}
+
+impl From<UserV2> for User {
+    fn from(user: UserV2) -> Self {
+        Self {
+            first_name: user.name.given,
+            last_name: user.name.family,
+            username: user.username,
+            email: user.email,
+        }
+    }
+}
+
+/// Fields of a [`User`] to overwrite; `None` leaves the stored value untouched.
+///
+/// The username identifies the user and cannot be patched; unknown fields are
+/// rejected so that typos are reported instead of ignored.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Validate, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UserPatch {
+    #[validate(
+        length(min = 1, max = "MAX_NAME_LEN", message = "must be 1 to 100 characters"),
+        custom(function = "not_blank", message = "must not be blank")
+    )]
+    pub first_name: Option<String>,
+    #[validate(
+        length(min = 1, max = "MAX_NAME_LEN", message = "must be 1 to 100 characters"),
+        custom(function = "not_blank", message = "must not be blank")
+    )]
+    pub last_name: Option<String>,
+    #[validate(
+        email(message = "must be a valid email address"),
+        length(max = "MAX_EMAIL_LEN", message = "must be at most 254 characters")
+    )]
+    pub email: Option<String>,
+}
+
+impl UserPatch {
+    /// Whether the patch would leave the user unchanged.
+    pub fn is_empty(&self) -> bool {
+        self.first_name.is_none() && self.last_name.is_none() && self.email.is_none()
+    }
+
+    /// Overwrites the fields of `user` that the patch sets.
+    pub fn apply_to(self, user: &mut User) {
+        if let Some(first_name) = self.first_name {
+            user.first_name = first_name;
+        }
+        if let Some(last_name) = self.last_name {
+            user.last_name = last_name;
+        }
+        if let Some(email) = self.email {
+            user.email = email;
+        }
+    }
+}
+
+/// What a user may do, see [`policy`](crate::policy).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Manages every user and their roles.
+    Admin,
+    /// Creates users and updates any user's record.
+    Support,
+    /// Updates their own record.
+    #[serde(rename = "self")]
+    Owner,
+}
+
+impl Role {
+    /// Roles of users that were never assigned any.
+    pub fn defaults() -> Vec<Role> {
+        vec![Role::Owner]
+    }
+}
+
+/// What an API key may do, see [`policy`](crate::policy).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    /// Lists users.
+    ReadUsers,
+    /// Creates, updates and deletes users.
+    WriteUsers,
+}
+
+/// Body of the roles sub-resource of a user.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RoleAssignment {
+    pub roles: Vec<Role>,
+}
+
+fn not_blank(value: &str) -> Result<(), ValidationError> {
+    if value.trim().is_empty() {
+        return Err(ValidationError::new("blank"));
+    }
+    Ok(())
+}
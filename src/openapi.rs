@@ -0,0 +1,708 @@
+//! OpenAPI 3.1 description of the HTTP API, served at `/openapi.json`, and a
+//! self-contained page rendering it at `/docs`.
+//!
+//! Schemas are derived from the Rust types with `schemars`, so field names and
+//! validation rules cannot drift from the models; the operations mirror the
+//! routes registered by [`resources::scope`](crate::resources::scope) and the
+//! legacy handlers in `main`.
+
+use std::sync::LazyLock;
+
+use actix_web::{web, HttpResponse};
+use schemars::generate::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+use crate::accounts::{Credentials, Registration};
+use crate::api_keys::{ApiKeyInfo, CreatedApiKey, NewApiKey, API_KEY_HEADER};
+use crate::auth::{RefreshRequest, TokenPair};
+use crate::email_tokens::{PasswordResetConfirmation, PasswordResetRequest, VerifyEmailRequest};
+use crate::error::{ProblemDetails, PROBLEM_JSON};
+use crate::health::Report;
+use crate::model::{RoleAssignment, User, UserPatch, UserV2};
+use crate::pagination::{Page, PageQuery};
+use crate::patch::{JSON_PATCH_JSON, MERGE_PATCH_JSON};
+use crate::query::UserListQuery;
+use crate::sessions::SessionInfo;
+use crate::streaming::{StreamQuery, NDJSON};
+use crate::versioning::UserRepresentation;
+
+/// Rendered once; the document only depends on the types.
+static DOCUMENT: LazyLock<Value> = LazyLock::new(document);
+
+/// Offline documentation page; it loads nothing but `/openapi.json`.
+const DOCS_HTML: &str = include_str!("../assets/docs.html");
+
+/// Registers `/openapi.json` and `/docs`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/openapi.json", web::get().to(openapi_json))
+        .route("/docs", web::get().to(docs));
+}
+
+async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok().json(&*DOCUMENT)
+}
+
+async fn docs() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(DOCS_HTML)
+}
+
+/// A representation of users and the schemas it is described by.
+struct Representation {
+    media_type: &'static str,
+    user: Value,
+    page: Value,
+    registration: Value,
+}
+
+impl Representation {
+    fn of<R: UserRepresentation + JsonSchema>(generator: &mut SchemaGenerator) -> Self {
+        Self {
+            media_type: R::CONTENT_TYPE,
+            user: generator.subschema_for::<R>().to_value(),
+            page: generator.subschema_for::<Page<R>>().to_value(),
+            registration: generator.subschema_for::<Registration<R>>().to_value(),
+        }
+    }
+}
+
+/// Builds the OpenAPI document.
+pub fn document() -> Value {
+    let mut generator = SchemaSettings::draft2020_12()
+        .with(|settings| {
+            settings.definitions_path = "/components/schemas".into();
+            settings.meta_schema = None;
+        })
+        .into_generator();
+
+    let v1 = Representation::of::<User>(&mut generator);
+    let v2 = Representation::of::<UserV2>(&mut generator);
+    let user_patch = generator.subschema_for::<UserPatch>().to_value();
+    let role_assignment = generator.subschema_for::<RoleAssignment>().to_value();
+    let report = generator.subschema_for::<Report>().to_value();
+    let credentials = generator.subschema_for::<Credentials>().to_value();
+    let refresh_request = generator.subschema_for::<RefreshRequest>().to_value();
+    let token_pair = generator.subschema_for::<TokenPair>().to_value();
+    let new_api_key = generator.subschema_for::<NewApiKey>().to_value();
+    let api_key_info = generator.subschema_for::<ApiKeyInfo>().to_value();
+    let created_api_key = generator.subschema_for::<CreatedApiKey>().to_value();
+    let session_info = generator.subschema_for::<SessionInfo>().to_value();
+    let verify_email_request = generator.subschema_for::<VerifyEmailRequest>().to_value();
+    let password_reset_request = generator.subschema_for::<PasswordResetRequest>().to_value();
+    let password_reset_confirmation = generator.subschema_for::<PasswordResetConfirmation>().to_value();
+    // Referenced by the shared responses below.
+    generator.subschema_for::<ProblemDetails>();
+
+    let mut list_parameters = query_parameters::<UserListQuery>();
+    list_parameters.extend(query_parameters::<PageQuery>());
+    list_parameters.extend(query_parameters::<StreamQuery>());
+
+    let mut paths = Map::new();
+    for (path, representations, suffix) in [
+        (User::USERS_PATH, vec![&v1], "V1"),
+        (UserV2::USERS_PATH, vec![&v2], "V2"),
+        ("/users", vec![&v1, &v2], ""),
+    ] {
+        paths.insert(path.into(), collection_operations(&representations, &list_parameters, suffix));
+        paths.insert(format!("{path}/{{username}}"), item_operations(&representations, suffix));
+        paths.insert(format!("{path}/{{username}}/roles"), role_operations(&role_assignment, suffix));
+        paths.insert(format!("{path}/{{username}}/sessions"), user_session_operations(suffix));
+        paths.insert(format!("{path}/{{username}}/email-verification"), email_verification_operations(suffix));
+    }
+    paths.extend(account_operations(&credentials, &refresh_request, &token_pair));
+    paths.extend(session_operations(&credentials, &session_info));
+    paths.extend(email_token_operations(&verify_email_request, &password_reset_request, &password_reset_confirmation));
+    paths.extend(api_key_operations(&new_api_key, &api_key_info, &created_api_key));
+    paths.extend(legacy_operations(&v1, &user_patch, &list_parameters));
+    paths.extend(operational_endpoints(&report));
+
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Stores users by username. `/v1/users` and `/v2/users` serve fixed \
+                versions; `/users` negotiates the version by `Accept`, preferring v1.",
+        },
+        "tags": [
+            { "name": "users", "description": "User resources." },
+            { "name": "accounts", "description": "Password login, bearer tokens, cookie sessions, email verification and password reset." },
+            { "name": "api-keys", "description": "Keys for service-to-service callers, managed by admins." },
+            { "name": "legacy", "description": "Deprecated verb-style routes, to be removed after their sunset date." },
+            { "name": "operations", "description": "Health and monitoring." },
+        ],
+        "paths": paths,
+        "components": {
+            "schemas": generator.take_definitions(true),
+            "responses": problem_responses(),
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer", "bearerFormat": "JWT", "description": "Access token from `POST /login`." },
+                "apiKey": { "type": "apiKey", "in": "header", "name": API_KEY_HEADER, "description": "Key from `POST /api-keys`; used only when no `Authorization` header is sent." },
+                "session": { "type": "apiKey", "in": "cookie", "name": "session", "description": "Cookie from `POST /session`, named by `sessions.cookie_name`; \
+                    used only when neither an `Authorization` nor an API key header is sent." },
+            },
+        },
+    })
+}
+
+/// One optional query parameter per field of `T`.
+fn query_parameters<T: JsonSchema>() -> Vec<Value> {
+    let generator = SchemaSettings::draft2020_12()
+        .with(|settings| settings.inline_subschemas = true)
+        .into_generator();
+    let schema = generator.into_root_schema_for::<T>().to_value();
+    let properties = schema["properties"].as_object().cloned().unwrap_or_default();
+    properties
+        .into_iter()
+        .map(|(name, mut schema)| {
+            let description = schema.as_object_mut().and_then(|schema| schema.remove("description"));
+            let mut parameter = json!({ "name": name, "in": "query", "required": false });
+            if let Some(description) = description {
+                parameter["description"] = description;
+            }
+            parameter["schema"] = without_null(schema);
+            parameter
+        })
+        .collect()
+}
+
+/// Drops the `null` alternative of an `Option` field's schema, since an
+/// absent query parameter is simply omitted.
+fn without_null(mut schema: Value) -> Value {
+    if let Some(types) = schema.get_mut("type").and_then(Value::as_array_mut) {
+        types.retain(|kind| kind != "null");
+        if types.len() == 1 {
+            schema["type"] = types[0].clone();
+        }
+    }
+    if let Some(values) = schema.get_mut("enum").and_then(Value::as_array_mut) {
+        values.retain(|value| !value.is_null());
+    }
+    if let Some(variants) = schema.get("anyOf").and_then(Value::as_array) {
+        let variants: Vec<&Value> = variants.iter().filter(|variant| variant["type"] != "null").collect();
+        if let [variant] = variants[..] {
+            return without_null(variant.clone());
+        }
+    }
+    schema
+}
+
+/// Shared problem responses, keyed by the name operations reference them by.
+fn problem_responses() -> Value {
+    let problem = |description: &str| {
+        json!({
+            "description": description,
+            "content": { PROBLEM_JSON: { "schema": { "$ref": "#/components/schemas/ProblemDetails" } } },
+        })
+    };
+    json!({
+        "BadRequest": problem("`validation_failed`: the body, query or patch document could not be parsed."),
+        "NotFound": problem("`user_not_found`: no user has this username."),
+        "Conflict": problem("`duplicate_<field>`: another user has this value; `patch_test_failed`: a JSON Patch test did not hold; \
+            `concurrent_update`: the user changed while the patch was applied."),
+        "UnsupportedMediaType": problem("`unsupported_media_type`: the body has a content type this operation does not accept."),
+        "UnprocessableEntity": problem("`validation_failed`: fields hold unacceptable values; each is listed in `errors`."),
+        "Unauthorized": problem("`unauthenticated`: no bearer token or API key was sent; `invalid_token`: the token is invalid or expired; \
+            `invalid_api_key`: the API key is invalid, expired or revoked; `invalid_session`: the session has ended or expired."),
+        "Forbidden": problem("`forbidden`: the caller's roles or API key scopes do not allow this operation."),
+        "ApiKeyNotFound": problem("`api_key_not_found`: no API key has this id."),
+        "InvalidCredentials": problem("`invalid_credentials`: the username or password is incorrect."),
+        "InvalidEmailToken": problem("`invalid_email_token`: the link is invalid, already used or expired; \
+            `validation_failed`: the body could not be parsed."),
+        "MailUnavailable": problem("`mail_unavailable`: the email could not be sent; \
+            `storage_unavailable`: the user store could not complete the request."),
+        "ServiceUnavailable": problem("`storage_unavailable`: the user store could not complete the request."),
+    })
+}
+
+fn problem(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/responses/{name}") })
+}
+
+fn content(representations: &[&Representation], schema: impl Fn(&Representation) -> &Value) -> Value {
+    representations
+        .iter()
+        .map(|representation| (representation.media_type.to_string(), json!({ "schema": schema(representation) })))
+        .collect::<Map<_, _>>()
+        .into()
+}
+
+fn username_parameter() -> Value {
+    json!({ "name": "username", "in": "path", "required": true, "schema": { "type": "string" } })
+}
+
+fn collection_operations(representations: &[&Representation], list_parameters: &[Value], suffix: &str) -> Value {
+    let mut page = content(representations, |representation| &representation.page);
+    page[NDJSON] = json!({ "schema": { "type": "string" } });
+    json!({
+        "post": {
+            "tags": ["users"],
+            "operationId": format!("createUser{suffix}"),
+            "summary": "Create a user",
+            "security": any_credentials(),
+            "description": "An optional write-only `password` lets the user log in.",
+            "requestBody": { "required": true, "content": content(representations, |representation| &representation.registration) },
+            "responses": {
+                "201": {
+                    "description": "The created user.",
+                    "headers": { "Location": { "description": "URL of the created user.", "schema": { "type": "string" } } },
+                    "content": content(representations, |representation| &representation.user),
+                },
+                "400": problem("BadRequest"),
+                "401": problem("Unauthorized"),
+                "403": problem("Forbidden"),
+                "409": problem("Conflict"),
+                "422": problem("UnprocessableEntity"),
+                "503": problem("ServiceUnavailable"),
+            },
+        },
+        "get": {
+            "tags": ["users"],
+            "operationId": format!("listUsers{suffix}"),
+            "summary": "List users",
+            "security": any_credentials(),
+            "description": "Returns one page of users, or with `stream` every matching user. \
+                `stream` cannot be combined with `limit`, `offset` or `cursor`.",
+            "parameters": list_parameters,
+            "responses": {
+                "200": { "description": "A page of users; with `stream=json` an array of every user, with `stream=ndjson` one user per line.", "content": page },
+                "400": problem("BadRequest"),
+                "401": problem("Unauthorized"),
+                "403": problem("Forbidden"),
+                "503": problem("ServiceUnavailable"),
+            },
+        },
+    })
+}
+
+fn item_operations(representations: &[&Representation], suffix: &str) -> Value {
+    let user = content(representations, |representation| &representation.user);
+    let patch = json!({
+        MERGE_PATCH_JSON: { "schema": { "type": "object", "description": "RFC 7396 merge patch of the user." } },
+        JSON_PATCH_JSON: { "schema": { "type": "array", "description": "RFC 6902 JSON Patch operations on the user.", "items": { "type": "object" } } },
+    });
+    json!({
+        "parameters": [username_parameter()],
+        "get": {
+            "tags": ["users"],
+            "operationId": format!("getUser{suffix}"),
+            "summary": "Get a user",
+            "responses": {
+                "200": { "description": "The user.", "content": user },
+                "404": problem("NotFound"),
+                "503": problem("ServiceUnavailable"),
+            },
+        },
+        "put": {
+            "tags": ["users"],
+            "operationId": format!("replaceUser{suffix}"),
+            "summary": "Replace a user",
+            "security": any_credentials(),
+            "description": "The username in the body must match the one in the path. \
+                Only the user and admins may change the email.",
+            "requestBody": { "required": true, "content": user },
+            "responses": {
+                "200": { "description": "The replaced user.", "content": user },
+                "400": problem("BadRequest"),
+                "401": problem("Unauthorized"),
+                "403": problem("Forbidden"),
+                "404": problem("NotFound"),
+                "422": problem("UnprocessableEntity"),
+                "503": problem("ServiceUnavailable"),
+            },
+        },
+        "patch": {
+            "tags": ["users"],
+            "operationId": format!("patchUser{suffix}"),
+            "summary": "Patch a user",
+            "security": any_credentials(),
+            "description": "Applies a patch document addressing the user in this representation. The username cannot change, \
+                and only the user and admins may change the email.",
+            "requestBody": { "required": true, "content": patch },
+            "responses": {
+                "200": { "description": "The patched user.", "content": user },
+                "400": problem("BadRequest"),
+                "401": problem("Unauthorized"),
+                "403": problem("Forbidden"),
+                "404": problem("NotFound"),
+                "409": problem("Conflict"),
+                "415": problem("UnsupportedMediaType"),
+                "422": problem("UnprocessableEntity"),
+                "503": problem("ServiceUnavailable"),
+            },
+        },
+        "delete": {
+            "tags": ["users"],
+            "operationId": format!("deleteUser{suffix}"),
+            "summary": "Delete a user",
+            "security": any_credentials(),
+            "responses": {
+                "204": { "description": "The user was deleted." },
+                "401": problem("Unauthorized"),
+                "403": problem("Forbidden"),
+                "404": problem("NotFound"),
+                "503": problem("ServiceUnavailable"),
+            },
+        },
+    })
+}
+
+/// Security of the user operations, which accept a bearer token, an API key
+/// with the matching scope or a session cookie.
+fn any_credentials() -> Value {
+    json!([{ "bearer": [] }, { "apiKey": [] }, { "session": [] }])
+}
+
+/// Security of operations only users may perform.
+fn user_credentials() -> Value {
+    json!([{ "bearer": [] }, { "session": [] }])
+}
+
+fn role_operations(role_assignment: &Value, suffix: &str) -> Value {
+    let roles = json!({ "application/json": { "schema": role_assignment } });
+    json!({
+        "parameters": [username_parameter()],
+        "get": {
+            "tags": ["users"],
+            "operationId": format!("getRoles{suffix}"),
+            "summary": "Get the roles of a user",
+            "security": user_credentials(),
+            "responses": {
+                "200": { "description": "The roles assigned to the user.", "content": roles },
+                "401": problem("Unauthorized"),
+                "403": problem("Forbidden"),
+                "404": problem("NotFound"),
+                "503": problem("ServiceUnavailable"),
+            },
+        },
+        "put": {
+            "tags": ["users"],
+            "operationId": format!("replaceRoles{suffix}"),
+            "summary": "Replace the roles of a user",
+            "description": "Access tokens already issued keep their roles until they expire, at most an hour later; refreshing reads the new roles.",
+            "security": user_credentials(),
+            "requestBody": { "required": true, "content": roles },
+            "responses": {
+                "200": { "description": "The roles now assigned to the user.", "content": roles },
+                "400": problem("BadRequest"),
+                "401": problem("Unauthorized"),
+                "403": problem("Forbidden"),
+                "404": problem("NotFound"),
+                "503": problem("ServiceUnavailable"),
+            },
+        },
+    })
+}
+
+fn user_session_operations(suffix: &str) -> Value {
+    json!({
+        "parameters": [username_parameter()],
+        "delete": {
+            "tags": ["users"],
+            "operationId": format!("endSessions{suffix}"),
+            "summary": "Log a user out everywhere",
+            "description": "Ends every cookie session of the user and revokes their refresh tokens. \
+                Access tokens stay valid until they expire.",
+            "security": user_credentials(),
+            "responses": {
+                "204": { "description": "The user has no sessions left." },
+                "401": problem("Unauthorized"),
+                "403": problem("Forbidden"),
+                "503": problem("ServiceUnavailable"),
+            },
+        },
+    })
+}
+
+fn email_verification_operations(suffix: &str) -> Value {
+    json!({
+        "parameters": [username_parameter()],
+        "post": {
+            "tags": ["users"],
+            "operationId": format!("resendEmailVerification{suffix}"),
+            "summary": "Mail a new email verification link",
+            "description": "Replaces the user's earlier verification link. Nothing is sent once the address is verified. \
+                Only the user and admins may ask for a link.",
+            "security": user_credentials(),
+            "responses": {
+                "202": { "description": "The link was sent." },
+                "204": { "description": "The address is already verified." },
+                "401": problem("Unauthorized"),
+                "403": problem("Forbidden"),
+                "404": problem("NotFound"),
+                "503": problem("MailUnavailable"),
+            },
+        },
+    })
+}
+
+fn email_token_operations(verify_email_request: &Value, password_reset_request: &Value, password_reset_confirmation: &Value) -> Map<String, Value> {
+    let mut paths = Map::new();
+    paths.insert("/email/verify".into(), json!({ "post": {
+        "tags": ["accounts"], "operationId": "verifyEmail",
+        "summary": "Verify an email address",
+        "description": "Redeems the token from a verification link, which works once.",
+        "requestBody": { "required": true, "content": { "application/json": { "schema": verify_email_request } } },
+        "responses": {
+            "204": { "description": "The user's address is verified." },
+            "400": problem("InvalidEmailToken"),
+            "503": problem("ServiceUnavailable"),
+        },
+    }}));
+    paths.insert("/password-reset".into(), json!({ "post": {
+        "tags": ["accounts"], "operationId": "requestPasswordReset",
+        "summary": "Mail a password reset link",
+        "description": "Sends the link to the user's verified address, replacing any earlier one. The response is the same \
+            whether or not the user exists or has a verified address.",
+        "requestBody": { "required": true, "content": { "application/json": { "schema": password_reset_request } } },
+        "responses": {
+            "202": { "description": "A link was sent if the user has a verified address." },
+            "400": problem("BadRequest"),
+            "503": problem("ServiceUnavailable"),
+        },
+    }}));
+    paths.insert("/password-reset/confirm".into(), json!({ "post": {
+        "tags": ["accounts"], "operationId": "confirmPasswordReset",
+        "summary": "Set a new password with a reset link",
+        "description": "Redeems the token from a reset link, which works once, ends the user's cookie sessions \
+            and revokes their refresh tokens. Access tokens stay valid until they expire.",
+        "requestBody": { "required": true, "content": { "application/json": { "schema": password_reset_confirmation } } },
+        "responses": {
+            "204": { "description": "The password was changed." },
+            "400": problem("InvalidEmailToken"),
+            "422": problem("UnprocessableEntity"),
+            "503": problem("ServiceUnavailable"),
+        },
+    }}));
+    paths
+}
+
+fn session_operations(credentials: &Value, session_info: &Value) -> Map<String, Value> {
+    let session = json!({ "application/json": { "schema": session_info } });
+    let mut paths = Map::new();
+    paths.insert("/session".into(), json!({
+        "post": {
+            "tags": ["accounts"], "operationId": "startSession",
+            "summary": "Log in with a password and start a cookie session",
+            "description": "Sets an HttpOnly session cookie, replacing the session the browser had, if any.",
+            "requestBody": { "required": true, "content": { "application/json": { "schema": credentials } } },
+            "responses": {
+                "200": {
+                    "description": "The new session.",
+                    "headers": { "Set-Cookie": { "description": "The session cookie.", "schema": { "type": "string" } } },
+                    "content": session,
+                },
+                "400": problem("BadRequest"),
+                "401": problem("InvalidCredentials"),
+                "503": problem("ServiceUnavailable"),
+            },
+        },
+        "get": {
+            "tags": ["accounts"], "operationId": "getSession",
+            "summary": "Describe the current session",
+            "security": [{ "session": [] }],
+            "responses": {
+                "200": { "description": "The session's user and expiry.", "content": session },
+                "401": problem("Unauthorized"),
+                "503": problem("ServiceUnavailable"),
+            },
+        },
+        "delete": {
+            "tags": ["accounts"], "operationId": "endSession",
+            "summary": "Log out",
+            "description": "Ends the current session, if any, and removes the cookie.",
+            "responses": {
+                "204": { "description": "The session has ended." },
+                "503": problem("ServiceUnavailable"),
+            },
+        },
+    }));
+    paths
+}
+
+fn account_operations(credentials: &Value, refresh_request: &Value, token_pair: &Value) -> Map<String, Value> {
+    let tokens = json!({ "application/json": { "schema": token_pair } });
+    let mut paths = Map::new();
+    paths.insert("/login".into(), json!({ "post": {
+        "tags": ["accounts"], "operationId": "login",
+        "summary": "Log in with a password",
+        "requestBody": { "required": true, "content": { "application/json": { "schema": credentials } } },
+        "responses": {
+            "200": { "description": "An access token and a refresh token for the user.", "content": tokens },
+            "400": problem("BadRequest"),
+            "401": problem("InvalidCredentials"),
+            "503": problem("ServiceUnavailable"),
+        },
+    }}));
+    paths.insert("/token/refresh".into(), json!({ "post": {
+        "tags": ["accounts"], "operationId": "refreshToken",
+        "summary": "Exchange a refresh token for new tokens",
+        "requestBody": { "required": true, "content": { "application/json": { "schema": refresh_request } } },
+        "responses": {
+            "200": { "description": "A new access token and refresh token.", "content": tokens },
+            "400": problem("BadRequest"),
+            "401": problem("Unauthorized"),
+            "503": problem("ServiceUnavailable"),
+        },
+    }}));
+    paths
+}
+
+fn api_key_operations(new_api_key: &Value, api_key_info: &Value, created_api_key: &Value) -> Map<String, Value> {
+    let mut paths = Map::new();
+    paths.insert("/api-keys".into(), json!({
+        "post": {
+            "tags": ["api-keys"], "operationId": "createApiKey",
+            "summary": "Create an API key",
+            "description": "The key is only ever returned by this operation; only a hash of it is stored.",
+            "security": user_credentials(),
+            "requestBody": { "required": true, "content": { "application/json": { "schema": new_api_key } } },
+            "responses": {
+                "201": { "description": "The key and its details.", "content": { "application/json": { "schema": created_api_key } } },
+                "400": problem("BadRequest"),
+                "401": problem("Unauthorized"),
+                "403": problem("Forbidden"),
+                "422": problem("UnprocessableEntity"),
+                "503": problem("ServiceUnavailable"),
+            },
+        },
+        "get": {
+            "tags": ["api-keys"], "operationId": "listApiKeys",
+            "summary": "List API keys",
+            "description": "Every key, oldest first, including expired and revoked ones.",
+            "security": user_credentials(),
+            "responses": {
+                "200": { "description": "The keys, without their secrets.", "content": { "application/json": { "schema": { "type": "array", "items": api_key_info } } } },
+                "401": problem("Unauthorized"),
+                "403": problem("Forbidden"),
+                "503": problem("ServiceUnavailable"),
+            },
+        },
+    }));
+    paths.insert("/api-keys/{id}".into(), json!({
+        "parameters": [{ "name": "id", "in": "path", "required": true, "schema": { "type": "string" } }],
+        "delete": {
+            "tags": ["api-keys"], "operationId": "revokeApiKey",
+            "summary": "Revoke an API key",
+            "description": "The key stops working at once and stays listed with its revocation time.",
+            "security": user_credentials(),
+            "responses": {
+                "204": { "description": "The key is revoked." },
+                "401": problem("Unauthorized"),
+                "403": problem("Forbidden"),
+                "404": problem("ApiKeyNotFound"),
+                "503": problem("ServiceUnavailable"),
+            },
+        },
+    }));
+    paths
+}
+
+fn legacy_operations(v1: &Representation, user_patch: &Value, list_parameters: &[Value]) -> Map<String, Value> {
+    let text = |description: &str| json!({ "description": description, "content": { "text/plain": { "schema": { "type": "string" } } } });
+    let user = json!({ "application/json": { "schema": v1.user } });
+    let mut paths = Map::new();
+    paths.insert("/add_user".into(), json!({ "post": {
+        "tags": ["legacy"], "operationId": "legacyAddUser", "deprecated": true,
+        "summary": "Create a user; use `POST /v1/users`",
+        "security": any_credentials(),
+        "requestBody": { "required": true, "content": { "application/json": { "schema": v1.registration } } },
+        "responses": {
+            "200": text("`user added`"),
+            "400": problem("BadRequest"),
+            "401": problem("Unauthorized"),
+            "403": problem("Forbidden"),
+            "409": problem("Conflict"),
+            "422": problem("UnprocessableEntity"),
+            "503": problem("ServiceUnavailable"),
+        },
+    }}));
+    paths.insert("/get_user/{username}".into(), json!({ "get": {
+        "tags": ["legacy"], "operationId": "legacyGetUser", "deprecated": true,
+        "summary": "Get a user; use `GET /v1/users/{username}`",
+        "parameters": [username_parameter()],
+        "responses": {
+            "200": { "description": "The user.", "content": user },
+            "404": problem("NotFound"),
+            "503": problem("ServiceUnavailable"),
+        },
+    }}));
+    paths.insert("/get_users".into(), json!({ "get": {
+        "tags": ["legacy"], "operationId": "legacyGetUsers", "deprecated": true,
+        "summary": "List users; use `GET /v1/users`",
+        "security": any_credentials(),
+        "parameters": list_parameters,
+        "responses": {
+            "200": { "description": "A page of users.", "content": { "application/json": { "schema": v1.page } } },
+            "400": problem("BadRequest"),
+            "401": problem("Unauthorized"),
+            "403": problem("Forbidden"),
+            "503": problem("ServiceUnavailable"),
+        },
+    }}));
+    paths.insert("/update_user/{username}".into(), json!({ "post": {
+        "tags": ["legacy"], "operationId": "legacyUpdateUser", "deprecated": true,
+        "summary": "Update fields of a user; use `PATCH /v1/users/{username}`",
+        "security": any_credentials(),
+        "parameters": [username_parameter()],
+        "requestBody": { "required": true, "content": { "application/json": { "schema": user_patch } } },
+        "responses": {
+            "200": text("`User updated`"),
+            "400": problem("BadRequest"),
+            "401": problem("Unauthorized"),
+            "403": problem("Forbidden"),
+            "404": problem("NotFound"),
+            "422": problem("UnprocessableEntity"),
+            "503": problem("ServiceUnavailable"),
+        },
+    }}));
+    paths.insert("/delete_user/{username}".into(), json!({ "delete": {
+        "tags": ["legacy"], "operationId": "legacyDeleteUser", "deprecated": true,
+        "summary": "Delete a user; use `DELETE /v1/users/{username}`",
+        "security": any_credentials(),
+        "parameters": [username_parameter()],
+        "responses": {
+            "200": text("`User deleted`"),
+            "401": problem("Unauthorized"),
+            "403": problem("Forbidden"),
+            "404": problem("NotFound"),
+            "503": problem("ServiceUnavailable"),
+        },
+    }}));
+    paths
+}
+
+fn operational_endpoints(report: &Value) -> Map<String, Value> {
+    let report = json!({ "application/json": { "schema": report } });
+    let mut paths = Map::new();
+    paths.insert("/healthz".into(), json!({ "get": {
+        "tags": ["operations"], "operationId": "liveness",
+        "summary": "Liveness probe",
+        "responses": { "200": { "description": "The process is alive.", "content": report } },
+    }}));
+    paths.insert("/readyz".into(), json!({ "get": {
+        "tags": ["operations"], "operationId": "readiness",
+        "summary": "Readiness probe",
+        "description": "Fails while storage is unreachable or the server is shutting down.",
+        "responses": {
+            "200": { "description": "Every check passed.", "content": report },
+            "503": { "description": "At least one check failed.", "content": report },
+        },
+    }}));
+    paths.insert("/metrics".into(), json!({ "get": {
+        "tags": ["operations"], "operationId": "metrics",
+        "summary": "Prometheus metrics",
+        "responses": { "200": { "description": "Metrics in the Prometheus text format.", "content": { "text/plain": { "schema": { "type": "string" } } } } },
+    }}));
+    paths.insert("/openapi.json".into(), json!({ "get": {
+        "tags": ["operations"], "operationId": "openapi",
+        "summary": "This document",
+        "responses": { "200": { "description": "The OpenAPI document.", "content": { "application/json": { "schema": { "type": "object" } } } } },
+    }}));
+    paths.insert("/docs".into(), json!({ "get": {
+        "tags": ["operations"], "operationId": "docs",
+        "summary": "API reference page",
+        "responses": { "200": { "description": "A page rendering this document.", "content": { "text/html": { "schema": { "type": "string" } } } } },
+    }}));
+    paths
+}
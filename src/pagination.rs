@@ -0,0 +1,111 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ApiError;
+
+/// Page size used when a request does not supply `limit`.
+pub const DEFAULT_PAGE_SIZE: u64 = 50;
+/// Upper bound for `limit` unless configured otherwise.
+pub const DEFAULT_MAX_PAGE_SIZE: u64 = 500;
+/// Largest `max_page_size` the configuration accepts, so a page always fits in
+/// one response.
+pub const MAX_PAGE_SIZE_LIMIT: u64 = 10_000;
+
+/// Page size bounds, registered as application data.
+#[derive(Clone, Copy, Debug)]
+pub struct PageLimits {
+    pub default_size: u64,
+    pub max_size: u64,
+}
+
+impl Default for PageLimits {
+    fn default() -> Self {
+        Self { default_size: DEFAULT_PAGE_SIZE, max_size: DEFAULT_MAX_PAGE_SIZE }
+    }
+}
+
+/// Pagination query parameters accepted by list endpoints.
+///
+/// `offset` and `cursor` select the two paging modes and are mutually exclusive.
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct PageQuery {
+    /// Users per page, at least 1; larger values are clamped to the configured maximum.
+    pub limit: Option<u64>,
+    /// Users to skip in listing order.
+    pub offset: Option<u64>,
+    /// The `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+}
+
+/// Where a page starts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PageStart {
+    /// Skip this many users in listing order.
+    Offset(u64),
+    /// Start after the user with these sort key values (keyset pagination).
+    After(Vec<String>),
+}
+
+/// A validated page of users to fetch from the store.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PageRequest {
+    pub limit: u64,
+    pub start: PageStart,
+}
+
+impl PageQuery {
+    /// Checks the parameters and clamps `limit` to `limits.max_size`.
+    pub fn into_request(self, limits: &PageLimits) -> Result<PageRequest, ApiError> {
+        let limit = match self.limit {
+            Some(0) => return Err(ApiError::ValidationFailed("limit must be at least 1".into())),
+            Some(limit) => limit.min(limits.max_size),
+            None => limits.default_size.min(limits.max_size),
+        };
+        let start = match (self.offset, self.cursor) {
+            (Some(_), Some(_)) => {
+                return Err(ApiError::ValidationFailed(
+                    "offset and cursor cannot be combined".into(),
+                ))
+            }
+            (_, Some(cursor)) => PageStart::After(decode_cursor(&cursor)?),
+            (offset, None) => PageStart::Offset(offset.unwrap_or(0)),
+        };
+        Ok(PageRequest { limit, start })
+    }
+}
+
+/// Response envelope of a list endpoint.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[schemars(rename = "{T}Page")]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Pass as `cursor` to fetch the following page; `null` on the last page.
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    /// Builds a page from up to `limit + 1` fetched items; the extra item only
+    /// signals that another page exists and is not returned.
+    pub fn from_overfetch(mut items: Vec<T>, limit: u64, key: impl Fn(&T) -> Vec<String>) -> Self {
+        let has_more = items.len() as u64 > limit;
+        items.truncate(limit as usize);
+        let next_cursor = match items.last() {
+            Some(last) if has_more => Some(encode_cursor(&key(last))),
+            _ => None,
+        };
+        Self { items, next_cursor }
+    }
+}
+
+fn encode_cursor(values: &[String]) -> String {
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(values).expect("strings always serialize"))
+}
+
+fn decode_cursor(cursor: &str) -> Result<Vec<String>, ApiError> {
+    URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or_else(|| ApiError::ValidationFailed("cursor is not valid".into()))
+}
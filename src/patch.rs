@@ -0,0 +1,58 @@
+use json_patch::{Patch, PatchErrorKind};
+use serde_json::Value;
+
+use crate::error::ApiError;
+use crate::model::{User, UserPatch};
+use crate::versioning::UserRepresentation;
+
+/// Content type of an RFC 7396 JSON Merge Patch document.
+pub const MERGE_PATCH_JSON: &str = "application/merge-patch+json";
+/// Content type of an RFC 6902 JSON Patch document.
+pub const JSON_PATCH_JSON: &str = "application/json-patch+json";
+
+/// Applies a patch document of the given content type to `user` as rendered
+/// in representation `R`.
+///
+/// Returns the fields that differ from `user` afterwards; the result must
+/// still deserialize as an `R`, hold no other fields, and keep its username.
+pub fn apply<R: UserRepresentation>(user: &User, content_type: &str, body: &[u8]) -> Result<UserPatch, ApiError> {
+    let mut document = serde_json::to_value(R::from(user.clone())).expect("users always serialize");
+    match content_type {
+        MERGE_PATCH_JSON => {
+            let patch: Value = parse(body)?;
+            json_patch::merge(&mut document, &patch);
+        }
+        JSON_PATCH_JSON => {
+            let patch: Patch = parse(body)?;
+            json_patch::patch(&mut document, &patch).map_err(|err| match err.kind {
+                PatchErrorKind::TestFailed => ApiError::PatchTestFailed(err.to_string()),
+                _ => ApiError::ValidationFailed(err.to_string()),
+            })?;
+        }
+        other => return Err(ApiError::UnsupportedMediaType(other.into())),
+    }
+
+    let patched: R = serde_json::from_value(document.clone())
+        .map_err(|err| ApiError::ValidationFailed(format!("patched user is invalid: {err}")))?;
+    // Deserializing ignores fields the representation does not know, so a
+    // round trip that loses anything means the patch added such a field.
+    if serde_json::to_value(&patched).expect("users always serialize") != document {
+        return Err(ApiError::ValidationFailed("patched user has unknown fields".into()));
+    }
+    let patched: User = patched.into();
+    if patched.username != user.username {
+        return Err(ApiError::ValidationFailed("username cannot be changed".into()));
+    }
+
+    let changed = |before: &String, after: String| (*before != after).then_some(after);
+    Ok(UserPatch {
+        first_name: changed(&user.first_name, patched.first_name),
+        last_name: changed(&user.last_name, patched.last_name),
+        email: changed(&user.email, patched.email),
+    })
+}
+
+fn parse<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, ApiError> {
+    serde_json::from_slice(body)
+        .map_err(|err| ApiError::ValidationFailed(format!("patch document is invalid: {err}")))
+}
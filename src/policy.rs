@@ -0,0 +1,193 @@
+//! Role-based authorization.
+//!
+//! Each protected route is wrapped in [`Authorize`] with the [`Policy`] it
+//! enforces: requests without a valid access token, API key or session cookie
+//! get 401, and requests whose user lacks a permitted role, or whose key lacks
+//! the permitted scope, get 403.
+//!
+//! | Policy                | admin | support    | self       | API key scope |
+//! |-----------------------|-------|------------|------------|---------------|
+//! | [`CREATE_USER`]       | yes   | yes        | no         | `write-users` |
+//! | [`LIST_USERS`]        | yes   | no         | no         | `read-users`  |
+//! | [`UPDATE_USER`]       | yes   | non-admins | own record | `write-users` |
+//! | [`DELETE_USER`]       | yes   | no         | no         | `write-users` |
+//! | [`MANAGE_ROLES`]      | yes   | no         | no         | none          |
+//! | [`MANAGE_API_KEYS`]   | yes   | no         | no         | none          |
+//! | [`END_SESSIONS`]      | yes   | no         | own record | none          |
+//! | [`CHANGE_EMAIL`]      | yes   | no         | own record | none          |
+//! | [`SEND_VERIFICATION`] | yes   | no         | own record | none          |
+//!
+//! Whoever controls a user's address can reset their password, so only the
+//! user and admins may change it or have a verification link sent to it;
+//! [`CHANGE_EMAIL`] is checked by the update handlers, on top of
+//! [`UPDATE_USER`].
+//!
+//! Policies that spare admins only let admins act on users holding the admin
+//! role, so support cannot take over an admin's account; users still act on
+//! their own record.
+//!
+//! Roles are read from the access token, so changes apply once the user's
+//! current token is refreshed. Sessions and API keys are checked against the
+//! store on every request, so role changes and revocations apply at once.
+
+use std::collections::BTreeSet;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::AUTHORIZATION,
+    web, Error, HttpMessage,
+};
+
+use crate::api_keys::{self, API_KEY_HEADER};
+use crate::auth::{self, Principal};
+use crate::error::ApiError;
+use crate::model::{Role, Scope};
+use crate::repository::{RepositoryError, UserRepository};
+use crate::sessions;
+
+/// Who may perform an action.
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    /// Describes the action in 403 responses.
+    action: &'static str,
+    /// Roles allowed to act on any user.
+    roles: &'static [Role],
+    /// Whether the `self` role allows acting on the user named by the
+    /// `{username}` path segment when it is the caller.
+    owner: bool,
+    /// API key scope allowing the action, if keys may perform it at all.
+    scope: Option<Scope>,
+    /// Whether acting on another user who holds the admin role takes the
+    /// admin role, whatever else allows the action.
+    spare_admins: bool,
+}
+
+pub const CREATE_USER: Policy = Policy { action: "create users", roles: &[Role::Admin, Role::Support], owner: false, scope: Some(Scope::WriteUsers), spare_admins: false };
+pub const LIST_USERS: Policy = Policy { action: "list users", roles: &[Role::Admin], owner: false, scope: Some(Scope::ReadUsers), spare_admins: false };
+pub const UPDATE_USER: Policy = Policy { action: "update this user", roles: &[Role::Admin, Role::Support], owner: true, scope: Some(Scope::WriteUsers), spare_admins: true };
+pub const DELETE_USER: Policy = Policy { action: "delete users", roles: &[Role::Admin], owner: false, scope: Some(Scope::WriteUsers), spare_admins: false };
+pub const MANAGE_ROLES: Policy = Policy { action: "manage roles", roles: &[Role::Admin], owner: false, scope: None, spare_admins: false };
+pub const MANAGE_API_KEYS: Policy = Policy { action: "manage API keys", roles: &[Role::Admin], owner: false, scope: None, spare_admins: false };
+pub const END_SESSIONS: Policy = Policy { action: "end this user's sessions", roles: &[Role::Admin], owner: true, scope: None, spare_admins: false };
+pub const CHANGE_EMAIL: Policy = Policy { action: "change this user's email", roles: &[Role::Admin], owner: true, scope: None, spare_admins: false };
+pub const SEND_VERIFICATION: Policy = Policy { action: "send this user a verification link", roles: &[Role::Admin], owner: true, scope: None, spare_admins: false };
+
+impl Policy {
+    /// Whether `principal` may act on the user named `username`, if any.
+    pub fn allows(&self, principal: &Principal, username: Option<&str>) -> bool {
+        let has = |role| principal.roles.contains(&role);
+        self.roles.iter().copied().any(has)
+            || (self.owner && has(Role::Owner) && username == Some(principal.username.as_str()))
+            || self.scope.is_some_and(|scope| principal.scopes.contains(&scope))
+    }
+
+    /// Like [`allows`](Self::allows), but failing with a `forbidden` error.
+    pub fn check(&self, principal: &Principal, username: Option<&str>) -> Result<(), ApiError> {
+        if self.allows(principal, username) {
+            Ok(())
+        } else {
+            Err(ApiError::Forbidden(self.action))
+        }
+    }
+}
+
+/// Roles of users: those stored with them plus those granted by configuration,
+/// so a first admin can exist before anyone can assign roles.
+#[derive(Clone, Debug, Default)]
+pub struct RoleGrants {
+    admins: BTreeSet<String>,
+}
+
+impl RoleGrants {
+    pub fn new(admins: impl IntoIterator<Item = String>) -> Self {
+        Self { admins: admins.into_iter().collect() }
+    }
+
+    /// The roles of `username`, or `None` if the user does not exist.
+    pub async fn roles_of(&self, repo: &dyn UserRepository, username: &str) -> Result<Option<Vec<Role>>, RepositoryError> {
+        let Some(mut roles) = repo.find_roles(username).await? else {
+            return Ok(None);
+        };
+        if self.admins.contains(username) && !roles.contains(&Role::Admin) {
+            roles.push(Role::Admin);
+        }
+        Ok(Some(roles))
+    }
+}
+
+/// Middleware enforcing a [`Policy`] on a route, and storing the caller's
+/// [`Principal`] in the request extensions when it passes.
+pub struct Authorize(pub Policy);
+
+impl<S, B: 'static> Transform<S, ServiceRequest> for Authorize
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AuthorizeMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthorizeMiddleware { service: Rc::new(service), policy: self.0 }))
+    }
+}
+
+pub struct AuthorizeMiddleware<S> {
+    service: Rc<S>,
+    policy: Policy,
+}
+
+impl<S, B: 'static> Service<ServiceRequest> for AuthorizeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let policy = self.policy;
+        Box::pin(async move {
+            let principal = match authorize(&req, policy).await {
+                Ok(principal) => principal,
+                // Answered here, so outer middleware sees a response rather than an error.
+                Err(err) => return Ok(req.error_response(err).map_into_right_body()),
+            };
+            req.extensions_mut().insert(principal);
+            Ok(service.call(req).await?.map_into_left_body())
+        })
+    }
+}
+
+/// Authenticates the caller by the first of a bearer token, an API key and a
+/// session cookie the request carries, and checks `policy`.
+async fn authorize(req: &ServiceRequest, policy: Policy) -> Result<Principal, ApiError> {
+    let principal = if req.headers().contains_key(AUTHORIZATION) {
+        auth::authenticate(req)?
+    } else if req.headers().contains_key(API_KEY_HEADER) {
+        api_keys::authenticate(req).await?
+    } else {
+        sessions::authenticate(req).await?.ok_or(ApiError::Unauthenticated)?
+    };
+    let username = req.match_info().get("username");
+    if !policy.allows(&principal, username) {
+        return Err(ApiError::Forbidden(policy.action));
+    }
+    if let Some(username) = username.filter(|&username| policy.spare_admins && !principal.roles.contains(&Role::Admin) && username != principal.username) {
+        let repo = req.app_data::<web::Data<dyn UserRepository>>().expect("UserRepository is registered");
+        let grants = req.app_data::<web::Data<RoleGrants>>().expect("RoleGrants is registered");
+        if grants.roles_of(&***repo, username).await?.is_some_and(|roles| roles.contains(&Role::Admin)) {
+            return Err(ApiError::Forbidden(policy.action));
+        }
+    }
+    Ok(principal)
+}
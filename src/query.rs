@@ -0,0 +1,215 @@
+use std::cmp::Ordering;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::error::ApiError;
+use crate::model::User;
+use crate::pagination::{PageRequest, PageStart};
+
+/// A [`User`] field that can be filtered or sorted on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UserField {
+    Username,
+    FirstName,
+    LastName,
+    Email,
+}
+
+impl UserField {
+    /// Parses a field name as it appears in the User JSON.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "username" => Some(UserField::Username),
+            "first_name" => Some(UserField::FirstName),
+            "last_name" => Some(UserField::LastName),
+            "email" => Some(UserField::Email),
+            _ => None,
+        }
+    }
+
+    /// Name of the field in the User JSON and in the stored document.
+    pub fn name(self) -> &'static str {
+        match self {
+            UserField::Username => "username",
+            UserField::FirstName => "first_name",
+            UserField::LastName => "last_name",
+            UserField::Email => "email",
+        }
+    }
+
+    pub fn get(self, user: &User) -> &str {
+        match self {
+            UserField::Username => &user.username,
+            UserField::FirstName => &user.first_name,
+            UserField::LastName => &user.last_name,
+            UserField::Email => &user.email,
+        }
+    }
+}
+
+/// A single condition a listed user must satisfy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FieldFilter {
+    /// The field equals the value.
+    Exact(UserField, String),
+    /// The field starts with the value.
+    Prefix(UserField, String),
+    /// The email address belongs to the domain, which is lowercase. Only ASCII
+    /// letters compare case-insensitively, in every store.
+    EmailDomain(String),
+}
+
+impl FieldFilter {
+    pub fn matches(&self, user: &User) -> bool {
+        match self {
+            FieldFilter::Exact(field, value) => field.get(user) == value,
+            FieldFilter::Prefix(field, prefix) => field.get(user).starts_with(prefix.as_str()),
+            FieldFilter::EmailDomain(domain) => user
+                .email
+                .rsplit_once('@')
+                .is_some_and(|(_, email_domain)| email_domain.to_ascii_lowercase() == *domain),
+        }
+    }
+}
+
+/// One component of the listing order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SortKey {
+    pub field: UserField,
+    pub descending: bool,
+}
+
+/// Filter and sort query parameters accepted by `get_users`.
+///
+/// `sort` is a comma-separated list of field names, each optionally prefixed
+/// with `-` for descending order, e.g. `last_name,-username`.
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct UserListQuery {
+    /// Only the user with exactly this username.
+    pub username: Option<String>,
+    /// Only users whose username starts with this value.
+    pub username_prefix: Option<String>,
+    /// Only users with exactly this first name.
+    pub first_name: Option<String>,
+    /// Only users whose first name starts with this value.
+    pub first_name_prefix: Option<String>,
+    /// Only users with exactly this last name.
+    pub last_name: Option<String>,
+    /// Only users whose last name starts with this value.
+    pub last_name_prefix: Option<String>,
+    /// Only users whose email address is in this domain, ignoring case.
+    pub email_domain: Option<String>,
+    /// Comma-separated field names, `-` prefixed for descending order, e.g. `last_name,-username`.
+    pub sort: Option<String>,
+}
+
+/// Which users to list and in what order.
+///
+/// The order always ends with `username`, which is unique, so that keyset
+/// pagination over any sort is stable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserQuery {
+    pub filters: Vec<FieldFilter>,
+    pub sort: Vec<SortKey>,
+}
+
+impl UserListQuery {
+    pub fn into_query(self) -> Result<UserQuery, ApiError> {
+        let mut filters = Vec::new();
+        let exact = [
+            (UserField::Username, self.username),
+            (UserField::FirstName, self.first_name),
+            (UserField::LastName, self.last_name),
+        ];
+        let prefix = [
+            (UserField::Username, self.username_prefix),
+            (UserField::FirstName, self.first_name_prefix),
+            (UserField::LastName, self.last_name_prefix),
+        ];
+        filters.extend(exact.into_iter().filter_map(|(field, value)| Some(FieldFilter::Exact(field, value?))));
+        filters.extend(prefix.into_iter().filter_map(|(field, value)| Some(FieldFilter::Prefix(field, value?))));
+        if let Some(domain) = self.email_domain {
+            filters.push(FieldFilter::EmailDomain(domain.to_ascii_lowercase()));
+        }
+
+        let mut sort = Vec::new();
+        for key in self.sort.iter().flat_map(|sort| sort.split(',')) {
+            let (name, descending) = match key.strip_prefix('-') {
+                Some(name) => (name, true),
+                None => (key, false),
+            };
+            let field = UserField::parse(name)
+                .ok_or_else(|| ApiError::ValidationFailed(format!("cannot sort by {key:?}")))?;
+            if sort.iter().any(|existing: &SortKey| existing.field == field) {
+                return Err(ApiError::ValidationFailed(format!("{name} appears twice in sort")));
+            }
+            sort.push(SortKey { field, descending });
+        }
+        if !sort.iter().any(|key| key.field == UserField::Username) {
+            sort.push(SortKey { field: UserField::Username, descending: false });
+        }
+
+        Ok(UserQuery { filters, sort })
+    }
+}
+
+impl UserQuery {
+    pub fn matches(&self, user: &User) -> bool {
+        self.filters.iter().all(|filter| filter.matches(user))
+    }
+
+    /// Values of the sort keys for `user`, as stored in a page cursor.
+    pub fn sort_values(&self, user: &User) -> Vec<String> {
+        self.sort.iter().map(|key| key.field.get(user).to_string()).collect()
+    }
+
+    /// The sort as a string, such as `last_name,-email`, stored in page cursors
+    /// ahead of the sort values.
+    fn sort_spec(&self) -> String {
+        let keys: Vec<String> = self
+            .sort
+            .iter()
+            .map(|key| format!("{}{}", if key.descending { "-" } else { "" }, key.field.name()))
+            .collect();
+        keys.join(",")
+    }
+
+    /// What the cursor to the page after `user` holds: the sort, then the
+    /// values of its keys for `user`.
+    pub fn cursor_values(&self, user: &User) -> Vec<String> {
+        let mut values = vec![self.sort_spec()];
+        values.extend(self.sort_values(user));
+        values
+    }
+
+    /// Compares a user against cursor values in listing order.
+    pub fn compare(&self, user: &User, values: &[String]) -> Ordering {
+        self.sort
+            .iter()
+            .zip(values)
+            .map(|(key, value)| {
+                let ordering = key.field.get(user).cmp(value.as_str());
+                if key.descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+
+    /// Rejects cursors that were issued for a different sort, and leaves only
+    /// the sort values in those that were not.
+    pub fn check_page(&self, page: &mut PageRequest) -> Result<(), ApiError> {
+        let PageStart::After(values) = &mut page.start else {
+            return Ok(());
+        };
+        if values.len() != self.sort.len() + 1 || values[0] != self.sort_spec() {
+            return Err(ApiError::ValidationFailed("cursor does not match the requested sort".into()));
+        }
+        values.remove(0);
+        Ok(())
+    }
+}
@@ -0,0 +1,635 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::RwLock;
+
+use async_trait::async_trait;
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    error::{CommandError, ErrorKind, WriteFailure},
+    options::IndexOptions,
+    Client, Collection, IndexModel,
+};
+use uuid::Uuid;
+
+use crate::model::{Role, User, UserPatch};
+use crate::pagination::{PageRequest, PageStart};
+use crate::query::{FieldFilter, UserQuery};
+use crate::streaming::UserStream;
+
+/// Errors returned by a [`UserRepository`].
+#[derive(Debug)]
+pub enum RepositoryError {
+    /// A unique index rejected the write; holds the name of the offending field.
+    DuplicateKey(String),
+    /// The underlying MongoDB driver reported an error.
+    Mongo(mongodb::error::Error),
+    /// Storage initialization has not finished yet.
+    NotReady,
+    /// A stored time is outside the range of RFC 3339.
+    InvalidTime(mongodb::bson::datetime::Error),
+}
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepositoryError::DuplicateKey(field) => write!(f, "duplicate value for unique field {field}"),
+            RepositoryError::Mongo(err) => err.fmt(f),
+            RepositoryError::NotReady => f.write_str("storage is not ready"),
+            RepositoryError::InvalidTime(err) => write!(f, "stored time out of range: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for RepositoryError {}
+
+impl From<mongodb::error::Error> for RepositoryError {
+    fn from(err: mongodb::error::Error) -> Self {
+        match duplicate_key_field(&err) {
+            Some(field) => RepositoryError::DuplicateKey(field),
+            None => RepositoryError::Mongo(err),
+        }
+    }
+}
+
+/// Server error code for a write rejected by a unique index.
+const DUPLICATE_KEY_CODE: i32 = 11000;
+/// Server error code for a command on a collection that does not exist.
+const NAMESPACE_NOT_FOUND_CODE: i32 = 26;
+
+/// Returns the field named by a duplicate key (E11000) write error, if `err` is one.
+///
+/// The structured `keyPattern` of the error is preferred, then the `dup key`
+/// document of its message, and the index name only as a last resort.
+fn duplicate_key_field(err: &mongodb::error::Error) -> Option<String> {
+    match err.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(write_error))
+            if write_error.code == DUPLICATE_KEY_CODE =>
+        {
+            Some(
+                write_error
+                    .details
+                    .as_ref()
+                    .and_then(duplicate_key_field_from_details)
+                    .or_else(|| duplicate_key_field_from_message(&write_error.message))
+                    .unwrap_or_else(|| "unknown".into()),
+            )
+        }
+        _ => None,
+    }
+}
+
+/// Extracts the field from the `keyPattern` or `keyValue` document of an
+/// E11000 error. For a compound index this is its first field.
+pub fn duplicate_key_field_from_details(details: &Document) -> Option<String> {
+    ["keyPattern", "keyValue"]
+        .into_iter()
+        .find_map(|name| details.get_document(name).ok()?.keys().next().cloned())
+}
+
+/// Extracts the field from an E11000 message such as
+/// `E11000 duplicate key error collection: myApp.users index: username_1 dup key: { username: "jane" }`.
+///
+/// The `dup key` document is preferred. Servers that omit it only name the
+/// index: the field of a single-field index such as `email_1` is recovered,
+/// while other index names are returned whole.
+pub fn duplicate_key_field_from_message(message: &str) -> Option<String> {
+    if let Some((_, dup_key)) = message.split_once("dup key: {") {
+        let field = dup_key.split(':').next()?.trim().trim_matches('"');
+        if !field.is_empty() {
+            return Some(field.into());
+        }
+    }
+
+    let (_, index) = message.split_once("index: ")?;
+    let index = index.split_whitespace().next()?;
+    let single_field = index
+        .strip_suffix("_1")
+        .or_else(|| index.strip_suffix("_-1"))
+        .filter(|field| !field.contains("_1_") && !field.contains("_-1_"));
+    Some(single_field.unwrap_or(index).into())
+}
+
+/// The `$set` document of a patch.
+fn patch_set(patch: UserPatch) -> Document {
+    let mut set = doc! {};
+    if let Some(first_name) = patch.first_name {
+        set.insert("first_name", first_name);
+    }
+    if let Some(last_name) = patch.last_name {
+        set.insert("last_name", last_name);
+    }
+    if let Some(email) = patch.email {
+        set.insert("email", email);
+    }
+    set
+}
+
+/// Storage operations the HTTP handlers need for users.
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    /// Stores a new user, with the hash of its password if it has one, and a
+    /// new [token generation](Self::find_token_generation).
+    async fn insert(&self, user: User, password_hash: Option<String>) -> Result<(), RepositoryError>;
+
+    /// Gets the user with the supplied username, if any.
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, RepositoryError>;
+
+    /// Gets at most `page.limit` users matching `query`, in its order, starting at `page.start`.
+    async fn find_page(&self, query: &UserQuery, page: &PageRequest) -> Result<Vec<User>, RepositoryError>;
+
+    /// Streams every user matching `query`, in its order, without buffering them.
+    async fn stream(&self, query: &UserQuery) -> Result<UserStream, RepositoryError>;
+
+    /// Applies `patch` to the user with the supplied username.
+    /// Returns `false` when no such user exists.
+    async fn update(&self, username: &str, patch: UserPatch) -> Result<bool, RepositoryError>;
+
+    /// Applies `patch` to the user `current` was read from, unless that user
+    /// has changed since. Returns `false` when it was changed or deleted.
+    async fn update_if_unchanged(&self, current: &User, patch: UserPatch) -> Result<bool, RepositoryError>;
+
+    /// Deletes the user with the supplied username.
+    /// Returns `false` when no such user exists.
+    async fn delete(&self, username: &str) -> Result<bool, RepositoryError>;
+
+    /// Gets the password hash of the user with the supplied username, if the
+    /// user exists and has a password.
+    async fn find_password_hash(&self, username: &str) -> Result<Option<String>, RepositoryError>;
+
+    /// Replaces the password hash of the user with the supplied username.
+    /// Returns `false` when no such user exists.
+    async fn set_password_hash(&self, username: &str, password_hash: String) -> Result<bool, RepositoryError>;
+
+    /// Gets the roles of the user with the supplied username, or `None` if the
+    /// user does not exist. Users never assigned roles have
+    /// [`Role::defaults`].
+    async fn find_roles(&self, username: &str) -> Result<Option<Vec<Role>>, RepositoryError>;
+
+    /// Replaces the roles of the user with the supplied username. Returns
+    /// `false` when no such user exists.
+    async fn set_roles(&self, username: &str, roles: Vec<Role>) -> Result<bool, RepositoryError>;
+
+    /// Gets the token generation of the user with the supplied username, or
+    /// `None` if the user does not exist. It is random and set when the user
+    /// is created, so refresh tokens name the user they were issued to rather
+    /// than any user of the same name.
+    async fn find_token_generation(&self, username: &str) -> Result<Option<String>, RepositoryError>;
+
+    /// Gives the user with the supplied username a new token generation, so
+    /// every refresh token issued to them so far is rejected. Returns `false`
+    /// when no such user exists.
+    async fn rotate_token_generation(&self, username: &str) -> Result<bool, RepositoryError>;
+
+    /// Gets the email address of the user with the supplied username, if the
+    /// user exists and has verified that address.
+    async fn find_verified_email(&self, username: &str) -> Result<Option<String>, RepositoryError>;
+
+    /// Records that the user with the supplied username verified `email`.
+    /// Returns `false` when no such user exists or `email` is no longer their
+    /// address.
+    async fn set_verified_email(&self, username: &str, email: &str) -> Result<bool, RepositoryError>;
+
+    /// Checks that the store can be reached.
+    async fn ping(&self) -> Result<(), RepositoryError>;
+
+    /// Checks whether the unique index on usernames exists.
+    async fn username_index_exists(&self) -> Result<bool, RepositoryError>;
+}
+
+/// Document field holding the password hash.
+///
+/// It is not a [`User`] field, so reading documents as users drops it and it
+/// can never be returned by the API.
+const PASSWORD_HASH_FIELD: &str = "password_hash";
+/// Document field holding the roles, absent until roles are first assigned.
+const ROLES_FIELD: &str = "roles";
+/// Document field holding the token generation. Users created before it was
+/// introduced lack it and have the empty generation.
+const TOKEN_GENERATION_FIELD: &str = "token_generation";
+/// Document field holding the last email address the user verified. The
+/// user's address is verified while it equals this one, so changing the
+/// address needs no extra write.
+const VERIFIED_EMAIL_FIELD: &str = "verified_email";
+
+/// [`UserRepository`] backed by a MongoDB collection.
+#[derive(Clone)]
+pub struct MongoUserRepository {
+    collection: Collection<User>,
+}
+
+impl MongoUserRepository {
+    pub fn new(client: &Client, db_name: &str, coll_name: &str) -> Self {
+        Self { collection: client.database(db_name).collection(coll_name) }
+    }
+
+    /// Creates an index on the "username" field to force the values to be unique.
+    pub async fn create_username_index(&self) -> Result<(), RepositoryError> {
+        let options = IndexOptions::builder().unique(true).build();
+        let model = IndexModel::builder()
+            .keys(doc! { "username": 1 })
+            .options(options)
+            .build();
+        self.collection.create_index(model).await?;
+        Ok(())
+    }
+
+    /// Removes every document from the collection.
+    #[cfg(test)]
+    pub async fn drop(&self) -> Result<(), RepositoryError> {
+        self.collection.drop().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UserRepository for MongoUserRepository {
+    async fn insert(&self, user: User, password_hash: Option<String>) -> Result<(), RepositoryError> {
+        let mut document = mongodb::bson::to_document(&user).expect("users always serialize");
+        if let Some(password_hash) = password_hash {
+            document.insert(PASSWORD_HASH_FIELD, password_hash);
+        }
+        document.insert(TOKEN_GENERATION_FIELD, Uuid::new_v4().to_string());
+        self.collection.clone_with_type::<Document>().insert_one(document).await?;
+        Ok(())
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, RepositoryError> {
+        Ok(self.collection.find_one(doc! { "username": username }).await?)
+    }
+
+    async fn find_page(&self, query: &UserQuery, page: &PageRequest) -> Result<Vec<User>, RepositoryError> {
+        let mut clauses: Vec<Document> = query.filters.iter().map(filter_clause).collect();
+        let skip = match &page.start {
+            PageStart::Offset(offset) => *offset,
+            PageStart::After(values) => {
+                clauses.push(after_clause(query, values));
+                0
+            }
+        };
+
+        let cursor = self
+            .collection
+            .find(and_document(clauses))
+            .sort(sort_document(query))
+            .skip(skip)
+            .limit(i64::try_from(page.limit).unwrap_or(i64::MAX))
+            .await?;
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn stream(&self, query: &UserQuery) -> Result<UserStream, RepositoryError> {
+        let filter = and_document(query.filters.iter().map(filter_clause).collect());
+        let cursor = self.collection.find(filter).sort(sort_document(query)).await?;
+        Ok(cursor.map_err(RepositoryError::from).boxed())
+    }
+
+    async fn update(&self, username: &str, patch: UserPatch) -> Result<bool, RepositoryError> {
+        let result = self
+            .collection
+            .update_one(doc! { "username": username }, doc! { "$set": patch_set(patch) })
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn update_if_unchanged(&self, current: &User, patch: UserPatch) -> Result<bool, RepositoryError> {
+        // Every field of `current` must still hold, so a concurrent write makes the filter miss.
+        let filter = mongodb::bson::to_document(current).expect("users always serialize");
+        let result = self.collection.update_one(filter, doc! { "$set": patch_set(patch) }).await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn delete(&self, username: &str) -> Result<bool, RepositoryError> {
+        let result = self.collection.delete_one(doc! { "username": username }).await?;
+        Ok(result.deleted_count > 0)
+    }
+
+    async fn find_password_hash(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        let document = self
+            .collection
+            .clone_with_type::<Document>()
+            .find_one(doc! { "username": username })
+            .projection(doc! { PASSWORD_HASH_FIELD: 1 })
+            .await?;
+        Ok(document.and_then(|document| document.get_str(PASSWORD_HASH_FIELD).ok().map(str::to_string)))
+    }
+
+    async fn set_password_hash(&self, username: &str, password_hash: String) -> Result<bool, RepositoryError> {
+        let result = self
+            .collection
+            .update_one(doc! { "username": username }, doc! { "$set": { PASSWORD_HASH_FIELD: password_hash } })
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn find_roles(&self, username: &str) -> Result<Option<Vec<Role>>, RepositoryError> {
+        let document = self
+            .collection
+            .clone_with_type::<Document>()
+            .find_one(doc! { "username": username })
+            .projection(doc! { ROLES_FIELD: 1 })
+            .await?;
+        Ok(document.map(|document| match document.get(ROLES_FIELD) {
+            // Unknown roles written by a newer version grant nothing here.
+            Some(Bson::Array(roles)) => roles.iter().filter_map(|role| mongodb::bson::from_bson(role.clone()).ok()).collect(),
+            _ => Role::defaults(),
+        }))
+    }
+
+    async fn set_roles(&self, username: &str, roles: Vec<Role>) -> Result<bool, RepositoryError> {
+        let roles = mongodb::bson::to_bson(&roles).expect("roles always serialize");
+        let result = self
+            .collection
+            .update_one(doc! { "username": username }, doc! { "$set": { ROLES_FIELD: roles } })
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn find_token_generation(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        let document = self
+            .collection
+            .clone_with_type::<Document>()
+            .find_one(doc! { "username": username })
+            .projection(doc! { TOKEN_GENERATION_FIELD: 1 })
+            .await?;
+        Ok(document.map(|document| document.get_str(TOKEN_GENERATION_FIELD).unwrap_or_default().to_string()))
+    }
+
+    async fn rotate_token_generation(&self, username: &str) -> Result<bool, RepositoryError> {
+        let result = self
+            .collection
+            .update_one(doc! { "username": username }, doc! { "$set": { TOKEN_GENERATION_FIELD: Uuid::new_v4().to_string() } })
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn find_verified_email(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        let document = self
+            .collection
+            .clone_with_type::<Document>()
+            .find_one(doc! { "username": username })
+            .projection(doc! { "email": 1, VERIFIED_EMAIL_FIELD: 1 })
+            .await?;
+        Ok(document.and_then(|document| {
+            let email = document.get_str("email").ok()?;
+            (document.get_str(VERIFIED_EMAIL_FIELD).ok()? == email).then(|| email.to_string())
+        }))
+    }
+
+    async fn set_verified_email(&self, username: &str, email: &str) -> Result<bool, RepositoryError> {
+        let result = self
+            .collection
+            .update_one(doc! { "username": username, "email": email }, doc! { "$set": { VERIFIED_EMAIL_FIELD: email } })
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn ping(&self) -> Result<(), RepositoryError> {
+        self.collection.client().database("admin").run_command(doc! { "ping": 1 }).await?;
+        Ok(())
+    }
+
+    async fn username_index_exists(&self) -> Result<bool, RepositoryError> {
+        let indexes = match self.collection.list_indexes().await {
+            Ok(cursor) => cursor,
+            Err(err) if matches!(err.kind.as_ref(), ErrorKind::Command(CommandError { code: NAMESPACE_NOT_FOUND_CODE, .. })) => {
+                return Ok(false);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let indexes: Vec<IndexModel> = indexes.try_collect().await?;
+        Ok(indexes.iter().any(|index| {
+            index.keys == doc! { "username": 1 }
+                && index.options.as_ref().and_then(|options| options.unique) == Some(true)
+        }))
+    }
+}
+
+/// Combines query clauses, all of which must match.
+fn and_document(clauses: Vec<Document>) -> Document {
+    if clauses.is_empty() {
+        doc! {}
+    } else {
+        doc! { "$and": clauses }
+    }
+}
+
+fn sort_document(query: &UserQuery) -> Document {
+    query
+        .sort
+        .iter()
+        .map(|key| (key.field.name().to_string(), Bson::Int32(if key.descending { -1 } else { 1 })))
+        .collect()
+}
+
+/// Translates a filter into a query document.
+///
+/// Values are only ever placed in operand position, and prefixes are
+/// regex-escaped, so request input cannot introduce query operators.
+pub fn filter_clause(filter: &FieldFilter) -> Document {
+    match filter {
+        FieldFilter::Exact(field, value) => doc! { field.name(): { "$eq": value } },
+        FieldFilter::Prefix(field, prefix) => {
+            doc! { field.name(): { "$regex": format!("^{}", regex::escape(prefix)) } }
+        }
+        FieldFilter::EmailDomain(domain) => {
+            // The `i` option would also fold non-ASCII letters, which the
+            // in-memory store compares exactly.
+            let domain: String = domain
+                .chars()
+                .map(|c| match c {
+                    'a'..='z' => format!("[{c}{}]", c.to_ascii_uppercase()),
+                    _ => regex::escape(c.encode_utf8(&mut [0; 4])),
+                })
+                .collect();
+            doc! { "email": { "$regex": format!("@{domain}$") } }
+        }
+    }
+}
+
+/// Matches the users that sort after the cursor `values`.
+///
+/// For sort keys `k1..kn` this is `k1 > v1 OR (k1 = v1 AND k2 > v2) OR ...`,
+/// with `<` in place of `>` for descending keys.
+fn after_clause(query: &UserQuery, values: &[String]) -> Document {
+    let branches: Vec<Document> = (0..query.sort.len())
+        .map(|i| {
+            let mut branch = Document::new();
+            for (key, value) in query.sort[..i].iter().zip(values) {
+                branch.insert(key.field.name(), doc! { "$eq": value });
+            }
+            let key = query.sort[i];
+            let operator = if key.descending { "$lt" } else { "$gt" };
+            branch.insert(key.field.name(), doc! { operator: &values[i] });
+            branch
+        })
+        .collect();
+    doc! { "$or": branches }
+}
+
+/// [`UserRepository`] that keeps users in process memory, keyed by username.
+///
+/// Mirrors the unique username index of the MongoDB collection, so it can stand
+/// in for a live database when running the API locally or in tests.
+#[derive(Default)]
+pub struct InMemoryUserRepository {
+    users: RwLock<BTreeMap<String, User>>,
+    password_hashes: RwLock<BTreeMap<String, String>>,
+    roles: RwLock<BTreeMap<String, Vec<Role>>>,
+    token_generations: RwLock<BTreeMap<String, String>>,
+    verified_emails: RwLock<BTreeMap<String, String>>,
+}
+
+impl InMemoryUserRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl UserRepository for InMemoryUserRepository {
+    async fn insert(&self, user: User, password_hash: Option<String>) -> Result<(), RepositoryError> {
+        let mut users = self.users.write().unwrap();
+        if users.contains_key(&user.username) {
+            return Err(RepositoryError::DuplicateKey("username".into()));
+        }
+        if let Some(password_hash) = password_hash {
+            self.password_hashes.write().unwrap().insert(user.username.clone(), password_hash);
+        }
+        self.token_generations.write().unwrap().insert(user.username.clone(), Uuid::new_v4().to_string());
+        users.insert(user.username.clone(), user);
+        Ok(())
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, RepositoryError> {
+        Ok(self.users.read().unwrap().get(username).cloned())
+    }
+
+    async fn find_page(&self, query: &UserQuery, page: &PageRequest) -> Result<Vec<User>, RepositoryError> {
+        let users = self.users.read().unwrap();
+        let mut matching: Vec<&User> = users.values().filter(|user| query.matches(user)).collect();
+        matching.sort_by(|a, b| query.compare(a, &query.sort_values(b)));
+
+        let skip = match &page.start {
+            PageStart::Offset(offset) => usize::try_from(*offset).unwrap_or(usize::MAX),
+            PageStart::After(values) => {
+                matching.partition_point(|user| query.compare(user, values).is_le())
+            }
+        };
+        Ok(matching
+            .into_iter()
+            .skip(skip)
+            .take(usize::try_from(page.limit).unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+
+    async fn stream(&self, query: &UserQuery) -> Result<UserStream, RepositoryError> {
+        let users = self.users.read().unwrap();
+        let mut matching: Vec<User> = users.values().filter(|user| query.matches(user)).cloned().collect();
+        matching.sort_by(|a, b| query.compare(a, &query.sort_values(b)));
+        Ok(stream::iter(matching.into_iter().map(Ok)).boxed())
+    }
+
+    async fn update(&self, username: &str, patch: UserPatch) -> Result<bool, RepositoryError> {
+        let mut users = self.users.write().unwrap();
+        let Some(user) = users.get_mut(username) else {
+            return Ok(false);
+        };
+        patch.apply_to(user);
+        Ok(true)
+    }
+
+    async fn update_if_unchanged(&self, current: &User, patch: UserPatch) -> Result<bool, RepositoryError> {
+        let mut users = self.users.write().unwrap();
+        match users.get_mut(&current.username) {
+            Some(user) if user == current => {
+                patch.apply_to(user);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn delete(&self, username: &str) -> Result<bool, RepositoryError> {
+        let mut users = self.users.write().unwrap();
+        self.password_hashes.write().unwrap().remove(username);
+        self.roles.write().unwrap().remove(username);
+        self.token_generations.write().unwrap().remove(username);
+        self.verified_emails.write().unwrap().remove(username);
+        Ok(users.remove(username).is_some())
+    }
+
+    async fn find_password_hash(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        Ok(self.password_hashes.read().unwrap().get(username).cloned())
+    }
+
+    async fn set_password_hash(&self, username: &str, password_hash: String) -> Result<bool, RepositoryError> {
+        // Lock users first, as insert and delete do.
+        let users = self.users.read().unwrap();
+        if !users.contains_key(username) {
+            return Ok(false);
+        }
+        self.password_hashes.write().unwrap().insert(username.into(), password_hash);
+        Ok(true)
+    }
+
+    async fn find_roles(&self, username: &str) -> Result<Option<Vec<Role>>, RepositoryError> {
+        let users = self.users.read().unwrap();
+        if !users.contains_key(username) {
+            return Ok(None);
+        }
+        Ok(Some(self.roles.read().unwrap().get(username).cloned().unwrap_or_else(Role::defaults)))
+    }
+
+    async fn set_roles(&self, username: &str, roles: Vec<Role>) -> Result<bool, RepositoryError> {
+        let users = self.users.read().unwrap();
+        if !users.contains_key(username) {
+            return Ok(false);
+        }
+        self.roles.write().unwrap().insert(username.into(), roles);
+        Ok(true)
+    }
+
+    async fn find_token_generation(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        if !self.users.read().unwrap().contains_key(username) {
+            return Ok(None);
+        }
+        Ok(Some(self.token_generations.read().unwrap().get(username).cloned().unwrap_or_default()))
+    }
+
+    async fn rotate_token_generation(&self, username: &str) -> Result<bool, RepositoryError> {
+        if !self.users.read().unwrap().contains_key(username) {
+            return Ok(false);
+        }
+        self.token_generations.write().unwrap().insert(username.into(), Uuid::new_v4().to_string());
+        Ok(true)
+    }
+
+    async fn find_verified_email(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        let users = self.users.read().unwrap();
+        let Some(user) = users.get(username) else {
+            return Ok(None);
+        };
+        let verified = self.verified_emails.read().unwrap().get(username) == Some(&user.email);
+        Ok(verified.then(|| user.email.clone()))
+    }
+
+    async fn set_verified_email(&self, username: &str, email: &str) -> Result<bool, RepositoryError> {
+        let users = self.users.read().unwrap();
+        if users.get(username).is_none_or(|user| user.email != email) {
+            return Ok(false);
+        }
+        self.verified_emails.write().unwrap().insert(username.into(), email.into());
+        Ok(true)
+    }
+
+    async fn ping(&self) -> Result<(), RepositoryError> {
+        Ok(())
+    }
+
+    /// The map is keyed by username, which serves as the unique index.
+    async fn username_index_exists(&self) -> Result<bool, RepositoryError> {
+        Ok(true)
+    }
+}
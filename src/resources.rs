@@ -0,0 +1,234 @@
+//! Resource-oriented user routes, shared by every API version.
+//!
+//! Each handler is generic over the [`UserRepresentation`] its version serves.
+
+use actix_web::{http::header::LOCATION, web, HttpMessage, HttpRequest, HttpResponse, Scope};
+use serde_json::Value;
+use validator::Validate;
+
+use crate::accounts::{self, Passwords, Registration};
+use crate::auth::Principal;
+use crate::email_tokens::{self, EmailTokens};
+use crate::error::ApiError;
+use crate::model::{RoleAssignment, User, UserPatch};
+use crate::pagination::{Page, PageLimits, PageQuery};
+use crate::patch;
+use crate::policy::{self, Authorize};
+use crate::query::UserListQuery;
+use crate::repository::UserRepository;
+use crate::sessions::{self, Sessions};
+use crate::streaming::{stream_response, StreamQuery};
+use crate::versioning::{localize_error, UserRepresentation};
+
+/// Builds the users collection and item resources under `path`, served in
+/// representation `R`. Every route but reading a single user is subject to a
+/// [`policy`].
+pub fn scope<R: UserRepresentation>(path: &str) -> Scope {
+    web::scope(path)
+        .service(
+            web::resource("")
+                .route(web::post().to(create_user::<R>).wrap(Authorize(policy::CREATE_USER)))
+                .route(web::get().to(list_users::<R>).wrap(Authorize(policy::LIST_USERS))),
+        )
+        .service(
+            web::resource("/{username}")
+                .route(web::get().to(get_user::<R>))
+                .route(web::put().to(replace_user::<R>).wrap(Authorize(policy::UPDATE_USER)))
+                .route(web::patch().to(patch_user::<R>).wrap(Authorize(policy::UPDATE_USER)))
+                .route(web::delete().to(delete_user).wrap(Authorize(policy::DELETE_USER))),
+        )
+        .service(
+            web::resource("/{username}/roles")
+                .route(web::get().to(get_roles))
+                .route(web::put().to(replace_roles))
+                .wrap(Authorize(policy::MANAGE_ROLES)),
+        )
+        .service(
+            web::resource("/{username}/sessions")
+                .route(web::delete().to(sessions::end_sessions))
+                .wrap(Authorize(policy::END_SESSIONS)),
+        )
+        .service(
+            web::resource("/{username}/email-verification")
+                .route(web::post().to(email_tokens::resend_verification))
+                .wrap(Authorize(policy::SEND_VERIFICATION)),
+        )
+}
+
+fn validate<R: UserRepresentation>(value: &impl Validate) -> Result<(), ApiError> {
+    value.validate().map_err(|err| localize_error::<R>(err.into()))
+}
+
+fn render<R: UserRepresentation>(response: &mut actix_web::HttpResponseBuilder, user: User) -> HttpResponse {
+    response.content_type(R::CONTENT_TYPE).json(R::from(user))
+}
+
+/// Creates a user, with a password if the body has one, mails them a link to
+/// verify their email address and returns them with their location.
+pub async fn create_user<R: UserRepresentation>(repo: web::Data<dyn UserRepository>, passwords: web::Data<Passwords>, tokens: web::Data<EmailTokens>, json: web::Json<Value>) -> Result<HttpResponse, ApiError> {
+    let registration = Registration::<R>::parse(json.into_inner())?;
+    let user: User = registration.user.into();
+    validate::<R>(&user)?;
+    let password_hash = accounts::password_hash(&passwords, registration.password).await?;
+    repo.insert(user.clone(), password_hash).await?;
+    send_verification_to_created(tokens, &user);
+    let location = format!("{}/{}", R::USERS_PATH, user.username);
+    Ok(render::<R>(HttpResponse::Created().insert_header((LOCATION, location)), user))
+}
+
+/// Gets one page of the users in the collection, optionally filtered and sorted.
+///
+/// With `?stream=json` or `?stream=ndjson` every matching user is streamed
+/// instead, for exports too large to page through.
+pub async fn list_users<R: UserRepresentation>(repo: web::Data<dyn UserRepository>, limits: web::Data<PageLimits>, page: web::Query<PageQuery>, list: web::Query<UserListQuery>, stream: web::Query<StreamQuery>) -> Result<HttpResponse, ApiError> {
+    let query = list.into_inner().into_query()?;
+    if let Some(format) = stream.stream {
+        if page.limit.is_some() || page.offset.is_some() || page.cursor.is_some() {
+            return Err(ApiError::ValidationFailed("stream cannot be combined with paging parameters".into()));
+        }
+        return Ok(stream_response::<R>(format, repo.stream(&query).await?));
+    }
+
+    let mut page = page.into_inner().into_request(&limits)?;
+    query.check_page(&mut page)?;
+    let limit = page.limit;
+    // Fetch one extra user to learn whether another page follows.
+    page.limit = page.limit.saturating_add(1);
+    let users = repo.find_page(&query, &page).await?;
+    let page = Page::from_overfetch(users, limit, |user| query.cursor_values(user));
+    let page = Page { items: page.items.into_iter().map(R::from).collect(), next_cursor: page.next_cursor };
+    Ok(HttpResponse::Ok().content_type(R::CONTENT_TYPE).json(page))
+}
+
+/// Gets the user with the supplied username.
+pub async fn get_user<R: UserRepresentation>(repo: web::Data<dyn UserRepository>, username: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let username = username.into_inner();
+    match repo.find_by_username(&username).await? {
+        Some(user) => Ok(render::<R>(&mut HttpResponse::Ok(), user)),
+        None => Err(ApiError::UserNotFound(username)),
+    }
+}
+
+/// Replaces every field of the user with the supplied username.
+///
+/// The username in the body must match the one in the path.
+pub async fn replace_user<R: UserRepresentation>(repo: web::Data<dyn UserRepository>, principal: web::ReqData<Principal>, username: web::Path<String>, json: web::Json<R>) -> Result<HttpResponse, ApiError> {
+    let username = username.into_inner();
+    let user: User = json.into_inner().into();
+    if user.username != username {
+        return Err(ApiError::ValidationFailed("username cannot be changed".into()));
+    }
+    validate::<R>(&user)?;
+    check_email_change(&**repo, &principal, &username, Some(&user.email)).await?;
+
+    let patch = UserPatch {
+        first_name: Some(user.first_name.clone()),
+        last_name: Some(user.last_name.clone()),
+        email: Some(user.email.clone()),
+    };
+    if repo.update(&username, patch).await? {
+        Ok(render::<R>(&mut HttpResponse::Ok(), user))
+    } else {
+        Err(ApiError::UserNotFound(username))
+    }
+}
+
+/// Applies an `application/merge-patch+json` (RFC 7396) or
+/// `application/json-patch+json` (RFC 6902) document to the user with the
+/// supplied username and returns the patched user.
+///
+/// The document addresses the user as rendered in representation `R`. The
+/// update only applies if the user is unchanged since it was read, so `test`
+/// operations and the values a merge patch was based on still hold.
+pub async fn patch_user<R: UserRepresentation>(repo: web::Data<dyn UserRepository>, principal: web::ReqData<Principal>, username: web::Path<String>, req: HttpRequest, body: web::Bytes) -> Result<HttpResponse, ApiError> {
+    let username = username.into_inner();
+    let Some(mut user) = repo.find_by_username(&username).await? else {
+        return Err(ApiError::UserNotFound(username));
+    };
+
+    let patch = patch::apply::<R>(&user, req.content_type(), &body)?;
+    if !patch.is_empty() {
+        validate::<R>(&patch)?;
+        if patch.email.as_ref().is_some_and(|email| *email != user.email) {
+            policy::CHANGE_EMAIL.check(&principal, Some(&username))?;
+        }
+        if !repo.update_if_unchanged(&user, patch.clone()).await? {
+            return Err(match repo.find_by_username(&username).await? {
+                Some(_) => ApiError::ConcurrentUpdate(username),
+                None => ApiError::UserNotFound(username),
+            });
+        }
+        patch.apply_to(&mut user);
+    }
+    Ok(render::<R>(&mut HttpResponse::Ok(), user))
+}
+
+/// Rejects setting the email of the user with the supplied username to a new
+/// address unless [`policy::CHANGE_EMAIL`] allows the caller to.
+pub async fn check_email_change(repo: &dyn UserRepository, principal: &Principal, username: &str, email: Option<&str>) -> Result<(), ApiError> {
+    let Some(email) = email else {
+        return Ok(());
+    };
+    if policy::CHANGE_EMAIL.allows(principal, Some(username)) {
+        return Ok(());
+    }
+    // A missing user is left for the update to report.
+    match repo.find_by_username(username).await? {
+        Some(user) if user.email != email => policy::CHANGE_EMAIL.check(principal, Some(username)),
+        _ => Ok(()),
+    }
+}
+
+/// Deletes the user with the supplied username.
+pub async fn delete_user(repo: web::Data<dyn UserRepository>, sessions: web::Data<Sessions>, username: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let username = username.into_inner();
+    if repo.delete(&username).await? {
+        end_sessions_of_deleted(&sessions, &username).await;
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ApiError::UserNotFound(username))
+    }
+}
+
+/// Ends the sessions of a deleted user, so they cannot carry over to a new
+/// user of the same name. Failures are logged since the user is gone anyway.
+pub async fn end_sessions_of_deleted(sessions: &Sessions, username: &str) {
+    if let Err(err) = sessions.end_all(username).await {
+        tracing::warn!(error = %err, "cannot end the sessions of a deleted user");
+    }
+}
+
+/// Mails a new user a verification link once they are created. Failures are
+/// logged since the user was created anyway, and can ask for another link.
+pub fn send_verification_to_created(tokens: web::Data<EmailTokens>, user: &User) {
+    let (username, email) = (user.username.clone(), user.email.clone());
+    email_tokens::send_in_background("cannot send a verification email to a new user", async move {
+        tokens.send_verification(&username, &email).await
+    });
+}
+
+/// Gets the roles assigned to the user with the supplied username.
+pub async fn get_roles(repo: web::Data<dyn UserRepository>, username: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let username = username.into_inner();
+    match repo.find_roles(&username).await? {
+        Some(roles) => Ok(HttpResponse::Ok().json(RoleAssignment { roles })),
+        None => Err(ApiError::UserNotFound(username)),
+    }
+}
+
+/// Replaces the roles of the user with the supplied username.
+///
+/// Access tokens already issued keep their roles until they expire, at most
+/// [`MAX_ACCESS_TOKEN_TTL_SECS`](crate::config::MAX_ACCESS_TOKEN_TTL_SECS)
+/// later; refreshing reads the new roles.
+pub async fn replace_roles(repo: web::Data<dyn UserRepository>, username: web::Path<String>, json: web::Json<RoleAssignment>) -> Result<HttpResponse, ApiError> {
+    let username = username.into_inner();
+    let mut roles = json.into_inner().roles;
+    roles.sort();
+    roles.dedup();
+    if repo.set_roles(&username, roles.clone()).await? {
+        Ok(HttpResponse::Ok().json(RoleAssignment { roles }))
+    } else {
+        Err(ApiError::UserNotFound(username))
+    }
+}
@@ -0,0 +1,46 @@
+//! Opaque secrets handed to clients, such as API keys and session ids, and
+//! the times they expire at.
+//!
+//! Secrets are random, so a fast unsalted hash cannot be reversed; only the
+//! hash is ever stored.
+
+use std::time::Duration;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use mongodb::bson::DateTime;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::repository::RepositoryError;
+
+/// Random bytes in a secret.
+const SECRET_LEN: usize = 32;
+
+/// A new unpadded base64url secret.
+pub fn generate() -> String {
+    let mut secret = [0; SECRET_LEN];
+    OsRng.fill_bytes(&mut secret);
+    URL_SAFE_NO_PAD.encode(secret)
+}
+
+/// Unpadded base64url SHA-256 digest of `secret`.
+pub fn hash(secret: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// Whether `secret` has the stored `hash`, compared in constant time.
+pub fn matches(secret: &str, hash: &str) -> bool {
+    self::hash(secret).as_bytes().ct_eq(hash.as_bytes()).into()
+}
+
+/// The time `ttl` after `time`.
+pub fn after(time: DateTime, ttl: Duration) -> DateTime {
+    let millis = i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX);
+    DateTime::from_millis(time.timestamp_millis().saturating_add(millis))
+}
+
+/// `time` as an RFC 3339 string, for API responses.
+pub fn rfc3339(time: DateTime) -> Result<String, RepositoryError> {
+    time.try_to_rfc3339_string().map_err(RepositoryError::InvalidTime)
+}
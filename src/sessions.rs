@@ -0,0 +1,300 @@
+//! Cookie sessions, an alternative to bearer tokens for browsers.
+//!
+//! `POST /session` checks a username and password like `POST /login`, but
+//! answers with an HttpOnly session cookie instead of tokens. Any session the
+//! browser already had is ended first, so an id planted before login never
+//! becomes authenticated. Sessions are stored in their own collection, keyed
+//! by a hash of their id, and expire a fixed time after login; a TTL index
+//! removes them from MongoDB. `DELETE /session` logs out, and
+//! `DELETE /users/{username}/sessions` ends every session of a user and
+//! revokes their refresh tokens.
+//!
+//! Roles are read from the user store on every request, so unlike with
+//! tokens, role changes apply at once.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use actix_web::{
+    cookie::{time, Cookie, SameSite},
+    dev::ServiceRequest,
+    web, HttpRequest, HttpResponse,
+};
+use async_trait::async_trait;
+use mongodb::{
+    bson::{doc, DateTime},
+    options::IndexOptions,
+    Client, Collection, IndexModel,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::{self, Credentials, Passwords};
+use crate::auth::Principal;
+use crate::config::{SameSitePolicy, SessionConfig};
+use crate::error::ApiError;
+use crate::model::Role;
+use crate::policy::RoleGrants;
+use crate::repository::{RepositoryError, UserRepository};
+use crate::secrets::{self, rfc3339};
+
+/// Registers `/session`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/session")
+            .route(web::post().to(login))
+            .route(web::get().to(current))
+            .route(web::delete().to(logout)),
+    );
+}
+
+/// A session as stored.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SessionRecord {
+    /// [Hash](secrets::hash) of the session id, which is only known to the
+    /// browser.
+    #[serde(rename = "_id")]
+    pub id_hash: String,
+    pub username: String,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+}
+
+/// Storage operations for sessions.
+#[async_trait]
+pub trait SessionRepository: Send + Sync {
+    async fn insert(&self, session: SessionRecord) -> Result<(), RepositoryError>;
+
+    /// Gets the session whose id has the supplied hash, if any. It may have
+    /// expired but not been removed yet.
+    async fn find(&self, id_hash: &str) -> Result<Option<SessionRecord>, RepositoryError>;
+
+    /// Deletes the session whose id has the supplied hash, if any.
+    async fn delete(&self, id_hash: &str) -> Result<(), RepositoryError>;
+
+    /// Deletes every session of the user with the supplied username.
+    async fn delete_for_user(&self, username: &str) -> Result<(), RepositoryError>;
+}
+
+/// [`SessionRepository`] backed by a MongoDB collection.
+#[derive(Clone)]
+pub struct MongoSessionRepository {
+    collection: Collection<SessionRecord>,
+}
+
+impl MongoSessionRepository {
+    pub fn new(client: &Client, db_name: &str, coll_name: &str) -> Self {
+        Self { collection: client.database(db_name).collection(coll_name) }
+    }
+
+    /// Creates the TTL index removing expired sessions, and the index on
+    /// usernames used to end every session of a user.
+    pub async fn create_indexes(&self) -> Result<(), RepositoryError> {
+        let expiry = IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+            .build();
+        let username = IndexModel::builder().keys(doc! { "username": 1 }).build();
+        self.collection.create_indexes([expiry, username]).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionRepository for MongoSessionRepository {
+    async fn insert(&self, session: SessionRecord) -> Result<(), RepositoryError> {
+        self.collection.insert_one(session).await?;
+        Ok(())
+    }
+
+    async fn find(&self, id_hash: &str) -> Result<Option<SessionRecord>, RepositoryError> {
+        Ok(self.collection.find_one(doc! { "_id": id_hash }).await?)
+    }
+
+    async fn delete(&self, id_hash: &str) -> Result<(), RepositoryError> {
+        self.collection.delete_one(doc! { "_id": id_hash }).await?;
+        Ok(())
+    }
+
+    async fn delete_for_user(&self, username: &str) -> Result<(), RepositoryError> {
+        self.collection.delete_many(doc! { "username": username }).await?;
+        Ok(())
+    }
+}
+
+/// [`SessionRepository`] that keeps sessions in process memory.
+///
+/// Expired sessions are dropped whenever one is added, standing in for the
+/// TTL index.
+#[derive(Default)]
+pub struct InMemorySessionRepository {
+    sessions: RwLock<BTreeMap<String, SessionRecord>>,
+}
+
+impl InMemorySessionRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl SessionRepository for InMemorySessionRepository {
+    async fn insert(&self, session: SessionRecord) -> Result<(), RepositoryError> {
+        let mut sessions = self.sessions.write().unwrap();
+        let now = DateTime::now();
+        sessions.retain(|_, session| now < session.expires_at);
+        if sessions.contains_key(&session.id_hash) {
+            return Err(RepositoryError::DuplicateKey("_id".into()));
+        }
+        sessions.insert(session.id_hash.clone(), session);
+        Ok(())
+    }
+
+    async fn find(&self, id_hash: &str) -> Result<Option<SessionRecord>, RepositoryError> {
+        Ok(self.sessions.read().unwrap().get(id_hash).cloned())
+    }
+
+    async fn delete(&self, id_hash: &str) -> Result<(), RepositoryError> {
+        self.sessions.write().unwrap().remove(id_hash);
+        Ok(())
+    }
+
+    async fn delete_for_user(&self, username: &str) -> Result<(), RepositoryError> {
+        self.sessions.write().unwrap().retain(|_, session| session.username != username);
+        Ok(())
+    }
+}
+
+/// Starts, checks and ends sessions, and builds their cookies.
+pub struct Sessions {
+    repo: Arc<dyn SessionRepository>,
+    cookie_name: String,
+    ttl: Duration,
+    secure: bool,
+    same_site: SameSite,
+}
+
+impl Sessions {
+    pub fn new(repo: Arc<dyn SessionRepository>, config: &SessionConfig) -> Self {
+        Self {
+            repo,
+            cookie_name: config.cookie_name.clone(),
+            ttl: config.ttl(),
+            secure: config.secure,
+            same_site: match config.same_site {
+                SameSitePolicy::Strict => SameSite::Strict,
+                SameSitePolicy::Lax => SameSite::Lax,
+                SameSitePolicy::None => SameSite::None,
+            },
+        }
+    }
+
+    /// Stores a new session for `username` and returns its id.
+    async fn start(&self, username: &str) -> Result<(String, SessionRecord), ApiError> {
+        let id = secrets::generate();
+        let created_at = DateTime::now();
+        let session = SessionRecord {
+            id_hash: secrets::hash(&id),
+            username: username.into(),
+            created_at,
+            expires_at: secrets::after(created_at, self.ttl),
+        };
+        self.repo.insert(session.clone()).await?;
+        Ok((id, session))
+    }
+
+    /// The unexpired session with the id in `cookie`.
+    async fn find(&self, cookie: &Cookie<'_>) -> Result<SessionRecord, ApiError> {
+        let session = self.repo.find(&secrets::hash(cookie.value())).await?;
+        session
+            .filter(|session| DateTime::now() < session.expires_at)
+            .ok_or(ApiError::InvalidSession)
+    }
+
+    /// Ends every session of `username`.
+    pub async fn end_all(&self, username: &str) -> Result<(), RepositoryError> {
+        self.repo.delete_for_user(username).await
+    }
+
+    fn cookie(&self, value: String, max_age: time::Duration) -> Cookie<'static> {
+        Cookie::build(self.cookie_name.clone(), value)
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(self.same_site)
+            .max_age(max_age)
+            .finish()
+    }
+
+    /// Cookie carrying a new session id, expiring with the session.
+    fn session_cookie(&self, id: String) -> Cookie<'static> {
+        let max_age = time::Duration::seconds(i64::try_from(self.ttl.as_secs()).unwrap_or(i64::MAX));
+        self.cookie(id, max_age)
+    }
+
+    /// Cookie telling the browser to forget its session id.
+    fn removal_cookie(&self) -> Cookie<'static> {
+        self.cookie(String::new(), time::Duration::ZERO)
+    }
+}
+
+/// Reads the session cookie of a request; `None` when it has none.
+pub async fn authenticate(req: &ServiceRequest) -> Result<Option<Principal>, ApiError> {
+    let sessions = req.app_data::<web::Data<Sessions>>().expect("Sessions is registered");
+    let Some(cookie) = req.cookie(&sessions.cookie_name) else {
+        return Ok(None);
+    };
+    let repo = req.app_data::<web::Data<dyn UserRepository>>().expect("UserRepository is registered");
+    let grants = req.app_data::<web::Data<RoleGrants>>().expect("RoleGrants is registered");
+    let session = sessions.find(&cookie).await?;
+    let roles = grants.roles_of(&***repo, &session.username).await?.ok_or(ApiError::InvalidSession)?;
+    Ok(Some(Principal { username: session.username, roles, scopes: Vec::new() }))
+}
+
+/// Response of `POST /session` and `GET /session`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct SessionInfo {
+    pub username: String,
+    /// The user's current roles.
+    pub roles: Vec<Role>,
+    #[schemars(extend("format" = "date-time"))]
+    pub expires_at: String,
+}
+
+/// Checks a username and password and starts a session for their user, in
+/// place of the browser's current one.
+async fn login(req: HttpRequest, repo: web::Data<dyn UserRepository>, passwords: web::Data<Passwords>, grants: web::Data<RoleGrants>, sessions: web::Data<Sessions>, json: web::Json<Credentials>) -> Result<HttpResponse, ApiError> {
+    let (username, roles) = accounts::check_credentials(&**repo, &passwords, &grants, json.into_inner()).await?;
+    if let Some(cookie) = req.cookie(&sessions.cookie_name) {
+        sessions.repo.delete(&secrets::hash(cookie.value())).await?;
+    }
+    let (id, session) = sessions.start(&username).await?;
+    Ok(HttpResponse::Ok()
+        .cookie(sessions.session_cookie(id))
+        .json(SessionInfo { username, roles, expires_at: rfc3339(session.expires_at)? }))
+}
+
+/// Describes the browser's session.
+async fn current(req: HttpRequest, repo: web::Data<dyn UserRepository>, grants: web::Data<RoleGrants>, sessions: web::Data<Sessions>) -> Result<HttpResponse, ApiError> {
+    let cookie = req.cookie(&sessions.cookie_name).ok_or(ApiError::Unauthenticated)?;
+    let session = sessions.find(&cookie).await?;
+    let roles = grants.roles_of(&**repo, &session.username).await?.ok_or(ApiError::InvalidSession)?;
+    Ok(HttpResponse::Ok().json(SessionInfo { username: session.username, roles, expires_at: rfc3339(session.expires_at)? }))
+}
+
+/// Ends the browser's session, if it has one, and removes its cookie.
+async fn logout(req: HttpRequest, sessions: web::Data<Sessions>) -> Result<HttpResponse, ApiError> {
+    if let Some(cookie) = req.cookie(&sessions.cookie_name) {
+        sessions.repo.delete(&secrets::hash(cookie.value())).await?;
+    }
+    Ok(HttpResponse::NoContent().cookie(sessions.removal_cookie()).finish())
+}
+
+/// Ends every session of the user with the supplied username and revokes
+/// their refresh tokens, logging them out everywhere.
+pub async fn end_sessions(repo: web::Data<dyn UserRepository>, sessions: web::Data<Sessions>, username: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    sessions.end_all(&username).await?;
+    repo.rotate_token_generation(&username).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
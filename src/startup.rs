@@ -0,0 +1,265 @@
+//! Storage initialization that tolerates MongoDB being briefly unreachable.
+//!
+//! Startup retries index creation with exponential backoff. If every attempt
+//! fails, the server still starts in a degraded, not-ready state and a
+//! background task keeps retrying until the index exists.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::config::StorageConfig;
+use crate::email_tokens::MongoEmailTokenRepository;
+use crate::model::{Role, User, UserPatch};
+use crate::pagination::PageRequest;
+use crate::query::UserQuery;
+use crate::repository::{MongoUserRepository, RepositoryError, UserRepository};
+use crate::sessions::MongoSessionRepository;
+use crate::streaming::UserStream;
+
+/// Exponentially growing delay between retries.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    next: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { next: initial.min(max), max }
+    }
+
+    /// Returns the delay before the next attempt and doubles the one after it,
+    /// up to the maximum.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+}
+
+/// Whether the service is able to serve requests, shared with the handlers.
+#[derive(Debug, Default)]
+pub struct Readiness {
+    storage_ready: AtomicBool,
+    draining: AtomicBool,
+}
+
+impl Readiness {
+    pub fn is_storage_ready(&self) -> bool {
+        self.storage_ready.load(Ordering::Acquire)
+    }
+
+    pub fn mark_storage_ready(&self) {
+        self.storage_ready.store(true, Ordering::Release);
+    }
+
+    /// Whether shutdown has begun and load balancers should stop routing here.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    pub fn begin_drain(&self) {
+        self.draining.store(true, Ordering::Release);
+    }
+}
+
+/// [`UserRepository`] that fails fast with [`RepositoryError::NotReady`] until
+/// storage initialization has finished.
+///
+/// Without it, requests made in degraded mode would each wait out the driver's
+/// server selection timeout, and writes could run before the unique index exists.
+pub struct ReadinessGatedRepository {
+    inner: Arc<dyn UserRepository>,
+    readiness: Arc<Readiness>,
+}
+
+impl ReadinessGatedRepository {
+    pub fn new(inner: Arc<dyn UserRepository>, readiness: Arc<Readiness>) -> Self {
+        Self { inner, readiness }
+    }
+
+    fn check(&self) -> Result<(), RepositoryError> {
+        if self.readiness.is_storage_ready() {
+            Ok(())
+        } else {
+            Err(RepositoryError::NotReady)
+        }
+    }
+}
+
+#[async_trait]
+impl UserRepository for ReadinessGatedRepository {
+    async fn insert(&self, user: User, password_hash: Option<String>) -> Result<(), RepositoryError> {
+        self.check()?;
+        self.inner.insert(user, password_hash).await
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, RepositoryError> {
+        self.check()?;
+        self.inner.find_by_username(username).await
+    }
+
+    async fn find_page(&self, query: &UserQuery, page: &PageRequest) -> Result<Vec<User>, RepositoryError> {
+        self.check()?;
+        self.inner.find_page(query, page).await
+    }
+
+    async fn stream(&self, query: &UserQuery) -> Result<UserStream, RepositoryError> {
+        self.check()?;
+        self.inner.stream(query).await
+    }
+
+    async fn update(&self, username: &str, patch: UserPatch) -> Result<bool, RepositoryError> {
+        self.check()?;
+        self.inner.update(username, patch).await
+    }
+
+    async fn update_if_unchanged(&self, current: &User, patch: UserPatch) -> Result<bool, RepositoryError> {
+        self.check()?;
+        self.inner.update_if_unchanged(current, patch).await
+    }
+
+    async fn delete(&self, username: &str) -> Result<bool, RepositoryError> {
+        self.check()?;
+        self.inner.delete(username).await
+    }
+
+    async fn find_password_hash(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        self.check()?;
+        self.inner.find_password_hash(username).await
+    }
+
+    async fn set_password_hash(&self, username: &str, password_hash: String) -> Result<bool, RepositoryError> {
+        self.check()?;
+        self.inner.set_password_hash(username, password_hash).await
+    }
+
+    async fn find_roles(&self, username: &str) -> Result<Option<Vec<Role>>, RepositoryError> {
+        self.check()?;
+        self.inner.find_roles(username).await
+    }
+
+    async fn set_roles(&self, username: &str, roles: Vec<Role>) -> Result<bool, RepositoryError> {
+        self.check()?;
+        self.inner.set_roles(username, roles).await
+    }
+
+    async fn find_token_generation(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        self.check()?;
+        self.inner.find_token_generation(username).await
+    }
+
+    async fn rotate_token_generation(&self, username: &str) -> Result<bool, RepositoryError> {
+        self.check()?;
+        self.inner.rotate_token_generation(username).await
+    }
+
+    async fn find_verified_email(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        self.check()?;
+        self.inner.find_verified_email(username).await
+    }
+
+    async fn set_verified_email(&self, username: &str, email: &str) -> Result<bool, RepositoryError> {
+        self.check()?;
+        self.inner.set_verified_email(username, email).await
+    }
+
+    // Health checks bypass the gate so they report the store's actual state.
+    async fn ping(&self) -> Result<(), RepositoryError> {
+        self.inner.ping().await
+    }
+
+    async fn username_index_exists(&self) -> Result<bool, RepositoryError> {
+        self.inner.username_index_exists().await
+    }
+}
+
+/// Runs `attempt` until it succeeds or `max_attempts` have failed, sleeping
+/// according to `backoff` in between. `max_attempts` of `None` retries forever.
+///
+/// Returns whether an attempt succeeded.
+pub async fn retry<F, Fut>(what: &str, max_attempts: Option<u32>, backoff: &mut Backoff, mut attempt: F) -> bool
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), RepositoryError>>,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match attempt().await {
+            Ok(()) => return true,
+            Err(err) if max_attempts.is_some_and(|max| attempts >= max) => {
+                tracing::warn!(attempt = attempts, error = %err, "{what} failed, giving up");
+                return false;
+            }
+            Err(err) => {
+                let delay = backoff.next_delay();
+                tracing::warn!(attempt = attempts, error = %err, retry_in = ?delay, "{what} failed");
+                actix_rt::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// Creates the username index, retrying as configured.
+///
+/// When startup attempts are exhausted the returned [`Readiness`] reports the
+/// storage as not ready and a background task keeps retrying; it flips to
+/// ready once the index exists.
+pub async fn initialize_storage(repo: &MongoUserRepository, config: &StorageConfig) -> Arc<Readiness> {
+    let readiness = Arc::new(Readiness::default());
+    let mut backoff = Backoff::new(config.retry_initial_backoff(), config.retry_max_backoff());
+
+    let create_index = |repo: MongoUserRepository| move || {
+        let repo = repo.clone();
+        async move { repo.create_username_index().await }
+    };
+
+    if retry("creating the username index", Some(config.startup_attempts), &mut backoff, create_index(repo.clone())).await {
+        readiness.mark_storage_ready();
+        return readiness;
+    }
+
+    tracing::error!("MongoDB is unreachable; starting in degraded mode, not ready");
+    let background = (repo.clone(), readiness.clone());
+    actix_rt::spawn(async move {
+        let (repo, readiness) = background;
+        retry("creating the username index", None, &mut backoff, create_index(repo)).await;
+        readiness.mark_storage_ready();
+        tracing::info!("username index created; storage is ready");
+    });
+    readiness
+}
+
+/// Creates the session indexes in the background, retrying until it succeeds.
+///
+/// Sessions work without them, since expiry is also checked on every request;
+/// expired ones are only removed from the collection once the TTL index exists.
+pub fn create_session_indexes(repo: MongoSessionRepository, config: &StorageConfig) {
+    let mut backoff = Backoff::new(config.retry_initial_backoff(), config.retry_max_backoff());
+    actix_rt::spawn(async move {
+        let create_indexes = || {
+            let repo = repo.clone();
+            async move { repo.create_indexes().await }
+        };
+        retry("creating the session indexes", None, &mut backoff, create_indexes).await;
+    });
+}
+
+/// Creates the email token indexes in the background, retrying until it
+/// succeeds. Like sessions, tokens work without them, since expiry is also
+/// checked when they are used.
+pub fn create_email_token_indexes(repo: MongoEmailTokenRepository, config: &StorageConfig) {
+    let mut backoff = Backoff::new(config.retry_initial_backoff(), config.retry_max_backoff());
+    actix_rt::spawn(async move {
+        let create_indexes = || {
+            let repo = repo.clone();
+            async move { repo.create_indexes().await }
+        };
+        retry("creating the email token indexes", None, &mut backoff, create_indexes).await;
+    });
+}
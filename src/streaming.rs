@@ -0,0 +1,65 @@
+use actix_web::{web::Bytes, HttpResponse};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::error::ApiError;
+use crate::model::User;
+use crate::repository::RepositoryError;
+use crate::versioning::UserRepresentation;
+
+/// Users read lazily from the store, one at a time.
+pub type UserStream = BoxStream<'static, Result<User, RepositoryError>>;
+
+/// Content type of a newline-delimited JSON body.
+pub const NDJSON: &str = "application/x-ndjson";
+
+/// Body framing of a streamed listing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamFormat {
+    /// A single JSON array, e.g. `[{...},{...}]`.
+    Json,
+    /// One JSON document per line.
+    Ndjson,
+}
+
+/// Query parameter selecting a streamed listing instead of a page.
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct StreamQuery {
+    /// Streams every matching user in this format instead of returning a page.
+    pub stream: Option<StreamFormat>,
+}
+
+/// Builds a chunked response that serializes `users`, rendered as `R`, as they
+/// are read.
+///
+/// The body is pulled only as fast as the client consumes it, so memory use does
+/// not grow with the number of users. Headers are already sent when a storage
+/// error occurs mid-stream; the body then ends with an error, which aborts the
+/// connection instead of terminating the chunked body, so clients see a
+/// truncated transfer rather than a silently short listing.
+pub fn stream_response<R: UserRepresentation>(format: StreamFormat, users: UserStream) -> HttpResponse {
+    let users = users.map(|user| user.map(R::from));
+    match format {
+        StreamFormat::Ndjson => {
+            let body = users.map(|user| {
+                let mut line = serde_json::to_vec(&user?).expect("users always serialize");
+                line.push(b'\n');
+                Ok::<_, ApiError>(Bytes::from(line))
+            });
+            HttpResponse::Ok().content_type(NDJSON).streaming(body)
+        }
+        StreamFormat::Json => {
+            let items = users.enumerate().map(|(index, user)| {
+                let mut chunk = if index == 0 { Vec::new() } else { vec![b','] };
+                serde_json::to_writer(&mut chunk, &user?).expect("users always serialize");
+                Ok::<_, ApiError>(Bytes::from(chunk))
+            });
+            let body = stream::once(async { Ok(Bytes::from_static(b"[")) })
+                .chain(items)
+                .chain(stream::once(async { Ok(Bytes::from_static(b"]")) }));
+            HttpResponse::Ok().content_type(R::CONTENT_TYPE).streaming(body)
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! OpenTelemetry tracing.
+//!
+//! `tracing` spans become OpenTelemetry spans through the layer installed by
+//! [`logging::init`](crate::logging::init): one server span per request,
+//! continuing the caller's trace when it sends a W3C `traceparent` header, with
+//! a client span per MongoDB operation recorded by [`TracedRepository`].
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use actix_web::http::header::HeaderMap;
+use async_trait::async_trait;
+use mongodb::bson::DateTime;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::{SpanKind, Status};
+use opentelemetry::{Context, Value};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracerProvider, SpanData, SpanExporter};
+use opentelemetry_sdk::Resource;
+use serde_json::{json, Map};
+use tracing::Instrument;
+
+use crate::api_keys::{ApiKeyRecord, ApiKeyRepository};
+use crate::config::{TraceExporter, TracingConfig};
+use crate::email_tokens::{EmailTokenRecord, EmailTokenRepository, Purpose};
+use crate::model::{Role, User, UserPatch};
+use crate::pagination::PageRequest;
+use crate::query::UserQuery;
+use crate::repository::{RepositoryError, UserRepository};
+use crate::sessions::{SessionRecord, SessionRepository};
+use crate::streaming::UserStream;
+
+/// Builds the tracer provider for the configured exporter, or `None` when
+/// tracing is disabled.
+pub fn tracer_provider(config: &TracingConfig) -> Result<Option<SdkTracerProvider>, Box<dyn std::error::Error>> {
+    let resource = Resource::builder().with_service_name(config.service_name.clone()).build();
+    let builder = SdkTracerProvider::builder().with_resource(resource);
+    let builder = match config.exporter {
+        TraceExporter::None => return Ok(None),
+        TraceExporter::Otlp => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_endpoint(config.otlp_endpoint.clone())
+                .build()?;
+            builder.with_batch_exporter(exporter)
+        }
+        TraceExporter::Stdout => builder.with_batch_exporter(JsonLinesExporter::new(std::io::stdout())),
+        TraceExporter::File => {
+            let file = OpenOptions::new().create(true).append(true).open(&config.file_path)?;
+            builder.with_batch_exporter(JsonLinesExporter::new(file))
+        }
+    };
+    Ok(Some(builder.build()))
+}
+
+/// Reads the W3C trace context of an incoming request.
+///
+/// Without a valid `traceparent` header the returned context has no span, and
+/// the request starts a new trace.
+pub fn extract_trace_context(headers: &HeaderMap) -> Context {
+    TraceContextPropagator::new().extract(&HeaderExtractor(headers))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Span exporter writing one JSON object per span, for local debugging.
+pub struct JsonLinesExporter<W> {
+    writer: Arc<Mutex<W>>,
+}
+
+impl<W> JsonLinesExporter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer: Arc::new(Mutex::new(writer)) }
+    }
+}
+
+impl<W> fmt::Debug for JsonLinesExporter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("JsonLinesExporter")
+    }
+}
+
+impl<W: Write + Send + 'static> SpanExporter for JsonLinesExporter<W> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let mut writer = self.writer.lock().map_err(|_| OTelSdkError::InternalFailure("writer poisoned".into()))?;
+        for span in batch {
+            let mut line = serde_json::to_vec(&span_json(&span)).expect("spans always serialize");
+            line.push(b'\n');
+            writer.write_all(&line).map_err(|err| OTelSdkError::InternalFailure(err.to_string()))?;
+        }
+        writer.flush().map_err(|err| OTelSdkError::InternalFailure(err.to_string()))
+    }
+}
+
+fn span_json(span: &SpanData) -> serde_json::Value {
+    let nanos = |time: std::time::SystemTime| time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    let attributes: Map<String, serde_json::Value> = span
+        .attributes
+        .iter()
+        .map(|attribute| {
+            let value = match &attribute.value {
+                Value::Bool(value) => json!(value),
+                Value::I64(value) => json!(value),
+                Value::F64(value) => json!(value),
+                value => json!(value.to_string()),
+            };
+            (attribute.key.to_string(), value)
+        })
+        .collect();
+    let kind = match span.span_kind {
+        SpanKind::Client => "client",
+        SpanKind::Server => "server",
+        SpanKind::Producer => "producer",
+        SpanKind::Consumer => "consumer",
+        SpanKind::Internal => "internal",
+    };
+    let status = match &span.status {
+        Status::Unset => json!("unset"),
+        Status::Ok => json!("ok"),
+        Status::Error { description } => json!({ "error": description }),
+    };
+    json!({
+        "trace_id": span.span_context.trace_id().to_string(),
+        "span_id": span.span_context.span_id().to_string(),
+        "parent_span_id": span.parent_span_id.to_string(),
+        "name": span.name,
+        "kind": kind,
+        "start_time_unix_nano": nanos(span.start_time),
+        "end_time_unix_nano": nanos(span.end_time),
+        "attributes": attributes,
+        "status": status,
+    })
+}
+
+/// Repository that wraps every MongoDB operation in a client span,
+/// a child of the span of the request that caused it.
+pub struct TracedRepository<R: ?Sized> {
+    inner: Arc<R>,
+    collection: String,
+}
+
+impl<R: ?Sized> TracedRepository<R> {
+    pub fn new(inner: Arc<R>, collection: &str) -> Self {
+        Self { inner, collection: collection.into() }
+    }
+
+    async fn traced<T>(&self, operation: &str, call: impl std::future::Future<Output = Result<T, RepositoryError>>) -> Result<T, RepositoryError> {
+        let span = tracing::info_span!(
+            "mongodb",
+            otel.name = format!("{operation} {}", self.collection),
+            otel.kind = "client",
+            otel.status_code = tracing::field::Empty,
+            db.system.name = "mongodb",
+            db.operation.name = operation,
+            db.collection.name = self.collection.as_str(),
+        );
+        let result = call.instrument(span.clone()).await;
+        if result.is_err() {
+            span.record("otel.status_code", "error");
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl<R: UserRepository + ?Sized> UserRepository for TracedRepository<R> {
+    async fn insert(&self, user: User, password_hash: Option<String>) -> Result<(), RepositoryError> {
+        self.traced("insert_one", self.inner.insert(user, password_hash)).await
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, RepositoryError> {
+        self.traced("find_one", self.inner.find_by_username(username)).await
+    }
+
+    async fn find_page(&self, query: &UserQuery, page: &PageRequest) -> Result<Vec<User>, RepositoryError> {
+        self.traced("find", self.inner.find_page(query, page)).await
+    }
+
+    /// Only opening the cursor is traced; the stream is consumed at the client's pace.
+    async fn stream(&self, query: &UserQuery) -> Result<UserStream, RepositoryError> {
+        self.traced("find", self.inner.stream(query)).await
+    }
+
+    async fn update(&self, username: &str, patch: UserPatch) -> Result<bool, RepositoryError> {
+        self.traced("update_one", self.inner.update(username, patch)).await
+    }
+
+    async fn update_if_unchanged(&self, current: &User, patch: UserPatch) -> Result<bool, RepositoryError> {
+        self.traced("update_one", self.inner.update_if_unchanged(current, patch)).await
+    }
+
+    async fn delete(&self, username: &str) -> Result<bool, RepositoryError> {
+        self.traced("delete_one", self.inner.delete(username)).await
+    }
+
+    async fn find_password_hash(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        self.traced("find_one", self.inner.find_password_hash(username)).await
+    }
+
+    async fn set_password_hash(&self, username: &str, password_hash: String) -> Result<bool, RepositoryError> {
+        self.traced("update_one", self.inner.set_password_hash(username, password_hash)).await
+    }
+
+    async fn find_roles(&self, username: &str) -> Result<Option<Vec<Role>>, RepositoryError> {
+        self.traced("find_one", self.inner.find_roles(username)).await
+    }
+
+    async fn set_roles(&self, username: &str, roles: Vec<Role>) -> Result<bool, RepositoryError> {
+        self.traced("update_one", self.inner.set_roles(username, roles)).await
+    }
+
+    async fn find_token_generation(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        self.traced("find_one", self.inner.find_token_generation(username)).await
+    }
+
+    async fn rotate_token_generation(&self, username: &str) -> Result<bool, RepositoryError> {
+        self.traced("update_one", self.inner.rotate_token_generation(username)).await
+    }
+
+    async fn find_verified_email(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        self.traced("find_one", self.inner.find_verified_email(username)).await
+    }
+
+    async fn set_verified_email(&self, username: &str, email: &str) -> Result<bool, RepositoryError> {
+        self.traced("update_one", self.inner.set_verified_email(username, email)).await
+    }
+
+    async fn ping(&self) -> Result<(), RepositoryError> {
+        self.inner.ping().await
+    }
+
+    async fn username_index_exists(&self) -> Result<bool, RepositoryError> {
+        self.inner.username_index_exists().await
+    }
+}
+
+#[async_trait]
+impl<R: ApiKeyRepository + ?Sized> ApiKeyRepository for TracedRepository<R> {
+    async fn insert(&self, key: ApiKeyRecord) -> Result<(), RepositoryError> {
+        self.traced("insert_one", self.inner.insert(key)).await
+    }
+
+    async fn find(&self, id: &str) -> Result<Option<ApiKeyRecord>, RepositoryError> {
+        self.traced("find_one", self.inner.find(id)).await
+    }
+
+    async fn list(&self) -> Result<Vec<ApiKeyRecord>, RepositoryError> {
+        self.traced("find", self.inner.list()).await
+    }
+
+    async fn revoke(&self, id: &str, at: DateTime) -> Result<bool, RepositoryError> {
+        self.traced("update_one", self.inner.revoke(id, at)).await
+    }
+
+    async fn record_use(&self, id: &str, at: DateTime) -> Result<(), RepositoryError> {
+        self.traced("update_one", self.inner.record_use(id, at)).await
+    }
+}
+
+#[async_trait]
+impl<R: SessionRepository + ?Sized> SessionRepository for TracedRepository<R> {
+    async fn insert(&self, session: SessionRecord) -> Result<(), RepositoryError> {
+        self.traced("insert_one", self.inner.insert(session)).await
+    }
+
+    async fn find(&self, id_hash: &str) -> Result<Option<SessionRecord>, RepositoryError> {
+        self.traced("find_one", self.inner.find(id_hash)).await
+    }
+
+    async fn delete(&self, id_hash: &str) -> Result<(), RepositoryError> {
+        self.traced("delete_one", self.inner.delete(id_hash)).await
+    }
+
+    async fn delete_for_user(&self, username: &str) -> Result<(), RepositoryError> {
+        self.traced("delete_many", self.inner.delete_for_user(username)).await
+    }
+}
+
+#[async_trait]
+impl<R: EmailTokenRepository + ?Sized> EmailTokenRepository for TracedRepository<R> {
+    async fn insert(&self, token: EmailTokenRecord) -> Result<(), RepositoryError> {
+        self.traced("insert_one", self.inner.insert(token)).await
+    }
+
+    async fn take(&self, id_hash: &str, purpose: Purpose) -> Result<Option<EmailTokenRecord>, RepositoryError> {
+        self.traced("find_one_and_delete", self.inner.take(id_hash, purpose)).await
+    }
+
+    async fn delete_for_user(&self, username: &str, purpose: Purpose) -> Result<(), RepositoryError> {
+        self.traced("delete_many", self.inner.delete_for_user(username, purpose)).await
+    }
+}
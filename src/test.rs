@@ -1,46 +1,2254 @@
 use actix_web::{
-    test::{call_and_read_body, call_and_read_body_json, init_service, TestRequest},
+    dev::{Service, ServiceResponse},
+    http::{header::{HeaderName, ACCEPT, AUTHORIZATION, CONTENT_TYPE, LOCATION, VARY, WWW_AUTHENTICATE}, StatusCode},
+    test::{call_and_read_body, call_and_read_body_json, call_service, init_service, read_body, TestRequest},
     web::Bytes,
+    ResponseError,
 };
+use actix_http::Request;
+use actix_web::cookie::Cookie;
+use futures_util::StreamExt;
+use auth::TokenPair;
+use email_tokens::{EmailTokenRecord, Purpose};
+use error::{ProblemDetails, PROBLEM_JSON};
+use health::{Report, Status};
+use mailer::{Email, InMemoryMailer, Mailer};
+use model::{PersonName, Role, UserV2};
+use pagination::{Page, PageLimits};
+use repository::RepositoryError;
 
 use super::*;
 
-#[actix_web::test]
-#[ignore = "requires MongoDB instance running"]
-async fn test() {
-    let uri = std::env::var("MONGODB_URI").unwrap_or_else(|_| "mongodb://localhost:27017".into());
+fn jane() -> User {
+    User {
+        first_name: "Jane".into(),
+        last_name: "Doe".into(),
+        username: "janedoe".into(),
+        email: "example@example.com".into(),
+    }
+}
 
-    let client = Client::with_uri_str(uri).await.expect("failed to connect");
+fn john() -> User {
+    User {
+        first_name: "John".into(),
+        last_name: "Smith".into(),
+        username: "jsmith".into(),
+        email: "john@example.com".into(),
+    }
+}
 
-    // Clear any data currently in the users collection.
-    client
-        .database(DB_NAME)
-        .collection::<User>(COLL_NAME)
-        .drop()
+/// Builds the full application against an empty in-memory store.
+async fn test_app() -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+    TestApp::default().build().await
+}
+
+/// Options of the application under test. Unless set, it runs over an empty,
+/// ready in-memory store with the default page limits, and mails through an
+/// [`InMemoryMailer`] nobody reads.
+#[derive(Default)]
+struct TestApp {
+    repo: Option<Arc<dyn UserRepository>>,
+    limits: PageLimits,
+    readiness: Option<Arc<Readiness>>,
+    email_tokens: Option<EmailTokens>,
+}
+
+impl TestApp {
+    fn repo(self, repo: Arc<dyn UserRepository>) -> Self {
+        Self { repo: Some(repo), ..self }
+    }
+
+    fn limits(self, limits: PageLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    fn readiness(self, readiness: Arc<Readiness>) -> Self {
+        Self { readiness: Some(readiness), ..self }
+    }
+
+    fn mailer(self, mailer: Arc<InMemoryMailer>) -> Self {
+        self.email_tokens(test_email_tokens(mailer))
+    }
+
+    fn email_tokens(self, email_tokens: EmailTokens) -> Self {
+        Self { email_tokens: Some(email_tokens), ..self }
+    }
+
+    async fn build(self) -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+        let repo = self.repo.unwrap_or_else(|| Arc::new(InMemoryUserRepository::new()));
+        let readiness = self.readiness.unwrap_or_else(|| {
+            let readiness = Arc::new(Readiness::default());
+            readiness.mark_storage_ready();
+            readiness
+        });
+        let email_tokens = self.email_tokens.unwrap_or_else(|| test_email_tokens(Arc::new(InMemoryMailer::new())));
+        let probe = Probe { readiness, check_timeout: Duration::from_secs(1) };
+        let metrics = Arc::new(Metrics::new());
+        init_service(
+            App::new()
+                .app_data(web::Data::from(repo))
+                .app_data(web::Data::new(self.limits))
+                // Minimal costs keep the tests fast.
+                .app_data(web::Data::new(Passwords::new(argon2::Params::new(8, 1, 1, None).unwrap())))
+                .app_data(web::Data::new(test_tokens()))
+                .app_data(web::Data::new(RoleGrants::default()))
+                .app_data(web::Data::new(ApiKeys::new(Arc::new(InMemoryApiKeyRepository::new()), TEST_API_KEY_MAX_TTL)))
+                .app_data(web::Data::new(Sessions::new(Arc::new(InMemorySessionRepository::new()), &config::SessionConfig::default())))
+                .app_data(web::Data::new(email_tokens))
+                .app_data(web::Data::new(probe))
+                .app_data(web::Data::from(metrics.clone()))
+                .wrap(RecordMetrics::new(metrics))
+                .wrap(RequestLogging)
+                .configure(configure),
+        )
         .await
-        .expect("drop collection should succeed");
+    }
+}
 
-    let app = init_service(
-        App::new()
-            .app_data(web::Data::new(client))
-            .service(add_user)
-            .service(get_user),
-    )
-    .await;
+fn test_email_tokens(mailer: Arc<InMemoryMailer>) -> EmailTokens {
+    EmailTokens::new(Arc::new(InMemoryEmailTokenRepository::new()), mailer, &config::MailConfig::default())
+}
+
+const TEST_API_KEY_MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const TEST_JWT_SECRET: &str = "test secret of at least thirty-two bytes";
+
+fn test_tokens() -> Tokens {
+    Tokens::hs256(&config::AuthConfig::default(), TEST_JWT_SECRET.as_bytes())
+}
+
+/// Authorization header of an admin's access token.
+fn authorized() -> (HeaderName, String) {
+    bearer(&test_tokens().issue("tester", &[Role::Admin], "").access_token)
+}
+
+fn bearer(token: &str) -> (HeaderName, String) {
+    (AUTHORIZATION, format!("Bearer {token}"))
+}
+
+fn user_named(username: &str) -> User {
+    User { username: username.into(), ..jane() }
+}
+
+fn add_request(user: &User) -> Request {
+    TestRequest::post().insert_header(authorized()).uri("/add_user").set_json(user).to_request()
+}
+
+#[actix_web::test]
+async fn add_and_get_user() {
+    let app = test_app().await;
+    let user = jane();
+
+    let response = call_and_read_body(&app, add_request(&user)).await;
+    assert_eq!(response, Bytes::from_static(b"user added"));
+
+    let req = TestRequest::get().uri(&format!("/get_user/{}", user.username)).to_request();
+    let response: User = call_and_read_body_json(&app, req).await;
+    assert_eq!(response, user);
+}
+
+#[actix_web::test]
+async fn get_missing_user_is_not_found() {
+    let app = test_app().await;
+
+    let req = TestRequest::get().uri("/get_user/nobody").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), PROBLEM_JSON);
+
+    let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(problem.code, "user_not_found");
+    assert_eq!(problem.status, 404);
+    assert_eq!(problem.detail, "No user found with username nobody");
+}
+
+#[actix_web::test]
+async fn get_users_lists_every_user() {
+    let app = test_app().await;
+
+    let req = TestRequest::get().insert_header(authorized()).uri("/get_users").to_request();
+    let response: Page<User> = call_and_read_body_json(&app, req).await;
+    assert!(response.items.is_empty());
+    assert_eq!(response.next_cursor, None);
+
+    call_service(&app, add_request(&jane())).await;
+    call_service(&app, add_request(&john())).await;
+
+    let req = TestRequest::get().insert_header(authorized()).uri("/get_users").to_request();
+    let response: Page<User> = call_and_read_body_json(&app, req).await;
+    assert_eq!(response.items, vec![jane(), john()]);
+    assert_eq!(response.next_cursor, None);
+}
+
+#[actix_web::test]
+async fn get_users_pages_by_offset() {
+    let app = test_app().await;
+    for username in ["alice", "bob", "carol", "dave", "erin"] {
+        call_service(&app, add_request(&user_named(username))).await;
+    }
+
+    let req = TestRequest::get().insert_header(authorized()).uri("/get_users?limit=2&offset=2").to_request();
+    let response: Page<User> = call_and_read_body_json(&app, req).await;
+    assert_eq!(response.items, vec![user_named("carol"), user_named("dave")]);
+    assert!(response.next_cursor.is_some());
+
+    let req = TestRequest::get().insert_header(authorized()).uri("/get_users?limit=2&offset=4").to_request();
+    let response: Page<User> = call_and_read_body_json(&app, req).await;
+    assert_eq!(response.items, vec![user_named("erin")]);
+    assert_eq!(response.next_cursor, None);
+}
+
+#[actix_web::test]
+async fn get_users_pages_by_cursor() {
+    let app = test_app().await;
+    for username in ["alice", "bob", "carol", "dave", "erin"] {
+        call_service(&app, add_request(&user_named(username))).await;
+    }
+
+    let mut seen = vec![];
+    let mut uri = "/get_users?limit=2".to_string();
+    loop {
+        let req = TestRequest::get().insert_header(authorized()).uri(&uri).to_request();
+        let response: Page<User> = call_and_read_body_json(&app, req).await;
+        seen.extend(response.items.into_iter().map(|user| user.username));
+        match response.next_cursor {
+            Some(cursor) => uri = format!("/get_users?limit=2&cursor={cursor}"),
+            None => break,
+        }
+    }
+    assert_eq!(seen, ["alice", "bob", "carol", "dave", "erin"]);
+}
+
+fn person(username: &str, first_name: &str, last_name: &str, email: &str) -> User {
+    User {
+        first_name: first_name.into(),
+        last_name: last_name.into(),
+        username: username.into(),
+        email: email.into(),
+    }
+}
+
+async fn list_usernames(app: &impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>, uri: &str) -> Vec<String> {
+    let req = TestRequest::get().insert_header(authorized()).uri(uri).to_request();
+    let response: Page<User> = call_and_read_body_json(app, req).await;
+    response.items.into_iter().map(|user| user.username).collect()
+}
+
+#[actix_web::test]
+async fn get_users_filters_by_field() {
+    let app = test_app().await;
+    for user in [
+        person("adoe", "Ann", "Doe", "ann@Example.com"),
+        person("bdoe", "Bob", "Doe", "bob@other.org"),
+        person("asmith", "Ann", "Smith", "ann@example.com.evil"),
+        person("carl", "Carl", "Doerr", "carl@example.com"),
+    ] {
+        call_service(&app, add_request(&user)).await;
+    }
+
+    assert_eq!(list_usernames(&app, "/get_users?last_name=Doe").await, ["adoe", "bdoe"]);
+    assert_eq!(list_usernames(&app, "/get_users?last_name_prefix=Doe").await, ["adoe", "bdoe", "carl"]);
+    assert_eq!(list_usernames(&app, "/get_users?first_name=Ann&username_prefix=a").await, ["adoe", "asmith"]);
+    assert_eq!(list_usernames(&app, "/get_users?email_domain=example.com").await, ["adoe", "carl"]);
+    assert_eq!(list_usernames(&app, "/get_users?email_domain=EXAMPLE.Com").await, ["adoe", "carl"]);
+    assert!(list_usernames(&app, "/get_users?username_prefix=.*").await.is_empty());
+}
+
+#[actix_web::test]
+async fn get_users_sorts_and_pages_by_cursor() {
+    let app = test_app().await;
+    for user in [
+        person("adoe", "Ann", "Doe", "ann@example.com"),
+        person("bdoe", "Bob", "Doe", "bob@example.com"),
+        person("asmith", "Ann", "Smith", "ann@example.com"),
+        person("carl", "Carl", "Abbot", "carl@example.com"),
+        person("zdoe", "Zed", "Doe", "zed@example.com"),
+    ] {
+        call_service(&app, add_request(&user)).await;
+    }
+
+    let expected = ["carl", "zdoe", "bdoe", "adoe", "asmith"];
+    assert_eq!(list_usernames(&app, "/get_users?sort=last_name,-username").await, expected);
+
+    let mut seen = vec![];
+    let mut uri = "/get_users?sort=last_name,-username&limit=2".to_string();
+    loop {
+        let req = TestRequest::get().insert_header(authorized()).uri(&uri).to_request();
+        let response: Page<User> = call_and_read_body_json(&app, req).await;
+        seen.extend(response.items.into_iter().map(|user| user.username));
+        match response.next_cursor {
+            Some(cursor) => uri = format!("/get_users?sort=last_name,-username&limit=2&cursor={cursor}"),
+            None => break,
+        }
+    }
+    assert_eq!(seen, expected);
+
+    let req = TestRequest::get().insert_header(authorized()).uri("/get_users?sort=last_name&limit=1").to_request();
+    let response: Page<User> = call_and_read_body_json(&app, req).await;
+    let cursor = response.next_cursor.unwrap();
+    for sort in ["-first_name,last_name", "email", "-last_name"] {
+        let req = TestRequest::get().insert_header(authorized()).uri(&format!("/get_users?sort={sort}&cursor={cursor}")).to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{sort}");
+    }
+}
+
+#[actix_web::test]
+async fn get_users_rejects_unknown_sort_fields() {
+    let app = test_app().await;
+
+    for uri in ["/get_users?sort=password", "/get_users?sort=$where", "/get_users?sort=username,-username"] {
+        let req = TestRequest::get().insert_header(authorized()).uri(uri).to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+}
+
+#[test]
+fn filters_cannot_inject_operators() {
+    use mongodb::bson::doc;
+    use query::{FieldFilter, UserField};
+
+    let clause = repository::filter_clause(&FieldFilter::Exact(UserField::Username, "$ne".into()));
+    assert_eq!(clause, doc! { "username": { "$eq": "$ne" } });
+
+    let clause = repository::filter_clause(&FieldFilter::Prefix(UserField::LastName, "a.*(".into()));
+    assert_eq!(clause, doc! { "last_name": { "$regex": r"^a\.\*\(" } });
+
+    let clause = repository::filter_clause(&FieldFilter::EmailDomain("ex-1.com".into()));
+    assert_eq!(clause, doc! { "email": { "$regex": r"@[eE][xX]\-1\.[cC][oO][mM]$" } });
+}
+
+#[actix_web::test]
+async fn get_users_streams_json_array() {
+    let app = test_app().await;
+
+    let req = TestRequest::get().insert_header(authorized()).uri("/get_users?stream=json").to_request();
+    let response: Vec<User> = call_and_read_body_json(&app, req).await;
+    assert!(response.is_empty());
+
+    for username in ["carol", "alice", "bob"] {
+        call_service(&app, add_request(&user_named(username))).await;
+    }
+
+    let req = TestRequest::get().insert_header(authorized()).uri("/get_users?stream=json&sort=-username").to_request();
+    let response: Vec<User> = call_and_read_body_json(&app, req).await;
+    assert_eq!(response, vec![user_named("carol"), user_named("bob"), user_named("alice")]);
+}
+
+#[actix_web::test]
+async fn get_users_streams_ndjson() {
+    let app = test_app().await;
+    for username in ["alice", "bob"] {
+        call_service(&app, add_request(&user_named(username))).await;
+    }
+
+    let req = TestRequest::get().insert_header(authorized()).uri("/get_users?stream=ndjson").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), streaming::NDJSON);
+
+    let body = read_body(response).await;
+    let users: Vec<User> = body
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice(line).unwrap())
+        .collect();
+    assert_eq!(users, vec![user_named("alice"), user_named("bob")]);
+}
+
+#[actix_web::test]
+async fn get_users_stream_rejects_paging_parameters() {
+    let app = test_app().await;
+
+    let req = TestRequest::get().insert_header(authorized()).uri("/get_users?stream=ndjson&limit=10").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+/// In-memory store whose listing stream fails after yielding one user.
+#[derive(Default)]
+struct FailingStreamRepository(InMemoryUserRepository);
+
+#[async_trait::async_trait]
+impl UserRepository for FailingStreamRepository {
+    async fn insert(&self, user: User, password_hash: Option<String>) -> Result<(), RepositoryError> {
+        self.0.insert(user, password_hash).await
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, RepositoryError> {
+        self.0.find_by_username(username).await
+    }
+
+    async fn find_page(&self, query: &query::UserQuery, page: &pagination::PageRequest) -> Result<Vec<User>, RepositoryError> {
+        self.0.find_page(query, page).await
+    }
+
+    async fn stream(&self, _query: &query::UserQuery) -> Result<streaming::UserStream, RepositoryError> {
+        let failure = RepositoryError::Mongo(mongodb::error::Error::custom("cursor killed"));
+        Ok(futures_util::stream::iter([Ok(jane()), Err(failure)]).boxed())
+    }
+
+    async fn update(&self, username: &str, patch: UserPatch) -> Result<bool, RepositoryError> {
+        self.0.update(username, patch).await
+    }
+
+    async fn update_if_unchanged(&self, current: &User, patch: UserPatch) -> Result<bool, RepositoryError> {
+        self.0.update_if_unchanged(current, patch).await
+    }
+
+    async fn delete(&self, username: &str) -> Result<bool, RepositoryError> {
+        self.0.delete(username).await
+    }
+
+    async fn find_password_hash(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        self.0.find_password_hash(username).await
+    }
+
+    async fn set_password_hash(&self, username: &str, password_hash: String) -> Result<bool, RepositoryError> {
+        self.0.set_password_hash(username, password_hash).await
+    }
+
+    async fn find_token_generation(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        self.0.find_token_generation(username).await
+    }
+
+    async fn rotate_token_generation(&self, username: &str) -> Result<bool, RepositoryError> {
+        self.0.rotate_token_generation(username).await
+    }
+
+    async fn find_roles(&self, username: &str) -> Result<Option<Vec<model::Role>>, RepositoryError> {
+        self.0.find_roles(username).await
+    }
+
+    async fn set_roles(&self, username: &str, roles: Vec<model::Role>) -> Result<bool, RepositoryError> {
+        self.0.set_roles(username, roles).await
+    }
+
+    async fn find_verified_email(&self, username: &str) -> Result<Option<String>, RepositoryError> {
+        self.0.find_verified_email(username).await
+    }
+
+    async fn set_verified_email(&self, username: &str, email: &str) -> Result<bool, RepositoryError> {
+        self.0.set_verified_email(username, email).await
+    }
+
+    async fn ping(&self) -> Result<(), RepositoryError> {
+        self.0.ping().await
+    }
+
+    async fn username_index_exists(&self) -> Result<bool, RepositoryError> {
+        self.0.username_index_exists().await
+    }
+}
+
+#[actix_web::test]
+async fn get_users_stream_error_aborts_body() {
+    let app = TestApp::default().repo(Arc::new(FailingStreamRepository::default())).readiness(Arc::new(Readiness::default())).build().await;
+
+    for format in ["json", "ndjson"] {
+        let req = TestRequest::get().insert_header(authorized()).uri(&format!("/get_users?stream={format}")).to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(actix_web::body::to_bytes(response.into_body()).await.is_err(), "{format}");
+    }
+}
+
+#[actix_web::test]
+async fn get_users_clamps_limit_to_max_page_size() {
+    let app = TestApp::default().limits(PageLimits { default_size: 1, max_size: 2 }).build().await;
+    for username in ["alice", "bob", "carol"] {
+        call_service(&app, add_request(&user_named(username))).await;
+    }
+
+    let req = TestRequest::get().insert_header(authorized()).uri("/get_users").to_request();
+    let response: Page<User> = call_and_read_body_json(&app, req).await;
+    assert_eq!(response.items.len(), 1);
+
+    let req = TestRequest::get().insert_header(authorized()).uri("/get_users?limit=100").to_request();
+    let response: Page<User> = call_and_read_body_json(&app, req).await;
+    assert_eq!(response.items.len(), 2);
+}
+
+#[actix_web::test]
+async fn get_users_rejects_bad_page_parameters() {
+    let app = test_app().await;
+
+    for uri in [
+        "/get_users?limit=0",
+        "/get_users?limit=ten",
+        "/get_users?offset=1&cursor=YWxpY2U",
+        "/get_users?cursor=***",
+    ] {
+        let req = TestRequest::get().insert_header(authorized()).uri(uri).to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+        let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(problem.code, "validation_failed", "{uri}");
+    }
+}
+
+#[actix_web::test]
+async fn duplicate_username_is_rejected() {
+    let app = test_app().await;
+
+    let response = call_service(&app, add_request(&jane())).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut duplicate = john();
+    duplicate.username = jane().username;
+    let response = call_service(&app, add_request(&duplicate)).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(problem.code, "duplicate_username");
+    assert_eq!(problem.field.as_deref(), Some("username"));
+
+    let req = TestRequest::get().uri(&format!("/get_user/{}", jane().username)).to_request();
+    let response: User = call_and_read_body_json(&app, req).await;
+    assert_eq!(response, jane());
+}
+
+#[test]
+fn mongo_duplicate_key_error_is_conflict() {
+    use mongodb::error::{Error, ErrorKind, WriteError, WriteFailure};
+
+    let write_error: WriteError = mongodb::bson::from_document(mongodb::bson::doc! {
+        "code": 11000,
+        "errmsg": r#"E11000 duplicate key error collection: myApp.users index: email_1 dup key: { email: "jane@example.com" }"#,
+    })
+    .unwrap();
+    let err = Error::from(ErrorKind::Write(WriteFailure::WriteError(write_error)));
+
+    let err = ApiError::from(RepositoryError::from(err));
+    assert_eq!(err.status_code(), StatusCode::CONFLICT);
+    let problem = err.problem();
+    assert_eq!(problem.code, "duplicate_email");
+    assert_eq!(problem.field.as_deref(), Some("email"));
+}
 
+#[test]
+fn duplicate_key_field_falls_back_to_index_name() {
+    assert_eq!(
+        repository::duplicate_key_field_from_message(
+            "E11000 duplicate key error collection: myApp.users index: username_1 dup key: { username: \"jane\" }"
+        )
+        .as_deref(),
+        Some("username")
+    );
+    assert_eq!(
+        repository::duplicate_key_field_from_message(
+            "E11000 duplicate key error collection: myApp.users index: email_1"
+        )
+        .as_deref(),
+        Some("email")
+    );
+    // Compound index names cannot be split into fields reliably.
+    assert_eq!(
+        repository::duplicate_key_field_from_message(
+            "E11000 duplicate key error collection: myApp.users index: email_1_tenant_1"
+        )
+        .as_deref(),
+        Some("email_1_tenant_1")
+    );
+}
+
+#[test]
+fn duplicate_key_field_prefers_the_key_pattern() {
+    use mongodb::error::{Error, ErrorKind, WriteError, WriteFailure};
+
+    let write_error: WriteError = mongodb::bson::from_document(mongodb::bson::doc! {
+        "code": 11000,
+        "errmsg": "E11000 duplicate key error collection: myApp.users index: by_contact",
+        "errInfo": { "keyPattern": { "email": 1, "tenant": 1 }, "keyValue": { "email": "jane@example.com", "tenant": "acme" } },
+    })
+    .unwrap();
+    let err = RepositoryError::from(Error::from(ErrorKind::Write(WriteFailure::WriteError(write_error))));
+    assert!(matches!(err, RepositoryError::DuplicateKey(field) if field == "email"));
+}
+
+#[actix_web::test]
+async fn patches_only_apply_to_the_user_they_were_based_on() {
+    let repo = InMemoryUserRepository::new();
+    repo.insert(jane(), None).await.unwrap();
+    let stale = jane();
+    let email_patch = |email: &str| UserPatch { email: Some(email.into()), ..Default::default() };
+    assert!(repo.update("janedoe", email_patch("jane@example.com")).await.unwrap());
+
+    assert!(!repo.update_if_unchanged(&stale, email_patch("stale@example.com")).await.unwrap());
+    let current = repo.find_by_username("janedoe").await.unwrap().unwrap();
+    assert_eq!(current.email, "jane@example.com");
+    assert!(repo.update_if_unchanged(&current, email_patch("fresh@example.com")).await.unwrap());
+
+    let err = ApiError::ConcurrentUpdate("janedoe".into());
+    assert_eq!((err.status_code(), err.code().as_ref()), (StatusCode::CONFLICT, "concurrent_update"));
+}
+
+#[actix_web::test]
+async fn update_user_changes_supplied_fields() {
+    let app = test_app().await;
+    call_service(&app, add_request(&jane())).await;
+
+    let req = TestRequest::post().insert_header(authorized())
+        .uri("/update_user/janedoe")
+        .set_json(serde_json::json!({ "last_name": "Roe", "email": "jane@example.com" }))
+        .to_request();
+    let response = call_and_read_body(&app, req).await;
+    assert_eq!(response, Bytes::from_static(b"User updated"));
+
+    let req = TestRequest::get().uri("/get_user/janedoe").to_request();
+    let response: User = call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        response,
+        User { last_name: "Roe".into(), email: "jane@example.com".into(), ..jane() }
+    );
+}
+
+#[actix_web::test]
+async fn update_user_rejects_malformed_patches() {
+    let app = test_app().await;
+    call_service(&app, add_request(&jane())).await;
+
+    for patch in [
+        serde_json::json!({ "frist_name": "Janet" }),
+        serde_json::json!({ "username": "janet" }),
+        serde_json::json!({ "first_name": 42 }),
+        serde_json::json!({}),
+        serde_json::json!({ "email": null }),
+    ] {
+        let req = TestRequest::post().insert_header(authorized())
+            .uri("/update_user/janedoe")
+            .set_json(&patch)
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{patch}");
+        let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(problem.code, "validation_failed", "{patch}");
+    }
+
+    let req = TestRequest::get().uri("/get_user/janedoe").to_request();
+    let response: User = call_and_read_body_json(&app, req).await;
+    assert_eq!(response, jane());
+}
+
+fn patch_request(username: &str, content_type: &str, body: serde_json::Value) -> Request {
+    TestRequest::patch().insert_header(authorized())
+        .uri(&format!("/users/{username}"))
+        .insert_header((CONTENT_TYPE, content_type))
+        .set_payload(body.to_string())
+        .to_request()
+}
+
+#[actix_web::test]
+async fn patch_user_applies_merge_patch() {
+    let app = test_app().await;
+    call_service(&app, add_request(&jane())).await;
+
+    let req = patch_request("janedoe", patch::MERGE_PATCH_JSON, serde_json::json!({ "last_name": "Roe" }));
+    let response: User = call_and_read_body_json(&app, req).await;
+    let expected = User { last_name: "Roe".into(), ..jane() };
+    assert_eq!(response, expected);
+
+    let req = TestRequest::get().uri("/get_user/janedoe").to_request();
+    let response: User = call_and_read_body_json(&app, req).await;
+    assert_eq!(response, expected);
+}
+
+#[actix_web::test]
+async fn patch_user_applies_json_patch_with_test() {
+    let app = test_app().await;
+    call_service(&app, add_request(&jane())).await;
+
+    let req = patch_request(
+        "janedoe",
+        patch::JSON_PATCH_JSON,
+        serde_json::json!([
+            { "op": "test", "path": "/email", "value": "example@example.com" },
+            { "op": "replace", "path": "/email", "value": "jane@example.com" },
+            { "op": "copy", "from": "/last_name", "path": "/first_name" },
+        ]),
+    );
+    let response: User = call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        response,
+        User { first_name: "Doe".into(), email: "jane@example.com".into(), ..jane() }
+    );
+
+    let req = patch_request(
+        "janedoe",
+        patch::JSON_PATCH_JSON,
+        serde_json::json!([
+            { "op": "test", "path": "/email", "value": "example@example.com" },
+            { "op": "replace", "path": "/last_name", "value": "Roe" },
+        ]),
+    );
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(problem.code, "patch_test_failed");
+}
+
+#[actix_web::test]
+async fn patch_user_rejects_invalid_patches() {
+    let app = test_app().await;
+    call_service(&app, add_request(&jane())).await;
+
+    let cases = [
+        (patch::MERGE_PATCH_JSON, serde_json::json!({ "username": "janet" }), StatusCode::BAD_REQUEST),
+        (patch::MERGE_PATCH_JSON, serde_json::json!({ "password": "hunter2" }), StatusCode::BAD_REQUEST),
+        (patch::MERGE_PATCH_JSON, serde_json::json!({ "email": null }), StatusCode::BAD_REQUEST),
+        (patch::MERGE_PATCH_JSON, serde_json::json!({ "email": "jane@" }), StatusCode::UNPROCESSABLE_ENTITY),
+        (patch::JSON_PATCH_JSON, serde_json::json!([{ "op": "remove", "path": "/nickname" }]), StatusCode::BAD_REQUEST),
+        (patch::JSON_PATCH_JSON, serde_json::json!({ "op": "remove" }), StatusCode::BAD_REQUEST),
+        ("application/json", serde_json::json!({ "last_name": "Roe" }), StatusCode::UNSUPPORTED_MEDIA_TYPE),
+    ];
+    for (content_type, body, status) in cases {
+        let response = call_service(&app, patch_request("janedoe", content_type, body.clone())).await;
+        assert_eq!(response.status(), status, "{body}");
+    }
+
+    let req = TestRequest::get().uri("/get_user/janedoe").to_request();
+    let response: User = call_and_read_body_json(&app, req).await;
+    assert_eq!(response, jane());
+
+    let req = patch_request("nobody", patch::MERGE_PATCH_JSON, serde_json::json!({ "last_name": "Roe" }));
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn update_missing_user_is_not_found() {
+    let app = test_app().await;
+
+    let req = TestRequest::post().insert_header(authorized())
+        .uri("/update_user/nobody")
+        .set_json(serde_json::json!({ "first_name": "Nobody" }))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn malformed_body_is_validation_failure() {
+    let app = test_app().await;
+
+    let req = TestRequest::post().insert_header(authorized())
+        .uri("/add_user")
+        .insert_header((CONTENT_TYPE, "application/json"))
+        .set_payload(r#"{ "username": "janedoe" }"#)
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), PROBLEM_JSON);
+
+    let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(problem.code, "validation_failed");
+}
+
+#[actix_web::test]
+async fn invalid_user_fields_are_listed() {
+    let app = test_app().await;
     let user = User {
-        first_name: "Jane".into(),
-        last_name: "Doe".into(),
+        first_name: "   ".into(),
+        last_name: "x".repeat(101),
+        username: "jane doe".into(),
+        email: "not-an-email".into(),
+    };
+
+    let response = call_service(&app, add_request(&user)).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(problem.code, "validation_failed");
+    let fields: Vec<(&str, &str)> = problem
+        .errors
+        .iter()
+        .map(|violation| (violation.field.as_str(), violation.code.as_str()))
+        .collect();
+    assert_eq!(
+        fields,
+        vec![
+            ("email", "email"),
+            ("first_name", "blank"),
+            ("last_name", "length"),
+            ("username", "username"),
+        ]
+    );
+
+    let req = TestRequest::get().insert_header(authorized()).uri("/get_users").to_request();
+    let response: Page<User> = call_and_read_body_json(&app, req).await;
+    assert!(response.items.is_empty());
+}
+
+#[actix_web::test]
+async fn invalid_update_is_rejected() {
+    let app = test_app().await;
+    call_service(&app, add_request(&jane())).await;
+
+    let req = TestRequest::post().insert_header(authorized())
+        .uri("/update_user/janedoe")
+        .set_json(serde_json::json!({ "email": "jane@" }))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let req = TestRequest::get().uri("/get_user/janedoe").to_request();
+    let response: User = call_and_read_body_json(&app, req).await;
+    assert_eq!(response, jane());
+}
+
+#[actix_web::test]
+async fn delete_user_removes_it() {
+    let app = test_app().await;
+    call_service(&app, add_request(&jane())).await;
+
+    let req = TestRequest::delete().insert_header(authorized()).uri("/delete_user/janedoe").to_request();
+    let response = call_and_read_body(&app, req).await;
+    assert_eq!(response, Bytes::from_static(b"User deleted"));
+
+    let req = TestRequest::get().uri("/get_user/janedoe").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let req = TestRequest::delete().insert_header(authorized()).uri("/delete_user/janedoe").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn v1_users_resource_crud() {
+    let app = test_app().await;
+
+    let req = TestRequest::post().insert_header(authorized()).uri("/v1/users").set_json(jane()).to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(response.headers().get(LOCATION).unwrap(), "/v1/users/janedoe");
+    assert!(response.headers().get("Deprecation").is_none());
+    let created: User = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(created, jane());
+
+    let req = TestRequest::post().insert_header(authorized()).uri("/v1/users").set_json(jane()).to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let req = TestRequest::get().uri("/v1/users/janedoe").to_request();
+    let response: User = call_and_read_body_json(&app, req).await;
+    assert_eq!(response, jane());
+
+    let replacement = User { first_name: "Janet".into(), email: "janet@example.com".into(), ..jane() };
+    let req = TestRequest::put().insert_header(authorized()).uri("/v1/users/janedoe").set_json(&replacement).to_request();
+    let response: User = call_and_read_body_json(&app, req).await;
+    assert_eq!(response, replacement);
+
+    let req = TestRequest::put().insert_header(authorized()).uri("/v1/users/janedoe").set_json(john()).to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let req = TestRequest::put().insert_header(authorized()).uri("/v1/users/jsmith").set_json(john()).to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let req = TestRequest::patch().insert_header(authorized())
+        .uri("/v1/users/janedoe")
+        .insert_header((CONTENT_TYPE, patch::MERGE_PATCH_JSON))
+        .set_payload(r#"{ "last_name": "Roe" }"#)
+        .to_request();
+    let response: User = call_and_read_body_json(&app, req).await;
+    assert_eq!(response, User { last_name: "Roe".into(), ..replacement.clone() });
+
+    let req = TestRequest::get().insert_header(authorized()).uri("/v1/users").to_request();
+    let response: Page<User> = call_and_read_body_json(&app, req).await;
+    assert_eq!(response.items, vec![User { last_name: "Roe".into(), ..replacement }]);
+
+    let req = TestRequest::delete().insert_header(authorized()).uri("/v1/users/janedoe").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let req = TestRequest::delete().insert_header(authorized()).uri("/v1/users/janedoe").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+fn jane_v2() -> UserV2 {
+    UserV2 {
         username: "janedoe".into(),
+        name: PersonName { given: "Jane".into(), family: "Doe".into() },
         email: "example@example.com".into(),
+    }
+}
+
+#[actix_web::test]
+async fn v2_shares_storage_with_v1() {
+    let app = test_app().await;
+
+    let req = TestRequest::post().insert_header(authorized()).uri("/v2/users").set_json(jane_v2()).to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(response.headers().get(LOCATION).unwrap(), "/v2/users/janedoe");
+    assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), versioning::V2_MEDIA_TYPE);
+
+    let req = TestRequest::get().uri("/v1/users/janedoe").to_request();
+    let response: User = call_and_read_body_json(&app, req).await;
+    assert_eq!(response, jane());
+
+    call_service(&app, TestRequest::post().insert_header(authorized()).uri("/v1/users").set_json(john()).to_request()).await;
+    let req = TestRequest::get().insert_header(authorized()).uri("/v2/users?sort=-username").to_request();
+    let response: Page<UserV2> = call_and_read_body_json(&app, req).await;
+    assert_eq!(response.items, vec![UserV2::from(john()), jane_v2()]);
+
+    let req = TestRequest::get().insert_header(authorized()).uri("/v2/users?stream=json").to_request();
+    let response: Vec<UserV2> = call_and_read_body_json(&app, req).await;
+    assert_eq!(response, vec![jane_v2(), UserV2::from(john())]);
+}
+
+#[actix_web::test]
+async fn v2_reports_fields_in_its_own_shape() {
+    let app = test_app().await;
+
+    let mut user = jane_v2();
+    user.name.given = " ".into();
+    let req = TestRequest::post().insert_header(authorized()).uri("/v2/users").set_json(&user).to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(problem.errors[0].field, "name.given");
+
+    let req = TestRequest::post().insert_header(authorized()).uri("/v2/users").set_json(jane()).to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn v2_patches_address_the_v2_shape() {
+    let app = test_app().await;
+    call_service(&app, add_request(&jane())).await;
+
+    let req = TestRequest::patch().insert_header(authorized())
+        .uri("/v2/users/janedoe")
+        .insert_header((CONTENT_TYPE, patch::JSON_PATCH_JSON))
+        .set_payload(r#"[{ "op": "replace", "path": "/name/family", "value": "Roe" }]"#)
+        .to_request();
+    let response: UserV2 = call_and_read_body_json(&app, req).await;
+    assert_eq!(response.name.family, "Roe");
+
+    let req = TestRequest::patch().insert_header(authorized())
+        .uri("/v2/users/janedoe")
+        .insert_header((CONTENT_TYPE, patch::MERGE_PATCH_JSON))
+        .set_payload(r#"{ "last_name": "Roe" }"#)
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn unprefixed_routes_negotiate_version_by_accept() {
+    let app = test_app().await;
+    call_service(&app, add_request(&jane())).await;
+
+    let req = TestRequest::get().uri("/users/janedoe").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.headers().get(VARY).unwrap(), "Accept");
+    let user: User = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(user, jane());
+
+    let req = TestRequest::get()
+        .uri("/users/janedoe")
+        .insert_header((ACCEPT, "application/json;q=0.5, application/vnd.users.v2+json"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), versioning::V2_MEDIA_TYPE);
+    assert_eq!(response.headers().get(VARY).unwrap(), "Accept");
+    let user: UserV2 = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(user, jane_v2());
+
+    let req = TestRequest::get()
+        .uri("/users/janedoe")
+        .insert_header((ACCEPT, "application/vnd.users.v2+json;q=0"))
+        .to_request();
+    let user: User = call_and_read_body_json(&app, req).await;
+    assert_eq!(user, jane());
+}
+
+#[actix_web::test]
+async fn legacy_routes_are_marked_deprecated() {
+    let app = test_app().await;
+    call_service(&app, add_request(&jane())).await;
+
+    let requests = [
+        add_request(&john()),
+        TestRequest::get().uri("/get_user/janedoe").to_request(),
+        TestRequest::get().uri("/get_user/nobody").to_request(),
+        TestRequest::get().insert_header(authorized()).uri("/get_users").to_request(),
+        TestRequest::post().insert_header(authorized())
+            .uri("/update_user/janedoe")
+            .set_json(serde_json::json!({ "last_name": "Roe" }))
+            .to_request(),
+        TestRequest::delete().insert_header(authorized()).uri("/delete_user/janedoe").to_request(),
+    ];
+    for req in requests {
+        let path = req.path().to_string();
+        let response = call_service(&app, req).await;
+        let headers = response.headers();
+        assert_eq!(headers.get("Deprecation").unwrap(), LEGACY_DEPRECATION, "{path}");
+        assert_eq!(headers.get("Sunset").unwrap(), LEGACY_SUNSET, "{path}");
+        assert_eq!(headers.get(LINK).unwrap(), r#"</v1/users>; rel="successor-version""#, "{path}");
+    }
+}
+
+/// An environment of `vars`, with the test JWT secret unless they set one.
+fn env_of(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+    let vars: std::collections::HashMap<String, String> = [("JWT_SECRET", TEST_JWT_SECRET)]
+        .iter()
+        .chain(vars)
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    move |name| vars.get(name).cloned()
+}
+
+#[test]
+fn config_defaults_match_previous_hardcoded_values() {
+    let config = Config::from_sources(None, env_of(&[])).unwrap();
+    let auth = config::AuthConfig { secret: TEST_JWT_SECRET.into(), ..Default::default() };
+    assert_eq!(config, Config { auth, ..Default::default() });
+    assert_eq!(config.server.bind_address, "127.0.0.1");
+    assert_eq!(config.server.port, 8080);
+    assert_eq!(config.storage.database, "myApp");
+    assert_eq!(config.storage.collection, "users");
+    assert_eq!(config.storage.backend, StorageBackend::Mongodb);
+}
+
+#[test]
+fn config_env_overrides_toml() {
+    let toml = r#"
+        [server]
+        port = 9000
+        workers = 2
+
+        [storage]
+        backend = "memory"
+        database = "fromToml"
+    "#;
+    let env = env_of(&[("PORT", "9100"), ("COLL_NAME", "people"), ("MAX_PAGE_SIZE", "20")]);
+    let config = Config::from_sources(Some(("config.toml", toml)), env).unwrap();
+
+    assert_eq!(config.server.port, 9100);
+    assert_eq!(config.server.workers, Some(2));
+    assert_eq!(config.storage.backend, StorageBackend::Memory);
+    assert_eq!(config.storage.database, "fromToml");
+    assert_eq!(config.storage.collection, "people");
+    assert_eq!(config.page_limits().max_size, 20);
+}
+
+#[test]
+fn config_rejects_invalid_values() {
+    let err = Config::from_sources(None, env_of(&[("PORT", "eighty")])).unwrap_err();
+    assert!(err.to_string().contains("PORT"), "{err}");
+
+    let err = Config::from_sources(None, env_of(&[("STORAGE_BACKEND", "postgres")])).unwrap_err();
+    assert!(err.to_string().contains("STORAGE_BACKEND"), "{err}");
+
+    let err = Config::from_sources(Some(("config.toml", "[server]\nprot = 80\n")), env_of(&[])).unwrap_err();
+    assert!(err.to_string().contains("config.toml"), "{err}");
+
+    let err = Config::from_sources(None, env_of(&[("MONGODB_URI", "localhost:27017")])).unwrap_err();
+    assert!(err.to_string().contains("storage.mongodb_uri"), "{err}");
+
+    let err = Config::from_sources(None, env_of(&[("MAX_PAGE_SIZE", "0")])).unwrap_err();
+    assert!(err.to_string().contains("pagination.max_page_size"), "{err}");
+
+    let err = Config::from_sources(None, env_of(&[("MAX_PAGE_SIZE", "18446744073709551615")])).unwrap_err();
+    assert!(err.to_string().contains("pagination.max_page_size"), "{err}");
+
+    let err = Config::from_sources(None, env_of(&[("ACCESS_TOKEN_TTL_SECS", "3601")])).unwrap_err();
+    assert!(err.to_string().contains("auth.access_token_ttl_secs"), "{err}");
+
+    let err = Config::from_sources(None, env_of(&[("REFRESH_TOKEN_TTL_SECS", "18446744073709551615")])).unwrap_err();
+    assert!(err.to_string().contains("auth.refresh_token_ttl_secs"), "{err}");
+
+    for (var, setting) in [
+        ("API_KEY_MAX_TTL_SECS", "auth.api_key_max_ttl_secs"),
+        ("SESSION_TTL_SECS", "sessions.ttl_secs"),
+        ("EMAIL_VERIFICATION_TTL_SECS", "mail.verification_ttl_secs"),
+        ("PASSWORD_RESET_TTL_SECS", "mail.reset_ttl_secs"),
+    ] {
+        let err = Config::from_sources(None, env_of(&[(var, "18446744073709551615")])).unwrap_err();
+        assert!(err.to_string().contains(setting), "{err}");
+    }
+
+    let err = Config::from_sources(None, env_of(&[("WORKERS", "0")])).unwrap_err();
+    assert!(err.to_string().contains("server.workers"), "{err}");
+
+    let err = Config::from_sources(None, env_of(&[("MONGODB_RETRY_MAX_BACKOFF_SECS", "0")])).unwrap_err();
+    assert!(err.to_string().contains("storage.retry_max_backoff_secs"), "{err}");
+
+    let vars = [("MONGODB_RETRY_INITIAL_BACKOFF_MS", "2500"), ("MONGODB_RETRY_MAX_BACKOFF_SECS", "2")];
+    let err = Config::from_sources(None, env_of(&vars)).unwrap_err();
+    assert!(err.to_string().contains("storage.retry_max_backoff_secs"), "{err}");
+
+    for secret in ["", "too short"] {
+        let err = Config::from_sources(None, env_of(&[("JWT_SECRET", secret)])).unwrap_err();
+        assert!(err.to_string().contains("auth.secret"), "{err}");
+    }
+
+    let err = Config::from_sources(None, env_of(&[("JWT_ALGORITHM", "rs256")])).unwrap_err();
+    assert!(err.to_string().contains("auth.private_key_file"), "{err}");
+
+    let err = Config::from_sources(None, env_of(&[("API_KEYS_COLL_NAME", "users")])).unwrap_err();
+    assert!(err.to_string().contains("storage.api_keys_collection"), "{err}");
+
+    let err = Config::from_sources(None, env_of(&[("SESSION_COOKIE_SAME_SITE", "none"), ("SESSION_COOKIE_SECURE", "false")])).unwrap_err();
+    assert!(err.to_string().contains("sessions.same_site"), "{err}");
+
+    let err = Config::from_sources(None, env_of(&[("SESSION_COOKIE_NAME", "my session")])).unwrap_err();
+    assert!(err.to_string().contains("sessions.cookie_name"), "{err}");
+
+    let err = Config::from_sources(None, env_of(&[("MAIL_TRANSPORT", "smtp")])).unwrap_err();
+    assert!(err.to_string().contains("mail.smtp_host"), "{err}");
+
+    let err = Config::from_sources(None, env_of(&[("MAIL_FROM", "not an address")])).unwrap_err();
+    assert!(err.to_string().contains("mail.from"), "{err}");
+
+    let err = Config::from_sources(None, env_of(&[("PASSWORD_RESET_URL", "https://example.com/reset")])).unwrap_err();
+    assert!(err.to_string().contains("mail.reset_url"), "{err}");
+}
+
+#[test]
+fn backoff_doubles_up_to_the_maximum() {
+    let mut backoff = startup::Backoff::new(Duration::from_millis(100), Duration::from_millis(500));
+    let delays: Vec<u128> = (0..5).map(|_| backoff.next_delay().as_millis()).collect();
+    assert_eq!(delays, [100, 200, 400, 500, 500]);
+}
+
+#[actix_web::test]
+async fn retry_stops_after_success_or_max_attempts() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    let fast = || startup::Backoff::new(Duration::from_millis(1), Duration::from_millis(2));
+
+    let calls = AtomicU32::new(0);
+    let succeeded = startup::retry("flaky", Some(5), &mut fast(), || {
+        let call = calls.fetch_add(1, Ordering::SeqCst) + 1;
+        async move { if call < 3 { Err(RepositoryError::NotReady) } else { Ok(()) } }
+    })
+    .await;
+    assert!(succeeded);
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    let calls = AtomicU32::new(0);
+    let succeeded = startup::retry("down", Some(4), &mut fast(), || {
+        calls.fetch_add(1, Ordering::SeqCst);
+        async { Err(RepositoryError::NotReady) }
+    })
+    .await;
+    assert!(!succeeded);
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+}
+
+#[actix_web::test]
+async fn unreachable_mongodb_starts_degraded() {
+    let storage = config::StorageConfig {
+        mongodb_uri: "mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=50&connectTimeoutMS=50".into(),
+        startup_attempts: 2,
+        retry_initial_backoff_ms: 1,
+        ..Default::default()
     };
+    let client = Client::with_uri_str(&storage.mongodb_uri).await.unwrap();
+    let repo = MongoUserRepository::new(&client, &storage.database, &storage.collection);
 
-    let req = TestRequest::post()
-        .uri("/add_user")
-        .set_form(&user)
+    let readiness = startup::initialize_storage(&repo, &storage).await;
+    assert!(!readiness.is_storage_ready());
+
+    let repo = Arc::new(ReadinessGatedRepository::new(Arc::new(repo), readiness.clone()));
+    let app = TestApp::default().repo(repo).readiness(readiness).build().await;
+    let req = TestRequest::get().uri("/v1/users/janedoe").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(problem.code, "storage_unavailable");
+
+    let response = call_service(&app, TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let report: Report = serde_json::from_slice(&read_body(response).await).unwrap();
+    let failing: Vec<&str> = report.checks.iter().filter(|check| check.status == Status::Fail).map(|check| check.name.as_str()).collect();
+    assert_eq!(failing, ["storage", "username_index"]);
+}
+
+#[actix_web::test]
+async fn healthz_reports_alive() {
+    let app = test_app().await;
+    let response = call_service(&app, TestRequest::get().uri("/healthz").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let report: Report = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(report.status, Status::Ok);
+}
+
+#[actix_web::test]
+async fn readyz_reports_each_check() {
+    let app = test_app().await;
+    let response = call_service(&app, TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let report: Report = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(report.status, Status::Ok);
+    let names: Vec<&str> = report.checks.iter().map(|check| check.name.as_str()).collect();
+    assert_eq!(names, ["shutdown", "storage", "username_index"]);
+    assert!(report.checks.iter().all(|check| check.status == Status::Ok && check.error.is_none()));
+}
+
+#[actix_web::test]
+async fn readyz_fails_while_draining() {
+    let readiness = Arc::new(Readiness::default());
+    readiness.mark_storage_ready();
+    let app = TestApp::default().readiness(readiness.clone()).build().await;
+
+    readiness.begin_drain();
+    let response = call_service(&app, TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let report: Report = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(report.checks[0].status, Status::Fail);
+
+    // Liveness is unaffected, and requests in flight are still served.
+    let response = call_service(&app, TestRequest::get().uri("/healthz").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = call_service(&app, TestRequest::get().insert_header(authorized()).uri("/v1/users").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn gated_repository_serves_once_ready() {
+    let readiness = Arc::new(startup::Readiness::default());
+    let inner: Arc<dyn UserRepository> = Arc::new(InMemoryUserRepository::new());
+    let repo = ReadinessGatedRepository::new(inner, readiness.clone());
+
+    assert!(matches!(repo.insert(jane(), None).await, Err(RepositoryError::NotReady)));
+    readiness.mark_storage_ready();
+    repo.insert(jane(), None).await.unwrap();
+    assert_eq!(repo.find_by_username("janedoe").await.unwrap(), Some(jane()));
+}
+
+#[actix_web::test]
+async fn metrics_count_requests_by_route_and_status() {
+    let app = test_app().await;
+    call_service(&app, add_request(&jane())).await;
+    for username in ["janedoe", "nobody", "someone"] {
+        call_service(&app, TestRequest::get().uri(&format!("/v1/users/{username}")).to_request()).await;
+    }
+
+    let response = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+    assert!(body.contains(r#"http_requests_total{method="POST",route="/add_user",status="200"} 1"#), "{body}");
+    assert!(body.contains(r#"http_requests_total{method="GET",route="/v1/users/{username}",status="200"} 1"#), "{body}");
+    assert!(body.contains(r#"http_requests_total{method="GET",route="/v1/users/{username}",status="404"} 2"#), "{body}");
+    assert!(body.contains(r#"http_request_duration_seconds_count{method="GET",route="/v1/users/{username}",status="404"} 2"#), "{body}");
+}
+
+#[actix_web::test]
+async fn instrumented_repository_records_operations() {
+    let metrics = Arc::new(Metrics::new());
+    let repo = metrics::InstrumentedRepository::new(Arc::new(InMemoryUserRepository::new()), "users", metrics.clone());
+    repo.insert(jane(), None).await.unwrap();
+    assert!(repo.insert(jane(), None).await.is_err());
+    repo.find_by_username("janedoe").await.unwrap();
+    repo.delete("janedoe").await.unwrap();
+
+    let body = metrics.render();
+    assert!(body.contains(r#"mongodb_operation_duration_seconds_count{collection="users",operation="insert_one"} 2"#), "{body}");
+    assert!(body.contains(r#"mongodb_operation_errors_total{collection="users",operation="insert_one"} 1"#), "{body}");
+    assert!(body.contains(r#"mongodb_operation_duration_seconds_count{collection="users",operation="find_one"} 1"#), "{body}");
+    assert!(body.contains(r#"mongodb_operation_duration_seconds_count{collection="users",operation="delete_one"} 1"#), "{body}");
+    assert!(!body.contains(r#"mongodb_operation_errors_total{collection="users",operation="find_one"}"#), "{body}");
+
+    let keys = metrics::InstrumentedRepository::new(Arc::new(InMemoryApiKeyRepository::new()), "api_keys", metrics.clone());
+    keys.list().await.unwrap();
+    let body = metrics.render();
+    assert!(body.contains(r#"mongodb_operation_duration_seconds_count{collection="api_keys",operation="find"} 1"#), "{body}");
+}
+
+#[actix_web::test]
+async fn request_id_is_generated_and_echoed() {
+    let app = test_app().await;
+    let response = call_service(&app, TestRequest::get().uri("/healthz").to_request()).await;
+    let id = response.headers().get(logging::REQUEST_ID_HEADER).unwrap().to_str().unwrap();
+    assert!(uuid::Uuid::parse_str(id).is_ok(), "{id}");
+
+    // Ids that are too long or not printable ASCII are replaced.
+    for supplied in ["has space", &"x".repeat(129)] {
+        let req = TestRequest::get().uri("/healthz").insert_header(("X-Request-Id", supplied)).to_request();
+        let response = call_service(&app, req).await;
+        let id = response.headers().get(logging::REQUEST_ID_HEADER).unwrap().to_str().unwrap();
+        assert!(uuid::Uuid::parse_str(id).is_ok(), "{id}");
+    }
+}
+
+#[actix_web::test]
+async fn request_id_is_propagated_to_responses_and_errors() {
+    let app = test_app().await;
+    let req = TestRequest::get().uri("/v1/users/nobody").insert_header(("X-Request-Id", "req-42")).to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.headers().get(logging::REQUEST_ID_HEADER).unwrap(), "req-42");
+    let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(problem.request_id.as_deref(), Some("req-42"));
+
+    // Extractor failures are rendered inside the request too.
+    let req = TestRequest::post().insert_header(authorized())
+        .uri("/v1/users")
+        .insert_header(("X-Request-Id", "req-43"))
+        .insert_header((CONTENT_TYPE, "application/json"))
+        .set_payload("{")
         .to_request();
+    let problem: ProblemDetails = serde_json::from_slice(&call_and_read_body(&app, req).await).unwrap();
+    assert_eq!(problem.request_id.as_deref(), Some("req-43"));
+}
 
-    let response = call_and_read_body(&app, req).await;
+/// Log output captured by a test subscriber.
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[actix_web::test]
+async fn access_log_is_json_with_request_id() {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let _guard = tracing::subscriber::set_default(logging::subscriber(move || writer.clone(), None));
+
+    let app = test_app().await;
+    let req = TestRequest::get()
+        .uri("/v1/users/nobody")
+        .insert_header(("X-Request-Id", "req-7"))
+        .insert_header(("User-Agent", "probe/1.0"))
+        .to_request();
+    call_service(&app, req).await;
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let line: serde_json::Value = logs
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .find(|line: &serde_json::Value| line["target"] == "access")
+        .expect("an access log line");
+    assert_eq!(line["method"], "GET");
+    assert_eq!(line["route"], "/v1/users/{username}");
+    assert_eq!(line["status"], 404);
+    assert_eq!(line["user_agent"], "probe/1.0");
+    assert!(line["duration_ms"].is_f64());
+    assert_eq!(line["span"]["request_id"], "req-7");
+}
+
+#[actix_web::test]
+async fn traces_continue_incoming_trace_context() {
+    let spans = CapturedLogs::default();
+    let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+        .with_simple_exporter(telemetry::JsonLinesExporter::new(spans.clone()))
+        .build();
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let _guard = tracing::subscriber::set_default(logging::subscriber(move || writer.clone(), Some(&provider)));
+
+    let repo = Arc::new(TracedRepository::new(Arc::new(InMemoryUserRepository::new()), "users"));
+    let app = TestApp::default().repo(repo).build().await;
+    let req = TestRequest::get()
+        .uri("/v1/users/nobody")
+        .insert_header(("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"))
+        .to_request();
+    call_service(&app, req).await;
+    provider.force_flush().unwrap();
+
+    let spans = String::from_utf8(spans.0.lock().unwrap().clone()).unwrap();
+    let spans: Vec<serde_json::Value> = spans.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    let server = spans.iter().find(|span| span["kind"] == "server").expect("a server span");
+    assert_eq!(server["name"], "GET /v1/users/{username}");
+    assert_eq!(server["trace_id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(server["parent_span_id"], "00f067aa0ba902b7");
+    assert_eq!(server["attributes"]["http.route"], "/v1/users/{username}");
+    assert_eq!(server["attributes"]["http.response.status_code"], 404);
+
+    let client = spans.iter().find(|span| span["kind"] == "client").expect("a MongoDB span");
+    assert_eq!(client["name"], "find_one users");
+    assert_eq!(client["trace_id"], server["trace_id"]);
+    assert_eq!(client["parent_span_id"], server["span_id"]);
+    assert_eq!(client["attributes"]["db.operation.name"], "find_one");
+
+    // Log lines written during the request carry the trace id.
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let access: serde_json::Value = logs
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .find(|line: &serde_json::Value| line["target"] == "access")
+        .expect("an access log line");
+    assert_eq!(access["span"]["trace_id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+}
+
+#[actix_web::test]
+async fn openapi_document_describes_the_models() {
+    let app = test_app().await;
+    let req = TestRequest::get().uri("/openapi.json").to_request();
+    let document: serde_json::Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(document["openapi"], "3.1.0");
+
+    let schemas = &document["components"]["schemas"];
+    let user = &schemas["User"];
+    assert_eq!(user["required"], serde_json::json!(["first_name", "last_name", "username", "email"]));
+    assert_eq!(user["properties"]["username"]["pattern"], "^[A-Za-z0-9._-]{3,32}$");
+    assert_eq!(user["properties"]["first_name"]["maxLength"], 100);
+    assert_eq!(user["properties"]["email"]["format"], "email");
+    assert!(schemas["UserV2"]["properties"]["name"].is_object());
+    assert!(schemas["ProblemDetails"]["properties"]["code"].is_object());
+
+    let list = &document["paths"]["/v1/users"]["get"];
+    let parameters: Vec<&str> = list["parameters"].as_array().unwrap().iter().map(|parameter| parameter["name"].as_str().unwrap()).collect();
+    for name in ["username_prefix", "email_domain", "sort", "limit", "offset", "cursor", "stream"] {
+        assert!(parameters.contains(&name), "{name} in {parameters:?}");
+    }
+    assert_eq!(list["responses"]["503"]["$ref"], "#/components/responses/ServiceUnavailable");
+    assert_eq!(document["paths"]["/add_user"]["post"]["deprecated"], true);
+
+    // Every reference resolves within the document.
+    fn references<'a>(value: &'a serde_json::Value, found: &mut Vec<&'a str>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                if let Some(reference) = map.get("$ref").and_then(|reference| reference.as_str()) {
+                    found.push(reference);
+                }
+                map.values().for_each(|value| references(value, found));
+            }
+            serde_json::Value::Array(items) => items.iter().for_each(|value| references(value, found)),
+            _ => {}
+        }
+    }
+    let mut found = Vec::new();
+    references(&document, &mut found);
+    for reference in found {
+        assert!(document.pointer(reference.trim_start_matches('#')).is_some(), "{reference}");
+    }
+}
+
+#[actix_web::test]
+async fn openapi_paths_are_routed() {
+    let app = test_app().await;
+    let document = openapi::document();
+    for (path, item) in document["paths"].as_object().unwrap() {
+        for method in ["get", "post", "put", "patch", "delete"] {
+            if item.get(method).is_none() {
+                continue;
+            }
+            let uri = path.replace("{username}", "nobody").replace("{id}", "none");
+            let req = TestRequest::default()
+                .method(method.to_uppercase().parse().unwrap())
+                .uri(&uri)
+                .to_request();
+            let response = call_service(&app, req).await;
+            // Unrouted requests get a bare 404 or 405, never a problem document.
+            let routed = !matches!(response.status(), StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED)
+                || response.headers().get(CONTENT_TYPE).is_some_and(|value| value == PROBLEM_JSON);
+            assert!(routed, "{method} {path} is not routed");
+        }
+    }
+}
+
+/// Route patterns registered by [`configure`].
+///
+/// actix-web cannot list its routes, so this walks the debug output of the
+/// resource map, where each resource shows its pattern and scopes nest theirs.
+async fn registered_patterns() -> Vec<String> {
+    async fn resource_map(req: actix_web::HttpRequest) -> String {
+        format!("{:#?}", req.resource_map())
+    }
+    let app = init_service(App::new().configure(configure).route("/resource-map", web::get().to(resource_map))).await;
+    let body = read_body(call_service(&app, TestRequest::get().uri("/resource-map").to_request()).await).await;
+    let dump = String::from_utf8(body.to_vec()).unwrap();
+
+    let mut patterns = Vec::new();
+    // Enclosing scopes, as their indentation and full prefix.
+    let mut scopes: Vec<(usize, String)> = Vec::new();
+    let mut pattern = None;
+    let mut named = None;
+    let mut lines = dump.lines();
+    while let Some(line) = lines.next() {
+        let indent = line.len() - line.trim_start().len();
+        let line = line.trim();
+        // Named resources repeat ones listed under their scope.
+        if let Some(named_indent) = named {
+            if indent == named_indent && line.starts_with('}') {
+                named = None;
+            }
+        } else if line == "named: {" {
+            named = Some(indent);
+        } else if line == "patterns: Single(" {
+            let literal = lines.next().unwrap().trim().trim_end_matches(',');
+            pattern = Some((indent, serde_json::from_str::<String>(literal).unwrap()));
+        } else if let Some(is_prefix) = line.strip_prefix("is_prefix: ") {
+            let (indent, pattern) = pattern.take().unwrap();
+            scopes.retain(|(scope_indent, _)| *scope_indent < indent);
+            let full = scopes.last().map_or(String::new(), |(_, prefix)| prefix.clone()) + &pattern;
+            if is_prefix == "true," {
+                scopes.push((indent, full));
+            } else {
+                patterns.push(full);
+            }
+        }
+    }
+    patterns.retain(|pattern| pattern != "/resource-map");
+    patterns
+}
+
+#[actix_web::test]
+async fn routes_are_documented() {
+    let app = test_app().await;
+    let document = openapi::document();
+    let parameter = regex::Regex::new(r"\{[^}]*\}").unwrap();
+    let patterns = registered_patterns().await;
+    assert!(patterns.contains(&"/v1/users/{username}".to_string()), "{patterns:?}");
+    for pattern in patterns {
+        let item = document["paths"].get(&pattern).unwrap_or_else(|| panic!("{pattern} is not documented"));
+        for method in ["get", "post", "put", "patch", "delete"] {
+            // An admin passes authorization, so only unrouted methods get 405.
+            let req = TestRequest::default()
+                .method(method.to_uppercase().parse().unwrap())
+                .uri(&parameter.replace_all(&pattern, "nobody"))
+                .insert_header(authorized())
+                .to_request();
+            let response = call_service(&app, req).await;
+            let routed = !matches!(response.status(), StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED)
+                || response.headers().get(CONTENT_TYPE).is_some_and(|value| value == PROBLEM_JSON);
+            assert!(!routed || item.get(method).is_some(), "{method} {pattern} is not documented");
+        }
+    }
+}
+
+#[actix_web::test]
+async fn docs_page_is_self_contained() {
+    let app = test_app().await;
+    let response = call_service(&app, TestRequest::get().uri("/docs").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get(CONTENT_TYPE).unwrap().to_str().unwrap().starts_with("text/html"));
+    let page = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+    assert!(page.contains("/openapi.json"));
+    assert!(!page.contains("http://") && !page.contains("https://"), "the page loads external resources");
+}
+
+fn login_request(username: &str, password: &str) -> Request {
+    TestRequest::post()
+        .uri("/login")
+        .set_json(serde_json::json!({ "username": username, "password": password }))
+        .to_request()
+}
+
+fn registration(user: &User, password: &str) -> serde_json::Value {
+    let mut body = serde_json::to_value(user).unwrap();
+    body["password"] = password.into();
+    body
+}
+
+#[actix_web::test]
+async fn registered_password_logs_in_and_is_never_returned() {
+    let repo = Arc::new(InMemoryUserRepository::new());
+    let app = TestApp::default().repo(repo.clone()).build().await;
+
+    let req = TestRequest::post().insert_header(authorized()).uri("/v1/users").set_json(registration(&jane(), "correct horse")).to_request();
+    let body = read_body(call_service(&app, req).await).await;
+    assert!(!String::from_utf8_lossy(&body).contains("password"), "{body:?}");
+    let hash = repo.find_password_hash("janedoe").await.unwrap().unwrap();
+    assert!(hash.starts_with("$argon2id$"), "{hash}");
+
+    for uri in ["/v1/users/janedoe", "/get_user/janedoe", "/get_users"] {
+        let body = read_body(call_service(&app, TestRequest::get().insert_header(authorized()).uri(uri).to_request()).await).await;
+        assert!(!String::from_utf8_lossy(&body).contains("argon2"), "{uri}");
+    }
+
+    let response = call_service(&app, login_request("janedoe", "correct horse")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let tokens: TokenPair = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(tokens.token_type, "Bearer");
+}
+
+#[actix_web::test]
+async fn login_rejects_wrong_password_and_unknown_user_alike() {
+    let app = test_app().await;
+    let req = TestRequest::post().insert_header(authorized()).uri("/add_user").set_json(registration(&jane(), "correct horse")).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+    // Users created without a password cannot log in.
+    call_service(&app, add_request(&user_named("nopass"))).await;
+
+    let mut details = Vec::new();
+    for (username, password) in [("janedoe", "wrong horse"), ("nobody", "correct horse"), ("nopass", "")] {
+        let response = call_service(&app, login_request(username, password)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(problem.code, "invalid_credentials");
+        details.push(problem.detail);
+    }
+    details.dedup();
+    assert_eq!(details.len(), 1, "failures must not reveal which part was wrong");
+}
+
+#[actix_web::test]
+async fn registration_enforces_password_policy() {
+    let app = test_app().await;
+
+    let req = TestRequest::post().insert_header(authorized()).uri("/v1/users").set_json(registration(&jane(), "short")).to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(problem.errors[0].field, "password");
+
+    let mut body = serde_json::to_value(jane_v2()).unwrap();
+    body["password"] = 12345678.into();
+    let req = TestRequest::post().insert_header(authorized()).uri("/v2/users").insert_header((CONTENT_TYPE, versioning::V2_MEDIA_TYPE)).set_payload(body.to_string()).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+
+    // The v2 representation still rejects unknown members.
+    let mut body = registration(&jane(), "correct horse");
+    body["name"] = serde_json::json!({ "given": "Jane", "family": "Doe" });
+    let req = TestRequest::post().insert_header(authorized()).uri("/v2/users").insert_header((CONTENT_TYPE, versioning::V2_MEDIA_TYPE)).set_payload(body.to_string()).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn login_upgrades_outdated_password_hashes() {
+    let repo = Arc::new(InMemoryUserRepository::new());
+    let outdated = accounts::Passwords::new(argon2::Params::new(16, 2, 1, None).unwrap());
+    let hash = outdated.hash("correct horse".into()).await;
+    repo.insert(jane(), Some(hash.clone())).await.unwrap();
+    let app = TestApp::default().repo(repo.clone()).build().await;
+
+    assert_eq!(call_service(&app, login_request("janedoe", "correct horse")).await.status(), StatusCode::OK);
+    let upgraded = repo.find_password_hash("janedoe").await.unwrap().unwrap();
+    assert_ne!(upgraded, hash);
+    assert!(upgraded.starts_with("$argon2id$v=19$m=8,t=1,p=1$"), "{upgraded}");
+    assert_eq!(call_service(&app, login_request("janedoe", "correct horse")).await.status(), StatusCode::OK);
+    assert_eq!(repo.find_password_hash("janedoe").await.unwrap().unwrap(), upgraded);
+}
+
+#[actix_web::test]
+async fn writes_require_a_bearer_token() {
+    let app = test_app().await;
+    call_service(&app, add_request(&jane())).await;
+
+    for req in [
+        TestRequest::post().uri("/add_user").set_json(john()),
+        TestRequest::post().uri("/update_user/janedoe").set_json(serde_json::json!({ "first_name": "Janet" })),
+        TestRequest::delete().uri("/delete_user/janedoe"),
+        TestRequest::post().uri("/v1/users").set_json(john()),
+        TestRequest::put().uri("/v1/users/janedoe").set_json(jane()),
+        TestRequest::delete().uri("/users/janedoe"),
+    ] {
+        let response = call_service(&app, req.to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers().get(WWW_AUTHENTICATE).unwrap(), "Bearer");
+        let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(problem.code, "unauthenticated");
+    }
+
+    // Reads of single users stay anonymous, and nothing was changed.
+    let req = TestRequest::get().uri("/get_user/janedoe").to_request();
+    let user: User = call_and_read_body_json(&app, req).await;
+    assert_eq!(user, jane());
+}
+
+#[actix_web::test]
+async fn invalid_bearer_tokens_are_rejected() {
+    let app = test_app().await;
+    let other_key = Tokens::hs256(&config::AuthConfig::default(), b"another secret of at least 32 bytes!");
+    let expired = {
+        // Past the default leeway of a minute.
+        let now = jsonwebtoken::get_current_timestamp();
+        let claims = serde_json::json!({ "sub": "tester", "iss": env!("CARGO_PKG_NAME"), "iat": now - 1000, "exp": now - 120, "typ": "access" });
+        let key = jsonwebtoken::EncodingKey::from_secret(TEST_JWT_SECRET.as_bytes());
+        jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &key).unwrap()
+    };
+
+    for authorization in [
+        "Bearer not-a-jwt".to_string(),
+        format!("Basic {}", test_tokens().issue("tester", &[Role::Admin], "").access_token),
+        format!("Bearer {}", other_key.issue("tester", &[Role::Admin], "").access_token),
+        format!("Bearer {}", test_tokens().issue("tester", &[Role::Admin], "").refresh_token),
+        format!("Bearer {expired}"),
+    ] {
+        let req = TestRequest::post().uri("/v1/users").insert_header((AUTHORIZATION, authorization.clone())).set_json(jane()).to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{authorization}");
+        assert_eq!(response.headers().get(WWW_AUTHENTICATE).unwrap(), "Bearer error=\"invalid_token\"");
+        let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(problem.code, "invalid_token");
+    }
+}
+
+#[actix_web::test]
+async fn login_tokens_authorize_writes_and_refresh() {
+    let app = test_app().await;
+    let req = TestRequest::post().insert_header(authorized()).uri("/v1/users").set_json(registration(&jane(), "correct horse")).to_request();
+    call_service(&app, req).await;
+
+    let req = login_request("janedoe", "correct horse");
+    let tokens: TokenPair = call_and_read_body_json(&app, req).await;
+    assert_eq!(tokens.expires_in, 900);
+    let update = |token: &str| TestRequest::put().uri("/v1/users/janedoe").insert_header(bearer(token)).set_json(jane()).to_request();
+    assert_eq!(call_service(&app, update(&tokens.access_token)).await.status(), StatusCode::OK);
+
+    // Only refresh tokens can be refreshed.
+    let refresh = |token: &str| TestRequest::post().uri("/token/refresh").set_json(serde_json::json!({ "refresh_token": token })).to_request();
+    assert_eq!(call_service(&app, refresh(&tokens.access_token)).await.status(), StatusCode::UNAUTHORIZED);
+    let refreshed: TokenPair = call_and_read_body_json(&app, refresh(&tokens.refresh_token)).await;
+    assert_eq!(call_service(&app, update(&refreshed.access_token)).await.status(), StatusCode::OK);
+
+    // Users that no longer exist cannot refresh.
+    let req = TestRequest::delete().uri("/v1/users/janedoe").insert_header(authorized()).to_request();
+    call_service(&app, req).await;
+    assert_eq!(call_service(&app, refresh(&refreshed.refresh_token)).await.status(), StatusCode::UNAUTHORIZED);
+
+    // Nor can their tokens refresh for a new user of the same name.
+    let req = TestRequest::post().insert_header(authorized()).uri("/v1/users").set_json(registration(&jane(), "correct horse")).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::CREATED);
+    assert_eq!(call_service(&app, refresh(&refreshed.refresh_token)).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+fn as_role(role: Role, req: TestRequest) -> Request {
+    req.insert_header(bearer(&test_tokens().issue("janedoe", &[role], "").access_token)).to_request()
+}
+
+/// Asserts that the request is rejected with a `forbidden` problem.
+async fn assert_forbidden(app: &impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>, req: Request) {
+    let response = call_service(app, req).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(problem.code, "forbidden");
+}
+
+#[actix_web::test]
+async fn only_admins_and_support_create_users() {
+    let app = test_app().await;
+    assert_forbidden(&app, as_role(Role::Owner, TestRequest::post().uri("/add_user").set_json(jane()))).await;
+    assert_forbidden(&app, as_role(Role::Owner, TestRequest::post().uri("/v1/users").set_json(jane()))).await;
+
+    let response = call_service(&app, as_role(Role::Support, TestRequest::post().uri("/v1/users").set_json(jane()))).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = call_service(&app, as_role(Role::Admin, TestRequest::post().uri("/add_user").set_json(john()))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn only_admins_list_users() {
+    let app = test_app().await;
+    for role in [Role::Owner, Role::Support] {
+        assert_forbidden(&app, as_role(role, TestRequest::get().uri("/get_users"))).await;
+        assert_forbidden(&app, as_role(role, TestRequest::get().uri("/v1/users"))).await;
+    }
+    let response = call_service(&app, as_role(Role::Admin, TestRequest::get().uri("/get_users"))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn users_update_only_their_own_record() {
+    let app = test_app().await;
+    call_service(&app, add_request(&jane())).await;
+    call_service(&app, add_request(&john())).await;
+    let patch = serde_json::json!({ "first_name": "Janet" });
+
+    // Tokens from as_role belong to janedoe.
+    let response = call_service(&app, as_role(Role::Owner, TestRequest::post().uri("/update_user/janedoe").set_json(&patch))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_forbidden(&app, as_role(Role::Owner, TestRequest::post().uri("/update_user/jsmith").set_json(&patch))).await;
+    assert_forbidden(&app, as_role(Role::Owner, TestRequest::put().uri("/v1/users/jsmith").set_json(john()))).await;
+
+    for role in [Role::Support, Role::Admin] {
+        let response = call_service(&app, as_role(role, TestRequest::post().uri("/update_user/jsmith").set_json(&patch))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
+
+#[actix_web::test]
+async fn only_users_and_admins_change_emails() {
+    let app = test_app().await;
+    call_service(&app, add_request(&jane())).await;
+    call_service(&app, add_request(&john())).await;
+    let patch = serde_json::json!({ "email": "attacker@example.com" });
+    let merge_patch = |uri: &str| TestRequest::patch().uri(uri).insert_header((CONTENT_TYPE, patch::MERGE_PATCH_JSON)).set_payload(patch.to_string());
+
+    // Support may update other fields, but not the address a password reset is sent to.
+    assert_forbidden(&app, as_role(Role::Support, TestRequest::post().uri("/update_user/jsmith").set_json(&patch))).await;
+    assert_forbidden(&app, as_role(Role::Support, TestRequest::put().uri("/v1/users/jsmith").set_json(User { email: "attacker@example.com".into(), ..john() }))).await;
+    assert_forbidden(&app, as_role(Role::Support, merge_patch("/v1/users/jsmith"))).await;
+    assert_forbidden(&app, as_role(Role::Support, TestRequest::post().uri("/v1/users/jsmith/email-verification"))).await;
+    let response = call_service(&app, as_role(Role::Support, TestRequest::put().uri("/v1/users/jsmith").set_json(User { first_name: "Johnny".into(), ..john() }))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Tokens from as_role belong to janedoe.
+    let response = call_service(&app, as_role(Role::Owner, merge_patch("/v1/users/janedoe"))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = call_service(&app, as_role(Role::Admin, merge_patch("/v1/users/jsmith"))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn only_admins_update_admins() {
+    let app = test_app().await;
+    call_service(&app, add_request(&john())).await;
+    let req = TestRequest::put().uri("/v1/users/jsmith/roles").insert_header(authorized()).set_json(serde_json::json!({ "roles": ["admin"] })).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+    let patch = serde_json::json!({ "email": "attacker@example.com" });
+
+    assert_forbidden(&app, as_role(Role::Support, TestRequest::post().uri("/update_user/jsmith").set_json(&patch))).await;
+    assert_forbidden(&app, as_role(Role::Support, TestRequest::put().uri("/v1/users/jsmith").set_json(john()))).await;
+    assert_forbidden(&app, as_role(Role::Support, TestRequest::patch().uri("/v1/users/jsmith").insert_header((CONTENT_TYPE, patch::MERGE_PATCH_JSON)).set_payload(patch.to_string()))).await;
+    let response = call_service(&app, as_role(Role::Admin, TestRequest::patch().uri("/v1/users/jsmith").insert_header((CONTENT_TYPE, patch::MERGE_PATCH_JSON)).set_payload(patch.to_string()))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn only_admins_delete_users() {
+    let app = test_app().await;
+    call_service(&app, add_request(&jane())).await;
+
+    for role in [Role::Owner, Role::Support] {
+        assert_forbidden(&app, as_role(role, TestRequest::delete().uri("/delete_user/janedoe"))).await;
+        assert_forbidden(&app, as_role(role, TestRequest::delete().uri("/v1/users/janedoe"))).await;
+    }
+    let response = call_service(&app, as_role(Role::Admin, TestRequest::delete().uri("/delete_user/janedoe"))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn admins_assign_roles_that_tokens_carry() {
+    let app = test_app().await;
+    let req = TestRequest::post().insert_header(authorized()).uri("/v1/users").set_json(registration(&jane(), "correct horse")).to_request();
+    call_service(&app, req).await;
+
+    let req = TestRequest::get().uri("/v1/users/janedoe/roles").insert_header(authorized()).to_request();
+    let roles: model::RoleAssignment = call_and_read_body_json(&app, req).await;
+    assert_eq!(roles.roles, vec![Role::Owner]);
+    assert_forbidden(&app, as_role(Role::Support, TestRequest::put().uri("/v1/users/janedoe/roles").set_json(serde_json::json!({ "roles": ["admin"] })))).await;
+
+    let req = TestRequest::put().uri("/v1/users/janedoe/roles").insert_header(authorized()).set_json(serde_json::json!({ "roles": ["support", "self", "support"] })).to_request();
+    let roles: model::RoleAssignment = call_and_read_body_json(&app, req).await;
+    assert_eq!(roles.roles, vec![Role::Support, Role::Owner]);
+
+    let tokens: TokenPair = call_and_read_body_json(&app, login_request("janedoe", "correct horse")).await;
+    let req = TestRequest::post().uri("/v1/users").insert_header(bearer(&tokens.access_token)).set_json(john()).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::CREATED);
+}
+
+#[actix_web::test]
+async fn configured_admins_are_granted_the_admin_role() {
+    let repo = InMemoryUserRepository::new();
+    repo.insert(jane(), None).await.unwrap();
+    let grants = RoleGrants::new(["janedoe".to_string()]);
+    assert_eq!(grants.roles_of(&repo, "janedoe").await.unwrap(), Some(vec![Role::Owner, Role::Admin]));
+    assert_eq!(grants.roles_of(&repo, "nobody").await.unwrap(), None);
+}
+
+#[actix_web::test]
+async fn rs256_tokens_are_verified_against_the_jwks() {
+    let config = config::AuthConfig {
+        algorithm: config::JwtAlgorithm::Rs256,
+        private_key_file: concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/jwt-rs256.pem").into(),
+        jwks_file: concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/jwks.json").into(),
+        key_id: "test-2026".into(),
+        ..Default::default()
+    };
+    let tokens = Tokens::from_config(&config).unwrap();
+    let token = tokens.issue("tester", &[Role::Admin], "").access_token;
+    assert_eq!(jsonwebtoken::decode_header(&token).unwrap().kid.as_deref(), Some("test-2026"));
+
+    let repo: Arc<dyn UserRepository> = Arc::new(InMemoryUserRepository::new());
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::from(repo))
+            .app_data(web::Data::new(Passwords::new(argon2::Params::new(8, 1, 1, None).unwrap())))
+            .app_data(web::Data::new(tokens))
+            .app_data(web::Data::new(test_email_tokens(Arc::new(InMemoryMailer::new()))))
+            .configure(configure),
+    )
+    .await;
+
+    let req = TestRequest::post().uri("/v1/users").insert_header(bearer(&token)).set_json(jane()).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::CREATED);
+    // HS256 tokens are not accepted in RS256 mode.
+    let req = TestRequest::post().uri("/v1/users").insert_header(authorized()).set_json(john()).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    let unknown_kid = config::AuthConfig { key_id: "retired".into(), ..config };
+    assert!(Tokens::from_config(&unknown_kid).is_err());
+}
+
+fn create_api_key_request(body: serde_json::Value) -> Request {
+    TestRequest::post().uri("/api-keys").insert_header(authorized()).set_json(body).to_request()
+}
+
+fn api_key(key: &str) -> (&'static str, String) {
+    (api_keys::API_KEY_HEADER, key.into())
+}
+
+#[actix_web::test]
+async fn api_keys_grant_their_scopes_until_revoked() {
+    let app = test_app().await;
+    let response = call_service(&app, create_api_key_request(serde_json::json!({ "name": "nightly export", "scopes": ["read-users"] }))).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: api_keys::CreatedApiKey = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert!(created.key.starts_with("uk_"));
+    assert_eq!(created.info.created_by, "tester");
+    assert!(created.info.last_used_at.is_none());
+
+    let req = TestRequest::get().uri("/v1/users").insert_header(api_key(&created.key)).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+    assert_forbidden(&app, TestRequest::post().uri("/v1/users").insert_header(api_key(&created.key)).set_json(jane()).to_request()).await;
+    // Keys cannot manage keys, whatever their scopes.
+    assert_forbidden(&app, TestRequest::get().uri("/api-keys").insert_header(api_key(&created.key)).to_request()).await;
+
+    let req = TestRequest::get().uri("/api-keys").insert_header(authorized()).to_request();
+    let listed: serde_json::Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(listed[0]["id"], created.info.id.as_str());
+    assert!(listed[0]["last_used_at"].is_string());
+    assert!(listed[0].get("key").is_none() && listed[0].get("secret_hash").is_none());
+
+    let req = TestRequest::delete().uri(&format!("/api-keys/{}", created.info.id)).insert_header(authorized()).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+    let req = TestRequest::get().uri("/v1/users").insert_header(api_key(&created.key)).to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(problem.code, "invalid_api_key");
+
+    let req = TestRequest::get().uri("/api-keys").insert_header(authorized()).to_request();
+    let listed: serde_json::Value = call_and_read_body_json(&app, req).await;
+    assert!(listed[0]["revoked_at"].is_string());
+    let req = TestRequest::delete().uri("/api-keys/unknown").insert_header(authorized()).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn api_keys_with_write_scope_manage_users() {
+    let app = test_app().await;
+    let created: api_keys::CreatedApiKey = call_and_read_body_json(&app, create_api_key_request(serde_json::json!({ "name": "sync", "scopes": ["write-users"] }))).await;
+
+    let req = TestRequest::post().uri("/v1/users").insert_header(api_key(&created.key)).set_json(jane()).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::CREATED);
+    let req = TestRequest::delete().uri("/v1/users/janedoe").insert_header(api_key(&created.key)).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+    assert_forbidden(&app, TestRequest::get().uri("/v1/users").insert_header(api_key(&created.key)).to_request()).await;
+    assert_forbidden(&app, TestRequest::put().uri("/v1/users/janedoe/roles").insert_header(api_key(&created.key)).set_json(serde_json::json!({ "roles": [] })).to_request()).await;
+}
+
+#[actix_web::test]
+async fn only_admins_manage_api_keys() {
+    let app = test_app().await;
+    assert_forbidden(&app, as_role(Role::Support, TestRequest::post().uri("/api-keys").set_json(serde_json::json!({ "name": "job", "scopes": ["read-users"] })))).await;
+    assert_forbidden(&app, as_role(Role::Owner, TestRequest::get().uri("/api-keys"))).await;
+    let req = TestRequest::get().uri("/api-keys").to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    for body in [
+        serde_json::json!({ "name": "job", "scopes": [] }),
+        serde_json::json!({ "name": "", "scopes": ["read-users"] }),
+        serde_json::json!({ "name": "job", "scopes": ["read-users"], "expires_in_secs": TEST_API_KEY_MAX_TTL.as_secs() + 1 }),
+    ] {
+        assert_eq!(call_service(&app, create_api_key_request(body)).await.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let req = create_api_key_request(serde_json::json!({ "name": "job", "scopes": ["delete-everything"] }));
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn expired_and_forged_api_keys_are_rejected() {
+    let keys = Arc::new(InMemoryApiKeyRepository::new());
+    let now = mongodb::bson::DateTime::now();
+    keys.insert(api_keys::ApiKeyRecord {
+        id: "expired".into(),
+        name: "old job".into(),
+        scopes: vec![model::Scope::ReadUsers],
+        secret_hash: secrets::hash("secret"),
+        created_by: "tester".into(),
+        created_at: now,
+        expires_at: now,
+        last_used_at: None,
+        revoked_at: None,
+    })
+    .await
+    .unwrap();
+    let repo: Arc<dyn UserRepository> = Arc::new(InMemoryUserRepository::new());
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::from(repo))
+            .app_data(web::Data::new(PageLimits::default()))
+            .app_data(web::Data::new(test_tokens()))
+            .app_data(web::Data::new(ApiKeys::new(keys.clone(), TEST_API_KEY_MAX_TTL)))
+            .configure(configure),
+    )
+    .await;
+
+    for key in ["uk_expired.secret", "uk_expired.forged", "uk_unknown.secret", "expired.secret"] {
+        let req = TestRequest::get().uri("/v1/users").insert_header(api_key(key)).to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{key}");
+        assert_eq!(response.headers().get(WWW_AUTHENTICATE).unwrap(), "ApiKey error=\"invalid_api_key\"");
+        let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(problem.code, "invalid_api_key");
+    }
+    assert!(keys.find("expired").await.unwrap().unwrap().last_used_at.is_none());
+}
+
+/// Every email sent so far, once the sends handlers left running in the
+/// background have finished.
+async fn sent_emails(mailer: &InMemoryMailer) -> Vec<Email> {
+    for _ in 0..10 {
+        actix_rt::task::yield_now().await;
+    }
+    mailer.sent()
+}
+
+/// Registers janedoe with a password.
+async fn register_jane(app: &impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>) {
+    let req = TestRequest::post().insert_header(authorized()).uri("/v1/users").set_json(registration(&jane(), "correct horse")).to_request();
+    assert_eq!(call_service(app, req).await.status(), StatusCode::CREATED);
+}
+
+/// Starts a session as janedoe, sending `current` as the existing cookie.
+async fn start_session(app: &impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>, current: Option<Cookie<'static>>) -> Cookie<'static> {
+    let mut req = TestRequest::post().uri("/session").set_json(serde_json::json!({ "username": "janedoe", "password": "correct horse" }));
+    if let Some(current) = current {
+        req = req.cookie(current);
+    }
+    let response = call_service(app, req.to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let cookie = response.response().cookies().next().unwrap().into_owned();
+    Cookie::new(cookie.name().to_string(), cookie.value().to_string())
+}
+
+async fn session_status(app: &impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>, cookie: &Cookie<'static>) -> StatusCode {
+    call_service(app, TestRequest::get().uri("/session").cookie(cookie.clone()).to_request()).await.status()
+}
+
+async fn refresh_status(app: &impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>, refresh_token: &str) -> StatusCode {
+    let req = TestRequest::post().uri("/token/refresh").set_json(serde_json::json!({ "refresh_token": refresh_token })).to_request();
+    call_service(app, req).await.status()
+}
+
+#[actix_web::test]
+async fn sessions_authorize_requests_until_logout() {
+    let app = test_app().await;
+    register_jane(&app).await;
+
+    let req = TestRequest::post().uri("/session").set_json(serde_json::json!({ "username": "janedoe", "password": "correct horse" })).to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let set_cookie = response.headers().get(actix_web::http::header::SET_COOKIE).unwrap().to_str().unwrap().to_string();
+    for attribute in ["session=", "HttpOnly", "Secure", "SameSite=Strict", "Path=/", "Max-Age=28800"] {
+        assert!(set_cookie.contains(attribute), "{set_cookie}");
+    }
+    let cookie = response.response().cookies().next().unwrap().into_owned();
+    let cookie = Cookie::new("session", cookie.value().to_string());
+    let info: sessions::SessionInfo = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!((info.username.as_str(), info.roles.as_slice()), ("janedoe", &[Role::Owner][..]));
+
+    let req = TestRequest::patch().uri("/v1/users/janedoe").cookie(cookie.clone()).insert_header((CONTENT_TYPE, "application/merge-patch+json")).set_payload(r#"{"first_name":"Janet"}"#).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+    assert_forbidden(&app, TestRequest::get().uri("/v1/users").cookie(cookie.clone()).to_request()).await;
+    let req = TestRequest::post().uri("/session").set_json(serde_json::json!({ "username": "janedoe", "password": "wrong horse" })).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    let response = call_service(&app, TestRequest::delete().uri("/session").cookie(cookie.clone()).to_request()).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let removal = response.response().cookies().next().unwrap();
+    assert_eq!(removal.max_age(), Some(actix_web::cookie::time::Duration::ZERO));
+
+    let response = call_service(&app, TestRequest::get().uri("/session").cookie(cookie.clone()).to_request()).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(problem.code, "invalid_session");
+    let req = TestRequest::get().uri("/session").to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn session_login_replaces_the_current_session() {
+    let app = test_app().await;
+    register_jane(&app).await;
+
+    let planted = start_session(&app, None).await;
+    let rotated = start_session(&app, Some(planted.clone())).await;
+    assert_ne!(planted.value(), rotated.value());
+    assert_eq!(session_status(&app, &planted).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(session_status(&app, &rotated).await, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn users_can_be_logged_out_everywhere() {
+    let app = test_app().await;
+    register_jane(&app).await;
+    let laptop = start_session(&app, None).await;
+    let phone = start_session(&app, None).await;
+    let tokens: TokenPair = call_and_read_body_json(&app, login_request("janedoe", "correct horse")).await;
+
+    assert_forbidden(&app, TestRequest::delete().uri("/v1/users/janedoe/sessions").insert_header(bearer(&test_tokens().issue("johndoe", &[Role::Owner], "").access_token)).to_request()).await;
+    assert_eq!(session_status(&app, &phone).await, StatusCode::OK);
+
+    let req = TestRequest::delete().uri("/v1/users/janedoe/sessions").cookie(laptop.clone()).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+    assert_eq!(session_status(&app, &laptop).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(session_status(&app, &phone).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(refresh_status(&app, &tokens.refresh_token).await, StatusCode::UNAUTHORIZED);
+
+    // Deleting the user ends their sessions, so none carry over to a new user of the same name.
+    let session = start_session(&app, None).await;
+    let req = TestRequest::delete().uri("/v1/users/janedoe").insert_header(authorized()).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+    register_jane(&app).await;
+    assert_eq!(session_status(&app, &session).await, StatusCode::UNAUTHORIZED);
+}
+
+/// The token in the link of `email`.
+fn link_token(email: &Email) -> String {
+    let (_, rest) = email.body.split_once("token=").expect("the email has a link");
+    rest.split_whitespace().next().unwrap().to_string()
+}
+
+async fn post_token(app: &impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>, uri: &str, body: serde_json::Value) -> ServiceResponse {
+    call_service(app, TestRequest::post().uri(uri).set_json(body).to_request()).await
+}
+
+#[actix_web::test]
+async fn new_users_verify_their_email_once() {
+    let mailer = Arc::new(InMemoryMailer::new());
+    let app = TestApp::default().mailer(mailer.clone()).build().await;
+    register_jane(&app).await;
+    call_service(&app, add_request(&john())).await;
+
+    let sent = sent_emails(&mailer).await;
+    assert_eq!(sent.iter().map(|email| email.to.as_str()).collect::<Vec<_>>(), ["example@example.com", "john@example.com"]);
+    assert_eq!(sent[0].subject, "Verify your email address");
+    let token = link_token(&sent[0]);
+    assert!(sent[0].body.contains(&format!("http://localhost:8080/verify-email?token={token}")));
+
+    // Unverified addresses get no reset links.
+    let response = post_token(&app, "/password-reset", serde_json::json!({ "username": "janedoe" })).await;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert_eq!(sent_emails(&mailer).await.len(), 2);
+
+    let response = post_token(&app, "/email/verify", serde_json::json!({ "token": token })).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = post_token(&app, "/email/verify", serde_json::json!({ "token": token })).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let problem: ProblemDetails = serde_json::from_slice(&read_body(response).await).unwrap();
+    assert_eq!(problem.code, "invalid_email_token");
+
+    // Verified addresses need no new link; unverified ones get one replacing the last.
+    let req = TestRequest::post().uri("/v1/users/janedoe/email-verification").insert_header(authorized()).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+    let first = link_token(&sent_emails(&mailer).await[1]);
+    let req = TestRequest::post().uri("/v1/users/jsmith/email-verification").insert_header(authorized()).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+    let second = link_token(&sent_emails(&mailer).await[2]);
+    let response = post_token(&app, "/email/verify", serde_json::json!({ "token": first })).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = post_token(&app, "/email/verify", serde_json::json!({ "token": second })).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+}
+
+#[actix_web::test]
+async fn password_resets_set_a_new_password_and_end_sessions() {
+    let mailer = Arc::new(InMemoryMailer::new());
+    let app = TestApp::default().mailer(mailer.clone()).build().await;
+    register_jane(&app).await;
+    post_token(&app, "/email/verify", serde_json::json!({ "token": link_token(&sent_emails(&mailer).await[0]) })).await;
+    let session = start_session(&app, None).await;
+    let tokens: TokenPair = call_and_read_body_json(&app, login_request("janedoe", "correct horse")).await;
+    assert_eq!(refresh_status(&app, &tokens.refresh_token).await, StatusCode::OK);
+
+    for username in ["janedoe", "nobody"] {
+        let response = post_token(&app, "/password-reset", serde_json::json!({ "username": username })).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+    }
+    let sent = sent_emails(&mailer).await;
+    assert_eq!(sent.len(), 2);
+    assert_eq!((sent[1].to.as_str(), sent[1].subject.as_str()), ("example@example.com", "Reset your password"));
+    let token = link_token(&sent[1]);
+
+    // A rejected password does not use up the token.
+    let response = post_token(&app, "/password-reset/confirm", serde_json::json!({ "token": token, "password": "short" })).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let response = post_token(&app, "/password-reset/confirm", serde_json::json!({ "token": token, "password": "battery staple" })).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = post_token(&app, "/password-reset/confirm", serde_json::json!({ "token": token, "password": "another staple" })).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    assert_eq!(session_status(&app, &session).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(refresh_status(&app, &tokens.refresh_token).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(call_service(&app, login_request("janedoe", "correct horse")).await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(call_service(&app, login_request("janedoe", "battery staple")).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn expired_and_stale_email_tokens_are_rejected() {
+    let mailer = Arc::new(InMemoryMailer::new());
+    let repo = Arc::new(InMemoryEmailTokenRepository::new());
+    let email_tokens = EmailTokens::new(repo.clone(), mailer.clone(), &config::MailConfig::default());
+    let app = TestApp::default().email_tokens(email_tokens).build().await;
+    register_jane(&app).await;
+
+    let now = mongodb::bson::DateTime::now();
+    repo.insert(EmailTokenRecord {
+        id_hash: secrets::hash("expired"),
+        purpose: Purpose::VerifyEmail,
+        username: "janedoe".into(),
+        email: jane().email,
+        created_at: now,
+        expires_at: now,
+    })
+    .await
+    .unwrap();
+    let response = post_token(&app, "/email/verify", serde_json::json!({ "token": "expired" })).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // Links sent to an address the user has since changed do not verify the new one.
+    let token = link_token(&sent_emails(&mailer).await[0]);
+    let req = TestRequest::patch().uri("/v1/users/janedoe").insert_header(authorized()).insert_header((CONTENT_TYPE, "application/merge-patch+json")).set_payload(r#"{"email":"jane@example.com"}"#).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+    let response = post_token(&app, "/email/verify", serde_json::json!({ "token": token })).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn json_lines_mailer_writes_one_email_per_line() {
+    let output = CapturedLogs::default();
+    let mailer = mailer::JsonLinesMailer::new("Users <no-reply@example.com>".parse().unwrap(), output.clone());
+    for to in ["example@example.com", "john@example.com"] {
+        mailer.send(Email { to: to.into(), subject: "Hello".into(), body: "Hi there".into() }).await.unwrap();
+    }
+
+    let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["from"], "Users <no-reply@example.com>");
+    assert_eq!(lines[1]["to"], "john@example.com");
+    assert_eq!(lines[1]["subject"], "Hello");
+    assert_eq!(lines[1]["body"], "Hi there");
+    assert!(lines[1]["sent_at"].is_string());
+}
+
+#[actix_web::test]
+#[ignore = "requires MongoDB instance running"]
+async fn test() {
+    let storage = Config::from_sources(None, |name| std::env::var(name).ok())
+        .expect("configuration should be valid")
+        .storage;
+
+    let client = Client::with_uri_str(&storage.mongodb_uri).await.expect("failed to connect");
+    let repo = MongoUserRepository::new(&client, &storage.database, &storage.collection);
+
+    // Clear any data currently in the users collection.
+    repo.drop().await.expect("drop collection should succeed");
+    let app = TestApp::default().repo(Arc::new(repo)).build().await;
+
+    let user = jane();
+
+    let response = call_and_read_body(&app, add_request(&user)).await;
     assert_eq!(response, Bytes::from_static(b"user added"));
 
     let req = TestRequest::get()
@@ -49,4 +2257,4 @@ async fn test() {
 
     let response: User = call_and_read_body_json(&app, req).await;
     assert_eq!(response, user);
-}
\ No newline at end of file
+}
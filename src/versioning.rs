@@ -0,0 +1,88 @@
+//! API versions and the user representation each one serves.
+//!
+//! A version is selected by path prefix (`/v1/users`, `/v2/users`) or, on the
+//! unprefixed `/users` routes, by `Accept: application/vnd.users.v2+json`;
+//! without that media type `/users` serves v1. All versions share the same
+//! handlers and storage and differ only in how a [`User`] is rendered.
+
+use actix_web::{
+    guard::{self, GuardContext},
+    http::header::{self, Quality},
+    middleware::DefaultHeaders,
+    web,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::{ApiError, FieldViolation};
+use crate::model::{User, UserV2};
+use crate::resources;
+
+/// Media type requesting the v2 representation.
+pub const V2_MEDIA_TYPE: &str = "application/vnd.users.v2+json";
+
+/// JSON shape of a user in one API version.
+pub trait UserRepresentation: Serialize + DeserializeOwned + From<User> + Into<User> + 'static {
+    /// Collection path served with this representation.
+    const USERS_PATH: &'static str;
+    /// Content type of response bodies in this representation.
+    const CONTENT_TYPE: &'static str;
+
+    /// Maps a [`User`] field name to its location in this representation, for
+    /// reporting validation failures.
+    fn field_path(field: &str) -> String {
+        field.to_string()
+    }
+}
+
+impl UserRepresentation for User {
+    const USERS_PATH: &'static str = "/v1/users";
+    const CONTENT_TYPE: &'static str = "application/json";
+}
+
+impl UserRepresentation for UserV2 {
+    const USERS_PATH: &'static str = "/v2/users";
+    const CONTENT_TYPE: &'static str = V2_MEDIA_TYPE;
+
+    fn field_path(field: &str) -> String {
+        match field {
+            "first_name" => "name.given".into(),
+            "last_name" => "name.family".into(),
+            field => field.into(),
+        }
+    }
+}
+
+/// Renames the fields of a validation failure into representation `R`.
+pub fn localize_error<R: UserRepresentation>(err: ApiError) -> ApiError {
+    match err {
+        ApiError::InvalidFields(violations) => ApiError::InvalidFields(
+            violations
+                .into_iter()
+                .map(|violation| FieldViolation { field: R::field_path(&violation.field), ..violation })
+                .collect(),
+        ),
+        err => err,
+    }
+}
+
+/// Whether the request accepts the v2 representation.
+fn accepts_v2(ctx: &GuardContext<'_>) -> bool {
+    ctx.header::<header::Accept>().is_some_and(|accept| {
+        accept
+            .iter()
+            .any(|item| item.item.essence_str() == V2_MEDIA_TYPE && item.quality > Quality::ZERO)
+    })
+}
+
+/// Registers the versioned user resources.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    let negotiated = || DefaultHeaders::new().add((header::VARY, "Accept"));
+    cfg.service(resources::scope::<User>(User::USERS_PATH))
+        .service(resources::scope::<UserV2>(UserV2::USERS_PATH))
+        .service(
+            resources::scope::<UserV2>("/users")
+                .guard(guard::fn_guard(accepts_v2))
+                .wrap(negotiated()),
+        )
+        .service(resources::scope::<User>("/users").wrap(negotiated()));
+}